@@ -0,0 +1,134 @@
+// Compares the memtable reps on small keys and values, which is where the per-entry overhead of
+// the treap matters most. Run with `cargo bench --bench memtable`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rand::prelude::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use albertdb::config::MemtableRepKind;
+use albertdb::memtable::Memtable;
+
+const NUM_KEYS: usize = 10_000;
+
+// counts the bytes that are currently allocated, so the memory bench can measure what each rep
+// really uses rather than what it reports
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const REPS: [(&str, MemtableRepKind); 3] = [
+    ("treap", MemtableRepKind::Treap),
+    ("skip_list", MemtableRepKind::SkipList),
+    ("arena", MemtableRepKind::Arena),
+];
+
+// 16 byte keys and 32 byte values in random order
+fn test_data() -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut rng = StdRng::seed_from_u64(42);
+    (0..NUM_KEYS)
+        .map(|_| {
+            let key = format!("{:016x}", rng.gen::<u64>()).into_bytes();
+            let value = format!("{:032x}", rng.gen::<u128>()).into_bytes();
+            (key, value)
+        })
+        .collect()
+}
+
+fn filled(kind: &MemtableRepKind, data: &[(Vec<u8>, Vec<u8>)]) -> Memtable {
+    let memtable = Memtable::with_rep(kind);
+    for (seq, (key, value)) in data.iter().enumerate() {
+        memtable.insert(key.clone(), Some(value.clone()), seq as u64 + 1);
+    }
+    memtable
+}
+
+fn insert(c: &mut Criterion) {
+    let data = test_data();
+    let mut group = c.benchmark_group("insert");
+    for (name, kind) in &REPS {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_batched(
+                || data.clone(),
+                |data| {
+                    let memtable = Memtable::with_rep(kind);
+                    for (seq, (key, value)) in data.into_iter().enumerate() {
+                        memtable.insert(key, Some(value), seq as u64 + 1);
+                    }
+                    memtable
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn search(c: &mut Criterion) {
+    let data = test_data();
+    let mut group = c.benchmark_group("search");
+    for (name, kind) in &REPS {
+        let memtable = filled(kind, &data);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                for (key, _) in &data {
+                    assert_eq!(true, memtable.search(key).1);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn iterate(c: &mut Criterion) {
+    let data = test_data();
+    let mut group = c.benchmark_group("iterate");
+    for (name, kind) in &REPS {
+        let memtable = filled(kind, &data);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| assert_eq!(NUM_KEYS, memtable.iter().count()))
+        });
+    }
+    group.finish();
+}
+
+// not timed: prints the bytes each rep has allocated once it holds the data, which criterion has
+// no way to report
+fn memory(_: &mut Criterion) {
+    let data = test_data();
+    let payload: usize = data.iter().map(|(k, v)| k.len() + v.len()).sum();
+    for (name, kind) in &REPS {
+        let before = ALLOCATED.load(Ordering::SeqCst);
+        let memtable = filled(kind, &data);
+        let allocated = ALLOCATED.load(Ordering::SeqCst) - before;
+        println!(
+            "memory/{}: {} bytes allocated for {} bytes of keys and values ({:.1}x)",
+            name,
+            allocated,
+            payload,
+            allocated as f64 / payload as f64
+        );
+        drop(memtable);
+    }
+}
+
+criterion_group!(benches, insert, search, iterate, memory);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+// A WriteBatch collects puts and deletes so they can be applied to the engine atomically. The
+// whole batch is written to the WAL as a single record, so after a crash either every operation
+// in the batch is recovered or none of them are.
+
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    // the operations in the order they were added. A value of None is a delete
+    operations: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch { operations: vec![] }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.operations.push((key.to_vec(), Some(value.to_vec())));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.operations.push((key.to_vec(), None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Vec<u8>, Option<Vec<u8>>)> {
+        self.operations.iter()
+    }
+}
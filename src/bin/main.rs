@@ -13,6 +13,6 @@ struct CliArgs {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args = CliArgs::parse();
-    let config = Config::from_file(&args.config);
+    let config = Config::from_file(&args.config)?;
     http::start(config).await
 }
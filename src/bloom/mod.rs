@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 
 // TODO add comments to this & some documentation about how to use this module
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct BloomFilter {
     num_hashes: u8,
     size: u32,
@@ -0,0 +1,19 @@
+// Compaction filters apply rules of the application to the entries compaction reads, e.g. to purge
+// the keys of a deleted tenant without deleting them one at a time.
+
+// what to do with an entry
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterDecision {
+    Keep,
+    // the entry is deleted. It's replaced by a tombstone, so older versions of the key in tables
+    // that aren't being compacted don't come back
+    Remove,
+    // keep the entry with a new value
+    ChangeValue(Vec<u8>),
+}
+
+pub trait CompactionFilter: Send + Sync {
+    // called for every value compaction reads, including older versions kept for snapshots.
+    // Tombstones aren't passed to the filter. level is the level being compacted
+    fn filter(&self, level: u8, key: &[u8], value: &[u8]) -> FilterDecision;
+}
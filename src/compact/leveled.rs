@@ -0,0 +1,204 @@
+// Leveled compaction keeps the tables at every level below 0 on disjoint key ranges, and lets each
+// level grow level_size_multiplier times larger than the one above it. Once a level is over its
+// target size one of its tables is merged with the tables it overlaps at the next level
+
+use std::path;
+
+use super::{read_table, Compaction, CompactionStrategy};
+use crate::config;
+use crate::error::Result;
+use crate::manifest;
+use crate::sstable;
+
+#[derive(Debug, Default)]
+pub struct LeveledStrategy;
+
+impl CompactionStrategy for LeveledStrategy {
+    // the bottommost level is never compacted, since there's no level below it
+    fn pick(
+        &self,
+        config: &config::Config,
+        manifest: &manifest::Manifest,
+        level: u8,
+    ) -> Result<Option<Compaction>> {
+        if level >= config.compaction_max_levels {
+            return Ok(None);
+        }
+        let inputs = find_compact_candidates(config, manifest, level)?;
+        if inputs.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Compaction {
+            inputs,
+            output_level: level + 1,
+            split_output: true,
+        }))
+    }
+}
+
+// find sstables that should be compacted at the given level, plus the sstables at the next level
+// whose key ranges overlap them. returns an array of tuples of the path of the sstable and its
+// metadata
+fn find_compact_candidates(
+    config: &config::Config,
+    manifest: &manifest::Manifest,
+    level: u8,
+) -> Result<Vec<(Box<path::Path>, sstable::TableMeta)>> {
+    let files = manifest.files_at_level(level);
+    if files.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut tables = vec![];
+    let mut total_size = 0u64;
+    for file_meta in files {
+        let table = read_table(config, &file_meta.id)?;
+        total_size += table.1.table_size_compressed();
+        tables.push((file_meta, table));
+    }
+
+    // level 0 is also compacted once it has enough tables to slow down writes, whatever its size
+    let target_size = level_target_size(config, level);
+    let too_many_files = level == 0 && tables.len() >= config.l0_files_slowdown;
+    if total_size < target_size && !too_many_files {
+        log::debug!(
+            "level {}: total size {} bytes is < target size {} bytes: not compacting",
+            level,
+            total_size,
+            target_size
+        );
+        return Ok(vec![]);
+    }
+
+    // the tables at level 0 overlap each other, so they're all compacted at once. Below that the
+    // table with the oldest entries is moved down
+    let mut inputs = if level == 0 {
+        tables
+    } else {
+        tables
+            .into_iter()
+            .min_by_key(|(file_meta, _)| file_meta.max_seq)
+            .into_iter()
+            .collect()
+    };
+    let smallest_key = inputs
+        .iter()
+        .map(|(file_meta, _)| file_meta.smallest_key.clone())
+        .min();
+    let largest_key = inputs
+        .iter()
+        .map(|(file_meta, _)| file_meta.largest_key.clone())
+        .max();
+    let (smallest_key, largest_key) = (smallest_key.unwrap(), largest_key.unwrap());
+    let compacted_count = inputs.len();
+    for file_meta in manifest.files_at_level(level + 1) {
+        if file_meta.smallest_key <= largest_key && smallest_key <= file_meta.largest_key {
+            inputs.push((file_meta, read_table(config, &file_meta.id)?));
+        }
+    }
+
+    log::debug!(
+        "level {}: total size {} bytes is > target size {} bytes: compacting {} tables with {} overlapping tables at level {}",
+        level,
+        total_size,
+        target_size,
+        compacted_count,
+        inputs.len() - compacted_count,
+        level + 1
+    );
+    Ok(inputs.into_iter().map(|(_, table)| table).collect())
+}
+
+// the size in bytes a level can grow to before it's compacted
+fn level_target_size(config: &config::Config, level: u8) -> u64 {
+    config
+        .compaction_threshold
+        .saturating_mul(config.level_size_multiplier.saturating_pow(level as u32))
+}
+
+#[cfg(test)]
+mod find_compact_candidates_tets {
+    use super::*;
+    use crate::compact::{flush_and_commit, to_memtable_id};
+    use crate::memtable;
+    use std::fs;
+    use std::sync::Mutex;
+
+    #[test]
+    fn it_can_choose_the_right_tables_to_compact() {
+        let data_dir =
+            "/tmp/compact_find_compact_candidates_tets/it_can_choose_the_right_tables_to_compact";
+        fs::remove_dir_all(data_dir);
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        config.compaction_threshold = 1;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+
+        let memtable1 = memtable::Memtable::new();
+        memtable1.insert("abc".bytes().collect(), Some("abc".bytes().collect()), 3);
+        flush_and_commit(&config, &manifest, &memtable1, 0);
+
+        let memtable2 = memtable::Memtable::new();
+        memtable2.insert("abc".bytes().collect(), Some("abc".bytes().collect()), 4);
+        flush_and_commit(&config, &manifest, &memtable2, 0);
+
+        // memtables that are at another level or that aren't in the MANIFEST aren't candidates
+        let memtable3 = memtable::Memtable::new();
+        memtable3.insert("abc".bytes().collect(), Some("abc".bytes().collect()), 5);
+        flush_and_commit(&config, &manifest, &memtable3, 2);
+        let memtable4 = memtable::Memtable::new();
+        memtable4.insert("abc".bytes().collect(), Some("abc".bytes().collect()), 6);
+        sstable::flush_to_sstable(&config, &memtable4, 0).unwrap();
+
+        let results_r = find_compact_candidates(&config, &manifest.lock().unwrap(), 0);
+        assert_eq!(true, results_r.is_ok());
+        let results = results_r.unwrap();
+        assert_eq!(2, results.len());
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_picks_one_table_and_the_overlapping_tables_at_the_next_level() {
+        let data_dir = "/tmp/compact_find_compact_candidates_tets/it_picks_one_table_and_the_overlapping_tables_at_the_next_level";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        config.compaction_threshold = 1;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+
+        // the oldest table at level 1 is picked
+        let tables = [
+            (1, "a", "c", 1),
+            (1, "d", "f", 2),
+            (2, "b", "b", 3),
+            (2, "e", "e", 4),
+        ];
+        let mut ids = vec![];
+        for (level, smallest, largest, seq) in tables {
+            let memtable = memtable::Memtable::new();
+            memtable.insert(smallest.bytes().collect(), Some(vec![1]), seq);
+            memtable.insert(largest.bytes().collect(), Some(vec![1]), seq);
+            flush_and_commit(&config, &manifest, &memtable, level);
+            ids.push(memtable.id.clone());
+        }
+
+        let results = find_compact_candidates(&config, &manifest.lock().unwrap(), 1).unwrap();
+        let result_ids: Vec<String> = results
+            .iter()
+            .map(|(path, _)| to_memtable_id(path))
+            .collect();
+        assert_eq!(vec![ids[0].clone(), ids[2].clone()], result_ids);
+
+        // a level under its target size isn't compacted
+        config.compaction_threshold = 1 << 20;
+        let results = find_compact_candidates(&config, &manifest.lock().unwrap(), 1).unwrap();
+        assert_eq!(0, results.len());
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
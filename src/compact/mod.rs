@@ -1,59 +1,351 @@
-// This database uses leveled compaction only
+// Compaction merges sstables to drop overwritten and deleted entries. Which tables are merged and
+// where the result goes is up to the CompactionStrategy: leveled compaction keeps the levels below
+// 0 on disjoint key ranges so reads check at most one table per level, while size-tiered compaction
+// merges tables of similar size, which rewrites each entry fewer times
 
 use regex::Regex;
-use std::fs;
-use std::io;
+use serde::Serialize;
+use std::collections::HashSet;
 use std::path;
+use std::sync::Mutex;
 
 use crate::config;
+use crate::error::Result;
+use crate::manifest;
 use crate::memtable;
+use crate::snapshot;
 use crate::sstable;
 
-// compact stables at a given level. returns the new memtable which old values are compacted into
-// as well as the list of memtable ids that were compacted. It returns None if there were no memtables
-// at the given level that needed compaction.
-// It does not return until the new memtable has finished flushing but it does NOT delete the old memtables
-// (that would be caller's responsibility).
-pub fn compact(config: &config::Config, level: u8) -> Option<(memtable::Memtable, Vec<String>)> {
-    let compact_candidates = find_compact_candidates(config, level).unwrap();
-    if compact_candidates.len() <= 0 {
-        return None;
+mod filter;
+mod leveled;
+mod size_tiered;
+
+pub use filter::{CompactionFilter, FilterDecision};
+pub use leveled::LeveledStrategy;
+pub use size_tiered::SizeTieredStrategy;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CompactionStats {
+    pub compactions: u64,
+    // tombstones that were dropped after their grace period, since nothing older was left for
+    // them to hide
+    pub tombstones_dropped: u64,
+    pub range_tombstones_dropped: u64,
+}
+
+// the sstables picked to be merged, as tuples of the path of the sstable and its metadata
+pub struct Compaction {
+    pub inputs: Vec<(Box<path::Path>, sstable::TableMeta)>,
+    // the level the merged tables are written to
+    pub output_level: u8,
+    // whether the output is split into tables of about sstable_target_size bytes
+    pub split_output: bool,
+}
+
+// decides which sstables are compacted together
+pub trait CompactionStrategy: Send + Sync {
+    // pick the sstables at the level to compact, or None if the level doesn't need compaction
+    fn pick(
+        &self,
+        config: &config::Config,
+        manifest: &manifest::Manifest,
+        level: u8,
+    ) -> Result<Option<Compaction>>;
+}
+
+pub fn strategy(kind: &config::CompactionStrategyKind) -> Box<dyn CompactionStrategy> {
+    match kind {
+        config::CompactionStrategyKind::Leveled => Box::new(LeveledStrategy),
+        config::CompactionStrategyKind::SizeTiered => Box::new(SizeTieredStrategy),
     }
+}
 
-    let mut memtable = memtable::Memtable::new();
+// compact the sstables at a given level that the configured strategy picks. returns the new memtables which old values are compacted into as well as the list of
+// memtable ids that were compacted. It returns None if the level didn't need compaction.
+// It does not return until the new memtables have finished flushing and the MANIFEST has been updated
+// to replace the old memtables with the new ones, but it does NOT delete the old memtables' files
+// (that would be caller's responsibility).
+// snapshots are the sequence numbers of the live snapshots. Older versions of keys that are still
+// visible to one of them are kept in the new memtables.
+// If there's a filter, it decides whether each value is kept, removed or changed.
+pub fn compact(
+    config: &config::Config,
+    manifest: &Mutex<manifest::Manifest>,
+    level: u8,
+    snapshots: &[u64],
+    filter: Option<&dyn CompactionFilter>,
+    stats: &Mutex<CompactionStats>,
+) -> Result<Option<(Vec<memtable::Memtable>, Vec<String>)>> {
+    let strategy = strategy(&config.compaction_strategy);
+    let picked = strategy.pick(config, &manifest.lock().unwrap(), level)?;
+    let Some(Compaction {
+        inputs: compact_candidates,
+        output_level,
+        split_output,
+    }) = picked
+    else {
+        return Ok(None);
+    };
+
+    let mut memtable = memtable::Memtable::with_rep(&config.memtable_rep);
     let mut compacted_memtable_ids = vec![];
+    let timestamp = compact_candidates
+        .iter()
+        .map(|(_, table_meta)| table_meta.timestamp)
+        .max()
+        .unwrap_or_default();
+
+    // tombstones can only be dropped when merging into the bottommost level, and once they're
+    // past the grace period. Tables record when their entries were written by, which is when
+    // their tombstones are considered written
+    let bottommost = output_level >= config.compaction_max_levels;
+    let now = sstable::now_millis();
+    let gc_before = now.saturating_sub(config.tombstone_gc_grace_ms) as u128;
+    let mut expired = Expired::default();
+
+    // the range tombstones are carried over to the new table, since they can still delete keys in
+    // older tables that aren't part of this compaction
+    let mut range_tombstones: Vec<memtable::RangeTombstone> = vec![];
+    for (_, table_meta) in &compact_candidates {
+        for tombstone in &table_meta.range_tombstones {
+            if bottommost && table_meta.timestamp <= gc_before {
+                expired.range_tombstones.insert(tombstone.seq);
+            }
+            memtable.add_range_tombstone(tombstone.clone());
+            range_tombstones.push(tombstone.clone());
+        }
+    }
 
-    // combine all the old memtables into a new one while removing duplicates
+    // combine all the old memtables into a new one while removing duplicates. The memtable keeps
+    // the version with the highest sequence number, so the order the tables are read in doesn't
+    // matter
     for (path, table_meta) in compact_candidates {
         compacted_memtable_ids.push(to_memtable_id(&path));
-        let iter = sstable::reader::SstableIterator::new(path, table_meta);
-        for entry in iter {
-            if entry.deleted {
-                // TODO handle case is older than GC grace period, currently
-                // tombstones are never removed unless the value is re-written
-                // after a delete
-                memtable.insert(entry.key, None);
+        let table_expired = bottommost && table_meta.timestamp <= gc_before;
+        let mut iter = sstable::reader::SstableIterator::new(path, table_meta)?;
+        while let Some(entry) = iter.try_next()? {
+            // a version deleted by a range tombstone can be dropped once no snapshot can see it
+            let covered = range_tombstones.iter().any(|tombstone| {
+                tombstone.covers(&entry.key, entry.seq)
+                    && !snapshot::is_needed(entry.seq, tombstone.seq, snapshots)
+            });
+            if covered {
+                continue;
+            }
+
+            // a value past its TTL is emptied but kept with its expiry, so it hides older versions
+            // of the key like a tombstone until it can be dropped. There's no need to wait for the
+            // grace period, since every replica expires the value at the same time
+            let ttl_expired = !entry.deleted && entry.is_expired(now);
+            let value = if entry.deleted {
+                None
+            } else if ttl_expired {
+                Some(vec![])
             } else {
-                memtable.insert(entry.key, Some(entry.value));
+                let decision = filter.map_or(FilterDecision::Keep, |filter| {
+                    filter.filter(level, &entry.key, &entry.value)
+                });
+                match decision {
+                    FilterDecision::Keep => Some(entry.value),
+                    FilterDecision::Remove => None,
+                    FilterDecision::ChangeValue(value) => Some(value),
+                }
+            };
+
+            if (value.is_none() && table_expired) || (bottommost && ttl_expired) {
+                expired.tombstones.insert((entry.key.clone(), entry.seq));
             }
+            let version = memtable::Version {
+                expires_at: entry.expires_at,
+                key: entry.key,
+                value,
+                seq: entry.seq,
+            };
+            memtable.insert_version(version, snapshots);
         }
     }
 
-    sstable::flush_to_sstable(config, &memtable, level + 1).unwrap();
+    let mut tombstones_dropped = 0;
+    let mut range_tombstones_dropped = 0;
+    if !expired.is_empty() {
+        let outside = outside_key_ranges(&manifest.lock().unwrap(), &compacted_memtable_ids);
+        (memtable, tombstones_dropped, range_tombstones_dropped) =
+            drop_expired_tombstones(config, &memtable, &expired, &outside, snapshots);
+    }
+
+    let outputs = if split_output {
+        split_by_size(config, &memtable, snapshots)
+    } else if memtable.is_empty() {
+        vec![]
+    } else {
+        vec![memtable]
+    };
+    let mut added = vec![];
+    for output in &outputs {
+        manifest.lock().unwrap().add_pending(&output.id);
+        added.push(sstable::flush_to_sstable_at(
+            config,
+            output,
+            output_level,
+            timestamp,
+        )?);
+    }
+
+    // swap the old tables for the new ones in a single edit, so that after a crash either the old
+    // tables or the new ones are live but never both
+    let edit = manifest::VersionEdit {
+        added,
+        removed: compacted_memtable_ids.clone(),
+    };
+    manifest.lock().unwrap().apply(edit)?;
     log::debug!(
-        "level {}: compacted {} memtables into new memtable {} at level {}",
+        "level {}: compacted {} memtables into {} new memtables at level {}, dropped {} tombstones and {} range tombstones",
         level,
         compacted_memtable_ids.len(),
-        memtable.id,
-        level + 1
+        outputs.len(),
+        output_level,
+        tombstones_dropped,
+        range_tombstones_dropped,
     );
-    Some((memtable, compacted_memtable_ids))
+
+    let mut stats = stats.lock().unwrap();
+    stats.compactions += 1;
+    stats.tombstones_dropped += tombstones_dropped;
+    stats.range_tombstones_dropped += range_tombstones_dropped;
+    Ok(Some((outputs, compacted_memtable_ids)))
+}
+
+// tombstones past their grace period, or left by values past their TTL, by key and sequence number. Range tombstones are only
+// identified by their sequence number, which is unique to the delete_range that wrote them
+#[derive(Default)]
+struct Expired {
+    tombstones: HashSet<(Vec<u8>, u64)>,
+    range_tombstones: HashSet<u64>,
+}
+
+impl Expired {
+    fn is_empty(&self) -> bool {
+        self.tombstones.is_empty() && self.range_tombstones.is_empty()
+    }
+}
+
+// split the memtable into memtables of about sstable_target_size bytes. The versions of a key are
+// never split up, and range tombstones are cut at the boundaries between the memtables, so their
+// key ranges don't overlap. Nothing is returned if the memtable is empty
+fn split_by_size(
+    config: &config::Config,
+    memtable: &memtable::Memtable,
+    snapshots: &[u64],
+) -> Vec<memtable::Memtable> {
+    let mut outputs = vec![];
+    // the first key of every memtable after the first one
+    let mut boundaries: Vec<Vec<u8>> = vec![];
+    let mut current = memtable::Memtable::with_rep(&config.memtable_rep);
+    let mut last_key: Option<Vec<u8>> = None;
+    for version in memtable.iter() {
+        let new_key = last_key.as_ref().is_some_and(|key| *key != version.key);
+        if new_key && current.approximate_size() as u64 >= config.sstable_target_size {
+            outputs.push(current);
+            boundaries.push(version.key.clone());
+            current = memtable::Memtable::with_rep(&config.memtable_rep);
+        }
+        last_key = Some(version.key.clone());
+        current.insert_version(version, snapshots);
+    }
+    if !current.is_empty() || !memtable.range_tombstones().is_empty() {
+        outputs.push(current);
+    }
+
+    for tombstone in memtable.range_tombstones() {
+        for (i, output) in outputs.iter().enumerate() {
+            let mut clipped = tombstone.clone();
+            if let Some(lower) = i.checked_sub(1).and_then(|i| boundaries.get(i)) {
+                clipped.start = clipped.start.max(lower.clone());
+            }
+            if let Some(upper) = boundaries.get(i) {
+                clipped.end = clipped.end.min(upper.clone());
+            }
+            if clipped.start < clipped.end {
+                output.add_range_tombstone(clipped);
+            }
+        }
+    }
+    outputs
+}
+
+// the key ranges of the live tables that aren't being compacted, which may hold older versions
+// of keys
+fn outside_key_ranges(
+    manifest: &manifest::Manifest,
+    compacted_ids: &[String],
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    manifest
+        .files()
+        .filter(|file| !compacted_ids.contains(&file.id))
+        .map(|file| (file.smallest_key.clone(), file.largest_key.clone()))
+        .collect()
+}
+
+// copy the memtable without the expired tombstones that hide nothing anymore: no table outside
+// the compaction can hold an older version, and no snapshot can see an older version either.
+// Returns the new memtable and the number of tombstones and range tombstones dropped
+fn drop_expired_tombstones(
+    config: &config::Config,
+    memtable: &memtable::Memtable,
+    expired: &Expired,
+    outside: &[(Vec<u8>, Vec<u8>)],
+    snapshots: &[u64],
+) -> (memtable::Memtable, u64, u64) {
+    // a snapshot from before the tombstone could still see what it deleted
+    let hides_nothing = |seq: u64| !snapshots.iter().any(|snapshot| *snapshot < seq);
+    let outside_overlaps = |start: &[u8], last: &[u8]| {
+        outside
+            .iter()
+            .any(|(smallest, largest)| **smallest <= *last && *start <= **largest)
+    };
+
+    let kept = memtable::Memtable::with_rep(&config.memtable_rep);
+    let mut tombstones_dropped = 0;
+    let mut range_tombstones_dropped = 0;
+    for tombstone in memtable.range_tombstones() {
+        // the end of the range is exclusive, but comparing with it only keeps more tombstones
+        if expired.range_tombstones.contains(&tombstone.seq)
+            && hides_nothing(tombstone.seq)
+            && !outside_overlaps(&tombstone.start, &tombstone.end)
+        {
+            range_tombstones_dropped += 1;
+        } else {
+            kept.add_range_tombstone(tombstone);
+        }
+    }
+
+    let mut last_key: Option<Vec<u8>> = None;
+    let mut dropping = false;
+    for version in memtable.iter() {
+        // versions of a key are newest first, and only the newest can be a droppable tombstone
+        if last_key.as_ref() != Some(&version.key) {
+            dropping = expired
+                .tombstones
+                .contains(&(version.key.clone(), version.seq))
+                && hides_nothing(version.seq)
+                && !outside_overlaps(&version.key, &version.key);
+            if dropping {
+                tombstones_dropped += 1;
+            }
+            last_key = Some(version.key.clone());
+        }
+        if !dropping {
+            kept.insert_version(version, snapshots);
+        }
+    }
+    (kept, tombstones_dropped, range_tombstones_dropped)
 }
 
 #[cfg(test)]
 mod compact_tests {
     use super::*;
     use crate::memtable;
+    use std::fs;
 
     #[test]
     fn it_can_compact_the_memtables() {
@@ -64,132 +356,428 @@ mod compact_tests {
         let mut config = config::Config::new();
         config.data_dir = String::from(data_dir);
         config.compaction_threshold = 1;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
 
-        let mut memtable1 = memtable::Memtable::new();
-        memtable1.insert("abc".bytes().collect(), Some("abc".bytes().collect()));
-        assert_eq!(
-            true,
-            sstable::flush_to_sstable(&config, &memtable1, 0).is_ok()
-        );
+        let memtable1 = memtable::Memtable::new();
+        memtable1.insert("abc".bytes().collect(), Some("abc".bytes().collect()), 1);
+        flush_and_commit(&config, &manifest, &memtable1, 0);
 
-        let mut memtable2 = memtable::Memtable::new();
-        memtable2.insert("abc".bytes().collect(), Some("abc".bytes().collect()));
-        assert_eq!(
-            true,
-            sstable::flush_to_sstable(&config, &memtable2, 0).is_ok()
-        );
+        let memtable2 = memtable::Memtable::new();
+        memtable2.insert("abc".bytes().collect(), Some("abc".bytes().collect()), 2);
+        flush_and_commit(&config, &manifest, &memtable2, 0);
 
-        compact(&config, 0);
+        let (outputs, compacted_ids) = compact(&config, &manifest, 0, &[], None, &Mutex::default())
+            .unwrap()
+            .unwrap();
+        let compacted = &outputs[0];
+        assert_eq!(2, compacted_ids.len());
         // TODO assert that there's a newer memtable than the other 2 and that it only has
         // one block and it has a size of 6 bytes
+
+        // the MANIFEST only has the new memtable
+        let manifest = manifest.lock().unwrap();
+        assert_eq!(0, manifest.files_at_level(0).len());
+        assert_eq!(1, manifest.files_at_level(1).len());
+        assert_eq!(true, manifest.contains(&compacted.id));
+
+        fs::remove_dir_all(data_dir).unwrap();
     }
-}
 
-// find sstables that should be compacted at the given level. returns an array
-// of tuples of the path of the sstable and its metadata
-fn find_compact_candidates(
-    config: &config::Config,
-    level: u8,
-) -> io::Result<Vec<(Box<path::Path>, sstable::TableMeta)>> {
-    let mut results = vec![];
-    let files: fs::ReadDir = fs::read_dir(&config.data_dir)?;
+    #[test]
+    fn it_keeps_versions_visible_to_snapshots() {
+        let data_dir = "/tmp/compact_tests/it_keeps_versions_visible_to_snapshots";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
 
-    let mut total_size = 0u64;
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        config.compaction_threshold = 1;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
 
-    for file in files {
-        let file_path = file.unwrap().path();
-        if !is_sstable(&file_path) {
-            continue;
-        }
-        if is_flushing(&file_path) {
-            continue;
-        }
+        let memtable1 = memtable::Memtable::new();
+        memtable1.insert("a".bytes().collect(), Some("old".bytes().collect()), 1);
+        memtable1.insert("b".bytes().collect(), Some("old".bytes().collect()), 2);
+        flush_and_commit(&config, &manifest, &memtable1, 0);
 
-        let meta_path = to_metadata_path(&file_path);
-        let table_meta = read_table_meta(&path::Path::new(&meta_path));
-        if table_meta.level != level {
-            // TODO write a test for this
-            continue;
-        }
+        let memtable2 = memtable::Memtable::new();
+        memtable2.insert("a".bytes().collect(), Some("new".bytes().collect()), 4);
+        memtable2.insert("b".bytes().collect(), Some("new".bytes().collect()), 5);
+        flush_and_commit(&config, &manifest, &memtable2, 0);
+
+        // the snapshot at 3 can see the old version of both keys
+        let (outputs, _) = compact(&config, &manifest, 0, &[3], None, &Mutex::default())
+            .unwrap()
+            .unwrap();
+        let compacted = &outputs[0];
+        assert_eq!(
+            (Some("new".bytes().collect()), true),
+            compacted.search("a".as_bytes())
+        );
+        assert_eq!(
+            (Some("old".bytes().collect()), true),
+            compacted.search_at("a".as_bytes(), 3)
+        );
+        assert_eq!(
+            (Some("old".bytes().collect()), true),
+            compacted.search_at("b".as_bytes(), 3)
+        );
+        assert_eq!(4, compacted.iter().count());
 
-        total_size += table_meta.table_size_compressed();
-        results.push((file_path.into_boxed_path(), table_meta));
+        fs::remove_dir_all(data_dir).unwrap();
     }
 
-    if total_size < config.compaction_threshold {
-        log::debug!(
-            "level {}: total size {} bytes is < compaction threshold {} bytes: not compacting",
-            level,
-            total_size,
-            config.compaction_threshold
+    #[test]
+    fn it_drops_keys_covered_by_range_tombstones() {
+        let data_dir = "/tmp/compact_tests/it_drops_keys_covered_by_range_tombstones";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        config.compaction_threshold = 1;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+
+        let memtable1 = memtable::Memtable::new();
+        memtable1.insert("a".bytes().collect(), Some("old".bytes().collect()), 1);
+        memtable1.insert("b".bytes().collect(), Some("old".bytes().collect()), 2);
+        memtable1.insert("c".bytes().collect(), Some("old".bytes().collect()), 4);
+        flush_and_commit(&config, &manifest, &memtable1, 0);
+
+        let memtable2 = memtable::Memtable::new();
+        memtable2.delete_range("a".bytes().collect(), "c".bytes().collect(), 5);
+        memtable2.insert("b".bytes().collect(), Some("new".bytes().collect()), 6);
+        flush_and_commit(&config, &manifest, &memtable2, 0);
+
+        // the snapshot at 1 can still see the old version of a, but not of b
+        let (outputs, _) = compact(&config, &manifest, 0, &[1], None, &Mutex::default())
+            .unwrap()
+            .unwrap();
+        let compacted = &outputs[0];
+        let versions: Vec<(String, u64)> = compacted
+            .iter()
+            .map(|v| (String::from_utf8(v.key).unwrap(), v.seq))
+            .collect();
+        assert_eq!(
+            vec![
+                (String::from("a"), 1),
+                (String::from("b"), 6),
+                (String::from("c"), 4)
+            ],
+            versions
         );
-        return Ok(vec![]);
+        assert_eq!((None, true), compacted.search("a".as_bytes()));
+
+        // the tombstone is kept for the keys in older tables
+        assert_eq!(1, compacted.range_tombstones().len());
+
+        fs::remove_dir_all(data_dir).unwrap();
     }
 
-    log::debug!(
-        "level {}: total size {} bytes is > compaction threshold {} bytes: compacting",
-        level,
-        total_size,
-        config.compaction_threshold
-    );
-    Ok(results)
-}
+    // removes the keys of tenant b and redacts the values of tenant a
+    struct TenantFilter;
 
-#[cfg(test)]
-mod find_compact_candidates_tets {
-    use super::*;
-    use crate::memtable;
+    impl CompactionFilter for TenantFilter {
+        fn filter(&self, _level: u8, key: &[u8], value: &[u8]) -> FilterDecision {
+            if key.starts_with(b"b/") {
+                FilterDecision::Remove
+            } else if key.starts_with(b"a/") {
+                FilterDecision::ChangeValue(vec![b'x'; value.len()])
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
 
     #[test]
-    fn it_can_choose_the_right_tables_to_compact() {
-        let data_dir =
-            "/tmp/compact_find_compact_candidates_tets/it_can_choose_the_right_tables_to_compact";
-        fs::remove_dir_all(data_dir);
+    fn it_applies_the_compaction_filter() {
+        let data_dir = "/tmp/compact_tests/it_applies_the_compaction_filter";
+        fs::remove_dir_all(data_dir).ok();
         fs::create_dir_all(data_dir).unwrap();
 
         let mut config = config::Config::new();
         config.data_dir = String::from(data_dir);
         config.compaction_threshold = 1;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
 
-        let mut memtable1 = memtable::Memtable::new();
-        memtable1.insert("abc".bytes().collect(), Some("abc".bytes().collect()));
+        let memtable = memtable::Memtable::new();
+        memtable.insert("a/1".bytes().collect(), Some("abc".bytes().collect()), 1);
+        memtable.insert("b/1".bytes().collect(), Some("abc".bytes().collect()), 2);
+        memtable.insert("c/1".bytes().collect(), Some("abc".bytes().collect()), 3);
+        flush_and_commit(&config, &manifest, &memtable, 0);
+
+        let (outputs, _) = compact(
+            &config,
+            &manifest,
+            0,
+            &[],
+            Some(&TenantFilter),
+            &Mutex::default(),
+        )
+        .unwrap()
+        .unwrap();
+        let compacted = &outputs[0];
+        assert_eq!(
+            (Some("xxx".bytes().collect()), true),
+            compacted.search("a/1".as_bytes())
+        );
+        // removed keys are left as tombstones, which hide any older versions
+        assert_eq!((None, true), compacted.search("b/1".as_bytes()));
         assert_eq!(
-            true,
-            sstable::flush_to_sstable(&config, &memtable1, 0).is_ok()
+            (Some("abc".bytes().collect()), true),
+            compacted.search("c/1".as_bytes())
         );
 
-        let mut memtable2 = memtable::Memtable::new();
-        memtable2.insert("abc".bytes().collect(), Some("abc".bytes().collect()));
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_drops_expired_values() {
+        let data_dir = "/tmp/compact_tests/it_drops_expired_values";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        config.compaction_threshold = 1;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+
+        let memtable = memtable::Memtable::new();
+        for (key, seq, expires_at) in [("a", 1, Some(1)), ("b", 2, Some(u64::MAX)), ("c", 3, None)]
+        {
+            memtable.insert_version(
+                memtable::Version {
+                    key: key.bytes().collect(),
+                    value: Some("abc".bytes().collect()),
+                    seq,
+                    expires_at,
+                },
+                &[],
+            );
+        }
+        flush_and_commit(&config, &manifest, &memtable, 0);
+
+        let (outputs, _) = compact(&config, &manifest, 0, &[], None, &Mutex::default())
+            .unwrap()
+            .unwrap();
+        let compacted = &outputs[0];
+        let versions: Vec<(String, Option<Vec<u8>>, Option<u64>)> = compacted
+            .iter()
+            .map(|v| (String::from_utf8(v.key).unwrap(), v.value, v.expires_at))
+            .collect();
+        // above the bottommost level only the expired value's data is dropped
         assert_eq!(
-            true,
-            sstable::flush_to_sstable(&config, &memtable2, 0).is_ok()
+            vec![
+                (String::from("a"), Some(vec![]), Some(1)),
+                (
+                    String::from("b"),
+                    Some("abc".bytes().collect()),
+                    Some(u64::MAX)
+                ),
+                (String::from("c"), Some("abc".bytes().collect()), None)
+            ],
+            versions
         );
 
-        let results_r = find_compact_candidates(&config, 0);
-        assert_eq!(true, results_r.is_ok());
-        let results = results_r.unwrap();
-        assert_eq!(2, results.len());
+        // the tombstone is dropped at the bottommost level, without waiting for the grace period
+        config.compaction_max_levels = 2;
+        let stats = Mutex::default();
+        let (outputs, _) = compact(&config, &manifest, 1, &[], None, &stats)
+            .unwrap()
+            .unwrap();
+        let compacted = &outputs[0];
+        assert_eq!(2, compacted.iter().count());
+        assert_eq!(1, stats.lock().unwrap().tombstones_dropped);
 
         fs::remove_dir_all(data_dir).unwrap();
     }
+
+    #[test]
+    fn it_splits_the_output_into_tables_that_dont_overlap() {
+        let data_dir = "/tmp/compact_tests/it_splits_the_output_into_tables_that_dont_overlap";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        config.compaction_threshold = 1;
+        config.sstable_target_size = 512;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+
+        let memtable = memtable::Memtable::new();
+        for i in 0..20u64 {
+            let key = format!("key{:02}", i).into_bytes();
+            memtable.insert(key, Some(vec![0; 32]), i + 1);
+        }
+        memtable.delete_range(b"key00".to_vec(), b"key99".to_vec(), 100);
+        flush_and_commit(&config, &manifest, &memtable, 0);
+
+        // the snapshot keeps the keys the range tombstone deleted
+        let (outputs, _) = compact(&config, &manifest, 0, &[50], None, &Mutex::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(true, outputs.len() > 1);
+        assert_eq!(
+            20,
+            outputs
+                .iter()
+                .map(|output| output.iter().count())
+                .sum::<usize>()
+        );
+
+        // each table has the tombstone for its own part of the range
+        let mut files = manifest
+            .lock()
+            .unwrap()
+            .files_at_level(1)
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.smallest_key.cmp(&b.smallest_key));
+        assert_eq!(outputs.len(), files.len());
+        assert_eq!(b"key00".to_vec(), files[0].smallest_key);
+        assert_eq!(b"key99".to_vec(), files[files.len() - 1].largest_key);
+        for pair in files.windows(2) {
+            assert_eq!(pair[0].largest_key, pair[1].smallest_key);
+        }
+        for output in &outputs {
+            assert_eq!(1, output.range_tombstones().len());
+        }
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    fn tombstone_gc_config(test_name: &str) -> config::Config {
+        let data_dir = format!("/tmp/compact_tests/{}", test_name);
+        fs::remove_dir_all(&data_dir).ok();
+        fs::create_dir_all(&data_dir).unwrap();
+
+        // compacting level 1 merges into the bottommost level
+        let mut config = config::Config::new();
+        config.data_dir = data_dir;
+        config.compaction_threshold = 1;
+        config.compaction_max_levels = 2;
+        config.tombstone_gc_grace_ms = 0;
+        config
+    }
+
+    // the tombstones at level 1 are compacted with the table they overlap at level 2. a is only in
+    // the tables being compacted, c also has an older version in a table at level 0
+    fn write_tombstones(config: &config::Config, manifest: &Mutex<manifest::Manifest>) {
+        let outside = memtable::Memtable::new();
+        outside.insert("c".bytes().collect(), Some("old".bytes().collect()), 1);
+        flush_and_commit(config, manifest, &outside, 0);
+
+        let memtable1 = memtable::Memtable::new();
+        memtable1.insert("a".bytes().collect(), Some("old".bytes().collect()), 2);
+        memtable1.insert("c".bytes().collect(), Some("old".bytes().collect()), 3);
+        flush_and_commit(config, manifest, &memtable1, 2);
+
+        let memtable2 = memtable::Memtable::new();
+        memtable2.insert("a".bytes().collect(), None, 4);
+        memtable2.insert("c".bytes().collect(), None, 5);
+        memtable2.delete_range("x".bytes().collect(), "z".bytes().collect(), 6);
+        flush_and_commit(config, manifest, &memtable2, 1);
+    }
+
+    fn keys(memtable: &memtable::Memtable) -> Vec<(String, u64)> {
+        memtable
+            .iter()
+            .map(|v| (String::from_utf8(v.key).unwrap(), v.seq))
+            .collect()
+    }
+
+    #[test]
+    fn it_drops_expired_tombstones_at_the_bottommost_level() {
+        let config = tombstone_gc_config("it_drops_expired_tombstones_at_the_bottommost_level");
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+        write_tombstones(&config, &manifest);
+
+        let stats = Mutex::default();
+        let (outputs, _) = compact(&config, &manifest, 1, &[], None, &stats)
+            .unwrap()
+            .unwrap();
+        let compacted = &outputs[0];
+
+        // the tombstone for c still hides the version at level 0
+        assert_eq!(vec![(String::from("c"), 5)], keys(&compacted));
+        assert_eq!(0, compacted.range_tombstones().len());
+        assert_eq!(
+            CompactionStats {
+                compactions: 1,
+                tombstones_dropped: 1,
+                range_tombstones_dropped: 1,
+            },
+            *stats.lock().unwrap()
+        );
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_tombstones_that_could_reveal_older_versions() {
+        let mut config =
+            tombstone_gc_config("it_keeps_tombstones_that_could_reveal_older_versions");
+        config.tombstone_gc_grace_ms = 60000;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+        write_tombstones(&config, &manifest);
+
+        // within the grace period
+        let stats = Mutex::default();
+        let (outputs, _) = compact(&config, &manifest, 1, &[], None, &stats)
+            .unwrap()
+            .unwrap();
+        let compacted = &outputs[0];
+        assert_eq!(
+            vec![(String::from("a"), 4), (String::from("c"), 5)],
+            keys(&compacted)
+        );
+        assert_eq!(0, stats.lock().unwrap().tombstones_dropped);
+        fs::remove_dir_all(&config.data_dir).unwrap();
+
+        // past the grace period, but a snapshot can still see the old version of a
+        config.tombstone_gc_grace_ms = 0;
+        fs::create_dir_all(&config.data_dir).unwrap();
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+        write_tombstones(&config, &manifest);
+        let (outputs, _) = compact(&config, &manifest, 1, &[2], None, &stats)
+            .unwrap()
+            .unwrap();
+        let compacted = &outputs[0];
+        assert_eq!(
+            vec![
+                (String::from("a"), 4),
+                (String::from("a"), 2),
+                (String::from("c"), 5)
+            ],
+            keys(&compacted)
+        );
+        assert_eq!(1, compacted.range_tombstones().len());
+        assert_eq!(0, stats.lock().unwrap().tombstones_dropped);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
 }
 
-// TODO this could be a util function as it's shared w/ sstable module (reader)
-fn is_sstable(path: &path::Path) -> bool {
-    let re = regex::Regex::new(r".*/sstable-data.*$").unwrap();
-    re.is_match(path.to_str().unwrap())
+fn read_table(config: &config::Config, id: &str) -> Result<(Box<path::Path>, sstable::TableMeta)> {
+    let file_path = path::PathBuf::from(format!("{}/sstable-data-{}", config.data_dir, id));
+    let meta_path = to_metadata_path(&file_path);
+    let table_meta = sstable::reader::read_table_meta(path::Path::new(&meta_path))?;
+    Ok((file_path.into_boxed_path(), table_meta))
 }
 
-// TODO this could be a util function as it's shared with WAL module (but its backwards)
-// check if the path was in the process of flushing when the database shut down last
-fn is_flushing(sstable_path: &path::Path) -> bool {
-    let sstable_data_path = String::from(
-        Regex::new("stable-data-")
-            .unwrap()
-            .replace(sstable_path.to_str().unwrap(), "wal-"),
-    );
-    let is_flushing = fs::metadata(&path::Path::new(&sstable_data_path));
-    return is_flushing.is_ok();
+// flush the memtable and add it to the MANIFEST
+#[cfg(test)]
+fn flush_and_commit(
+    config: &config::Config,
+    manifest: &Mutex<manifest::Manifest>,
+    memtable: &memtable::Memtable,
+    level: u8,
+) {
+    let edit = manifest::VersionEdit {
+        added: vec![sstable::flush_to_sstable(config, memtable, level).unwrap()],
+        removed: vec![],
+    };
+    manifest.lock().unwrap().apply(edit).unwrap();
 }
 
 // TODO this could be a util function as it's shared w/ sstable module (reader)
@@ -211,22 +799,3 @@ fn to_memtable_id(path: &path::Path) -> String {
     );
     return memtable_id;
 }
-
-// TODO make this a util function casue it's shared w/ sstable module (reader)
-fn read_table_meta(path: &path::Path) -> sstable::TableMeta {
-    let file = fs::OpenOptions::new().read(true).open(path);
-    match file {
-        Ok(file) => {
-            let result: sstable::TableMeta = serde_yaml::from_reader(file).unwrap();
-            return result;
-        }
-        Err(err) => {
-            log::error!(
-                "An error happened reading sstable meta at {:?}: {:?}",
-                path,
-                err
-            );
-            panic!("could not read table meta, invalid state");
-        }
-    };
-}
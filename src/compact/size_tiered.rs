@@ -0,0 +1,200 @@
+// Size-tiered compaction treats each level as a tier of sstables. The tables in a tier are grouped
+// into buckets of similar size, and once a bucket has size_tiered_min_tables tables they're merged
+// into a single table in the next tier. Tables in a tier can overlap, so reads may check all of
+// them, but each entry is only rewritten once per tier. The bottommost tier merges into itself
+
+use std::path;
+
+use super::{read_table, Compaction, CompactionStrategy};
+use crate::config;
+use crate::error::Result;
+use crate::manifest;
+use crate::sstable;
+
+type Table = (Box<path::Path>, sstable::TableMeta);
+
+#[derive(Debug, Default)]
+pub struct SizeTieredStrategy;
+
+impl CompactionStrategy for SizeTieredStrategy {
+    // the bucket of the smallest tables is merged first, since it's the cheapest
+    fn pick(
+        &self,
+        config: &config::Config,
+        manifest: &manifest::Manifest,
+        level: u8,
+    ) -> Result<Option<Compaction>> {
+        if level > config.compaction_max_levels {
+            return Ok(None);
+        }
+        let mut tables = vec![];
+        for file_meta in manifest.files_at_level(level) {
+            tables.push(read_table(config, &file_meta.id)?);
+        }
+
+        // merging a single table into the bottommost tier would rewrite it forever
+        let min_tables = config.size_tiered_min_tables.max(2);
+        // level 0 is also compacted once it has enough tables to slow down writes, whatever their
+        // sizes
+        let inputs = if level == 0 && tables.len() >= config.l0_files_slowdown {
+            tables
+        } else {
+            let bucket = buckets(config, tables)
+                .into_iter()
+                .find(|bucket| bucket.len() >= min_tables);
+            match bucket {
+                Some(bucket) => bucket,
+                None => return Ok(None),
+            }
+        };
+
+        log::debug!(
+            "level {}: merging a bucket of {} tables of {} bytes",
+            level,
+            inputs.len(),
+            inputs
+                .iter()
+                .map(|(_, table_meta)| table_meta.table_size_compressed())
+                .sum::<u64>()
+        );
+        Ok(Some(Compaction {
+            inputs,
+            output_level: (level + 1).min(config.compaction_max_levels),
+            split_output: false,
+        }))
+    }
+}
+
+// group the tables into buckets of similar size, smallest first. The tables are added in order of
+// size, so a table joins the last bucket unless it's too large for it
+fn buckets(config: &config::Config, mut tables: Vec<Table>) -> Vec<Vec<Table>> {
+    tables.sort_by_key(|(_, table_meta)| table_meta.table_size_compressed());
+    let mut buckets: Vec<Vec<Table>> = vec![];
+    let mut bucket_size = 0u64;
+    for table in tables {
+        let size = table.1.table_size_compressed();
+        let fits = buckets.last().is_some_and(|bucket| {
+            let average = bucket_size as f64 / bucket.len() as f64;
+            size as f64 <= average * config.size_tiered_bucket_ratio
+        });
+        match buckets.last_mut() {
+            Some(bucket) if fits => {
+                bucket.push(table);
+                bucket_size += size;
+            }
+            _ => {
+                buckets.push(vec![table]);
+                bucket_size = size;
+            }
+        }
+    }
+    buckets
+}
+
+#[cfg(test)]
+mod size_tiered_tests {
+    use super::*;
+    use crate::compact::{compact, flush_and_commit, to_memtable_id};
+    use crate::memtable;
+    use std::fs;
+    use std::sync::Mutex;
+
+    #[test]
+    fn it_merges_a_bucket_of_similar_sized_tables() {
+        let data_dir = "/tmp/compact_size_tiered_tests/it_merges_a_bucket_of_similar_sized_tables";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        config.compaction_strategy = config::CompactionStrategyKind::SizeTiered;
+        config.size_tiered_min_tables = 3;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+
+        let mut small_ids = vec![];
+        for seq in 1..=3 {
+            let memtable = memtable::Memtable::new();
+            memtable.insert(vec![seq as u8], Some(vec![seq as u8]), seq);
+            flush_and_commit(&config, &manifest, &memtable, 0);
+            small_ids.push(memtable.id.clone());
+        }
+        let large = memtable::Memtable::new();
+        for seq in 10..100 {
+            large.insert(
+                format!("key{}", seq).into_bytes(),
+                Some(format!("value{}", seq).into_bytes()),
+                seq,
+            );
+        }
+        flush_and_commit(&config, &manifest, &large, 0);
+
+        let picked = SizeTieredStrategy
+            .pick(&config, &manifest.lock().unwrap(), 0)
+            .unwrap()
+            .unwrap();
+        let mut picked_ids: Vec<String> = picked
+            .inputs
+            .iter()
+            .map(|(path, _)| to_memtable_id(path))
+            .collect();
+        picked_ids.sort();
+        small_ids.sort();
+        assert_eq!(small_ids, picked_ids);
+        assert_eq!(1, picked.output_level);
+        assert_eq!(false, picked.split_output);
+
+        // the bucket is merged into one table in the next tier
+        compact(&config, &manifest, 0, &[], None, &Mutex::default())
+            .unwrap()
+            .unwrap();
+        {
+            let manifest = manifest.lock().unwrap();
+            assert_eq!(1, manifest.files_at_level(0).len());
+            assert_eq!(true, manifest.contains(&large.id));
+            assert_eq!(1, manifest.files_at_level(1).len());
+        }
+
+        // the large table is in a bucket of its own
+        let picked = SizeTieredStrategy
+            .pick(&config, &manifest.lock().unwrap(), 0)
+            .unwrap();
+        assert_eq!(true, picked.is_none());
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_merges_the_bottommost_tier_into_itself() {
+        let data_dir = "/tmp/compact_size_tiered_tests/it_merges_the_bottommost_tier_into_itself";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        config.compaction_max_levels = 1;
+        config.size_tiered_min_tables = 2;
+        let manifest = Mutex::new(manifest::Manifest::open(&config).unwrap());
+
+        for seq in 1..=2 {
+            let memtable = memtable::Memtable::new();
+            memtable.insert(vec![seq as u8], Some(vec![seq as u8]), seq);
+            flush_and_commit(&config, &manifest, &memtable, 1);
+        }
+
+        let picked = SizeTieredStrategy
+            .pick(&config, &manifest.lock().unwrap(), 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, picked.inputs.len());
+        assert_eq!(1, picked.output_level);
+        assert_eq!(
+            true,
+            SizeTieredStrategy
+                .pick(&config, &manifest.lock().unwrap(), 2)
+                .unwrap()
+                .is_none()
+        );
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -1,7 +1,55 @@
-use log;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs;
 
+use crate::error::{Error, Result};
+
+// controls when writes to the WAL are synced to stable storage
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WalSyncMode {
+    // fsync after every write
+    Always,
+    // writers that are waiting at the same time share a single fsync
+    #[default]
+    Group,
+    // fsync in the background every N millis. Writes from the last interval can be lost on crash
+    IntervalMs(u64),
+}
+
+// what to do with files in the data directory that don't belong to any live sstable or WAL
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanFileAction {
+    // move them into a quarantine directory so they can be inspected
+    #[default]
+    Quarantine,
+    Delete,
+}
+
+// the data structure memtables are stored in
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemtableRepKind {
+    // lock-free, so reads don't wait for writes
+    #[default]
+    SkipList,
+    // a treap behind a single lock
+    Treap,
+    // a skiplist behind a single lock that packs keys and values into large chunks of memory
+    Arena,
+}
+
+// how sstables are picked for compaction
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompactionStrategyKind {
+    // levels below 0 hold tables with disjoint key ranges, so reads check one table per level
+    #[default]
+    Leveled,
+    // tables of similar size are merged, so each entry is rewritten fewer times
+    SizeTiered,
+}
+
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct Config {
     // id of the node
@@ -10,14 +58,40 @@ pub struct Config {
     // path on disk where data files will be stored
     pub data_dir: String,
 
-    // number of records that will be in a memtable before it is flushed to disk
-    pub memtable_max_count: u32,
+    // path on disk where WAL files will be stored. defaults to data_dir
+    #[serde(default)]
+    pub wal_dir: Option<String>,
+
+    // when writes to the WAL are synced to disk. one of `always`, `group` or `interval_ms: N`
+    #[serde(default)]
+    pub wal_sync_mode: WalSyncMode,
+
+    // whether orphaned files are quarantined or deleted. one of `quarantine` or `delete`
+    #[serde(default)]
+    pub orphan_file_action: OrphanFileAction,
+
+    // how memtables are stored. one of `skip_list`, `treap` or `arena`
+    #[serde(default)]
+    pub memtable_rep: MemtableRepKind,
+
+    // approximate size in bytes a memtable can grow to before it is flushed to disk. This counts
+    // keys, values and the memtable's own overhead
+    pub memtable_max_bytes: u64,
 
     // approximate size of compressed blocks in sstables
     pub sstable_block_size: u32,
 
-    // size of sstables on disk before they will be compacted
+    // size of sstables on disk before they will be compacted. This is the target size of level 0,
+    // and each level below is allowed to grow level_size_multiplier times larger than the one
+    // above it
     pub compaction_threshold: u64,
+    #[serde(default = "default_level_size_multiplier")]
+    pub level_size_multiplier: u64,
+
+    // approximate size in bytes of the sstables written by compaction. The output is split into
+    // several sstables once it grows past this, counted the same way as memtable_max_bytes
+    #[serde(default = "default_sstable_target_size")]
+    pub sstable_target_size: u64,
 
     // how often we check if we should compact tables (millis)
     pub compaction_check_period: u64,
@@ -25,6 +99,41 @@ pub struct Config {
     // number of levels for leveld compaction
     pub compaction_max_levels: u8,
 
+    // how sstables are picked for compaction. one of `leveled` or `size_tiered`
+    #[serde(default)]
+    pub compaction_strategy: CompactionStrategyKind,
+
+    // size-tiered compaction puts sstables at the same level in a bucket when their sizes are
+    // within size_tiered_bucket_ratio of the bucket's average size, and merges a bucket into a
+    // single sstable at the next level once it has size_tiered_min_tables of them
+    #[serde(default = "default_size_tiered_min_tables")]
+    pub size_tiered_min_tables: usize,
+    #[serde(default = "default_size_tiered_bucket_ratio")]
+    pub size_tiered_bucket_ratio: f64,
+
+    // how long a tombstone is kept before compaction into the bottommost level can drop it
+    // (millis). Deletes must reach every replica within this time, or deleted values can come back
+    #[serde(default = "default_tombstone_gc_grace_ms")]
+    pub tombstone_gc_grace_ms: u64,
+
+    // writes are slowed down once this many memtables are waiting to be flushed, and stopped
+    // until the flushes catch up once there are immutable_memtables_stop
+    #[serde(default = "default_immutable_memtables_slowdown")]
+    pub immutable_memtables_slowdown: usize,
+    #[serde(default = "default_immutable_memtables_stop")]
+    pub immutable_memtables_stop: usize,
+
+    // the same for the number of sstables at level 0. Level 0 is also compacted once it has
+    // l0_files_slowdown sstables, however small they are
+    #[serde(default = "default_l0_files_slowdown")]
+    pub l0_files_slowdown: usize,
+    #[serde(default = "default_l0_files_stop")]
+    pub l0_files_stop: usize,
+
+    // how long each write is delayed while writes are slowed down (millis)
+    #[serde(default = "default_write_slowdown_delay")]
+    pub write_slowdown_delay: u64,
+
     // the port to listen on for http front-end
     pub http_listen_port: u32,
 
@@ -38,17 +147,73 @@ pub struct Config {
     pub ring_svc_broadcast_host: String,
 }
 
+fn default_tombstone_gc_grace_ms() -> u64 {
+    // 10 days
+    864_000_000
+}
+
+fn default_level_size_multiplier() -> u64 {
+    10
+}
+
+fn default_sstable_target_size() -> u64 {
+    8192
+}
+
+fn default_size_tiered_min_tables() -> usize {
+    4
+}
+
+fn default_size_tiered_bucket_ratio() -> f64 {
+    1.5
+}
+
+fn default_immutable_memtables_slowdown() -> usize {
+    2
+}
+
+fn default_immutable_memtables_stop() -> usize {
+    4
+}
+
+fn default_l0_files_slowdown() -> usize {
+    8
+}
+
+fn default_l0_files_stop() -> usize {
+    12
+}
+
+fn default_write_slowdown_delay() -> u64 {
+    1
+}
+
 impl Config {
     pub fn new() -> Self {
         // TODO initialize this somehow & choose more reasonable defaults
         Config {
             node_id: String::from("standalone"),
             data_dir: String::from("/tmp"),
-            memtable_max_count: 3,
+            wal_dir: None,
+            wal_sync_mode: WalSyncMode::Group,
+            orphan_file_action: OrphanFileAction::Quarantine,
+            memtable_rep: MemtableRepKind::SkipList,
+            memtable_max_bytes: 4096,
             sstable_block_size: 64,
             compaction_threshold: 256,
+            level_size_multiplier: default_level_size_multiplier(),
+            sstable_target_size: default_sstable_target_size(),
             compaction_check_period: 30000,
             compaction_max_levels: 4,
+            compaction_strategy: CompactionStrategyKind::Leveled,
+            size_tiered_min_tables: default_size_tiered_min_tables(),
+            size_tiered_bucket_ratio: default_size_tiered_bucket_ratio(),
+            tombstone_gc_grace_ms: default_tombstone_gc_grace_ms(),
+            immutable_memtables_slowdown: default_immutable_memtables_slowdown(),
+            immutable_memtables_stop: default_immutable_memtables_stop(),
+            l0_files_slowdown: default_l0_files_slowdown(),
+            l0_files_stop: default_l0_files_stop(),
+            write_slowdown_delay: default_write_slowdown_delay(),
             http_listen_port: 4000,
             ring_svc_listen_port: 5147,
             ring_svc_seed_nodes: vec!["http://127.0.0.1:5147".to_owned()],
@@ -56,13 +221,15 @@ impl Config {
         }
     }
 
-    pub fn from_file(x: &str) -> Self {
-        let file_o = fs::OpenOptions::new().read(true).open(x);
-        if file_o.is_err() {
-            log::error!("No file can be found {}", x);
-        }
+    pub fn wal_dir(&self) -> &str {
+        self.wal_dir.as_deref().unwrap_or(&self.data_dir)
+    }
 
-        let config: Config = serde_yaml::from_reader(file_o.unwrap()).unwrap();
-        return config;
+    pub fn from_file(x: &str) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .open(x)
+            .map_err(|err| Error::Config(format!("can't open {}: {}", x, err)))?;
+        serde_yaml::from_reader(file).map_err(|err| Error::Config(format!("{}: {}", x, err)))
     }
 }
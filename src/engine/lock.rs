@@ -0,0 +1,83 @@
+// An advisory lock on a LOCK file in a directory, which stops two processes from opening the same
+// database at once. The lock is released when the DirLock is dropped, and by the OS if the process
+// dies without dropping it.
+
+use fs2::FileExt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::process;
+
+const LOCK_FILENAME: &str = "LOCK";
+
+#[derive(Debug)]
+pub struct DirLock {
+    file: fs::File,
+    path: path::PathBuf,
+}
+
+impl DirLock {
+    // lock the directory, creating it if it doesn't exist. Fails if another process (or another
+    // engine in this process) holds the lock
+    pub fn acquire(dir: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = path::Path::new(dir).join(LOCK_FILENAME);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        if let Err(err) = file.try_lock_exclusive() {
+            let holder = fs::read_to_string(&path).unwrap_or_default();
+            return Err(io::Error::new(
+                err.kind(),
+                format!(
+                    "{:?} is locked by another process (pid {}). Only one instance can use a \
+                     directory at a time: {}",
+                    path,
+                    holder.trim(),
+                    err
+                ),
+            ));
+        }
+
+        // record who holds the lock, to make the error above easier to act on
+        file.set_len(0)?;
+        file.write_all(process::id().to_string().as_bytes())?;
+
+        log::info!("locked {:?}", path);
+        Ok(DirLock { file, path })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.unlock() {
+            log::error!("error unlocking {:?}: {:?}", self.path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod lock_tests {
+    use super::*;
+
+    #[test]
+    fn it_only_allows_one_holder() {
+        let dir = "/tmp/lock_tests/it_only_allows_one_holder";
+        fs::remove_dir_all(dir).ok();
+
+        let lock = DirLock::acquire(dir).unwrap();
+        let err = DirLock::acquire(dir).unwrap_err();
+        assert_eq!(true, err.to_string().contains("locked by another process"));
+
+        // the lock is released once the holder is dropped
+        drop(lock);
+        assert_eq!(true, DirLock::acquire(dir).is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
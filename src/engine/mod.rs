@@ -1,206 +1,623 @@
 use log;
+use std::collections::{BTreeSet, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
-use std::sync::{Mutex, RwLock};
+use std::sync::{Condvar, Mutex, RwLock};
 use std::thread;
+use std::time;
 
+use crate::batch;
 use crate::compact;
 use crate::config;
+use crate::error::{Error, Result};
+use crate::manifest;
 use crate::memtable;
+use crate::merge;
+use crate::orphan;
+use crate::snapshot;
 use crate::sstable;
 use crate::wal;
 
+mod lock;
+mod stall;
+
+pub use stall::StallStats;
+
 pub struct Engine {
     config: config::Config,
-    flush_sender: Mutex<mpsc::Sender<Arc<memtable::Memtable>>>,
-    writable_table: memtable::Memtable,
-    writable_wal: wal::Wal,
+    // None once the engine is closed, which lets the flush thread drain the queue and exit
+    flush_sender: Mutex<Option<mpsc::Sender<FlushRequest>>>,
+    // replaced with a new memtable when it's flushed. Readers take their own pointer to it, so a
+    // flush doesn't pull it out from under them
+    writable_table: RwLock<Arc<memtable::Memtable>>,
+    writer: Mutex<Writer>,
+    // sequence number of the most recent write. Every write gets the next sequence number so
+    // that newer versions of a key can be told apart from older ones wherever they're stored. It's
+    // only advanced once the write is in the memtable, so a snapshot never misses a write it
+    // should see
+    last_seq: AtomicU64,
+    snapshots: Arc<snapshot::SnapshotList>,
     flushing_memtables: Arc<RwLock<Vec<Arc<memtable::Memtable>>>>,
     sstable_reader: Arc<RwLock<sstable::reader::Reader>>,
+    // the record of which sstables are live
+    manifest: Arc<Mutex<manifest::Manifest>>,
+    // locks on the data and WAL directories, held for as long as the engine is open
+    _locks: Vec<lock::DirLock>,
+    // tells the compaction thread to stop
+    shutdown: Arc<Shutdown>,
+    flush_handle: Option<thread::JoinHandle<()>>,
+    compact_handle: Option<thread::JoinHandle<()>>,
+    // set by close. No more writes are accepted after this
+    closed: bool,
+    // set when a flush or compaction fails. The engine is read only until it's resumed
+    background_error: Arc<Mutex<Option<String>>>,
+    // throttles writes when flushing or compaction falls behind
+    write_controller: Arc<stall::WriteController>,
+    compaction_stats: Arc<Mutex<compact::CompactionStats>>,
+    compaction_filter: Option<Arc<dyn compact::CompactionFilter>>,
+    // held while compacting, so a forced compaction and the compaction thread never pick the
+    // same tables
+    compaction_lock: Arc<Mutex<()>>,
+}
+
+// writers hold the lock on this while they write to the WAL and the memtable, so writes are
+// applied in order of their sequence numbers. Stalled writers wait before taking it
+struct Writer {
+    wal: wal::Wal,
+    next_wal_seq: u64,
+}
+
+enum FlushRequest {
+    Flush(Arc<memtable::Memtable>),
+    // try the flushes that were stopped by a background error again
+    Retry,
+}
+
+// a flag the background threads can wait on, so they wake up as soon as the engine is closed
+#[derive(Default)]
+struct Shutdown {
+    stopped: Mutex<bool>,
+    cond: Condvar,
+    // set to wake the compaction thread before its next scheduled check
+    woken: AtomicBool,
+}
+
+impl Shutdown {
+    fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.cond.notify_all();
+    }
+
+    fn wake(&self) {
+        self.woken.store(true, Ordering::SeqCst);
+        let _stopped = self.stopped.lock().unwrap();
+        self.cond.notify_all();
+    }
+
+    fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap()
+    }
+
+    // sleep for the timeout or until stopped or woken. Returns true if stopped
+    fn wait(&self, timeout: time::Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .cond
+            .wait_timeout_while(stopped, timeout, |stopped| {
+                !*stopped && !self.woken.swap(false, Ordering::SeqCst)
+            })
+            .unwrap();
+        *stopped
+    }
 }
 
 impl Engine {
+    pub fn new(config: config::Config) -> Result<Self> {
+        Engine::open(config, None)
+    }
+
+    // open the engine with a filter that compaction applies to every value it reads
+    pub fn with_compaction_filter(
+        config: config::Config,
+        filter: Arc<dyn compact::CompactionFilter>,
+    ) -> Result<Self> {
+        Engine::open(config, Some(filter))
+    }
+
     // TODO consider whether adding an init method instead of doing all this in the constructor
-    pub fn new(config: config::Config) -> Self {
-        // derive init state from the WAL that are on disk
-        // TODO this needs to take the config as input
-        let mut wal_recovery = wal::recover().unwrap();
+    fn open(
+        config: config::Config,
+        compaction_filter: Option<Arc<dyn compact::CompactionFilter>>,
+    ) -> Result<Self> {
+        // make sure no other process is using the same directories
+        let mut locks = vec![lock::DirLock::acquire(&config.data_dir)?];
+        if std::path::Path::new(config.wal_dir()) != std::path::Path::new(&config.data_dir) {
+            locks.push(lock::DirLock::acquire(config.wal_dir())?);
+        }
+
+        // find the live sstables and then derive init state from the WAL that are on disk
+        let manifest = manifest::Manifest::open(&config)?;
+        let wal_recovery = wal::recover(&config, &manifest)?;
 
         // when we want to flush a memtable, we send a pointer to it in this channel
-        let (flush_sender, flush_receiver) = mpsc::channel::<Arc<memtable::Memtable>>();
+        let (flush_sender, flush_receiver) = mpsc::channel::<FlushRequest>();
 
         // setup the memtable we'll be putting new writes into and the WAL
-        let mut memtable = memtable::Memtable::new();
-        if wal_recovery.writable_memtable.is_some() {
-            let recovered_memtable: &mut memtable::Memtable =
-                wal_recovery.writable_memtable.as_mut().unwrap();
-            std::mem::swap(&mut memtable, recovered_memtable);
-        }
-        let wal = wal::Wal::new(memtable.id.clone());
+        let memtable = wal_recovery.writable_memtable;
+        let wal = wal_recovery.writable_wal;
 
-        // setup the thing to read from sstables (on disk)
+        // setup the thing to read from sstables (on disk). Tables that were still being written
+        // when the database shut down are never live, so their files can be removed
+        sstable::remove_temporary_files(&config)?;
         let mut sstable_reader = sstable::reader::Reader::new();
-        sstable_reader.init(&config);
+        sstable_reader.init(&config, &manifest)?;
+        let last_seq = wal_recovery.max_seq.max(sstable_reader.max_seq());
+
+        // check the files in the data directory against the live sstables and WALs, and clean up
+        // anything left behind by a crash
+        let live = live_files(&manifest, &sstable_reader, vec![memtable.id.clone()]);
+        let report = orphan::collect(&config, &live)?;
+        log::info!(
+            "startup consistency check found {} orphans and {} sstables with missing files",
+            report.orphans.len(),
+            report.missing.len()
+        );
         let sstable_reader_ptr = Arc::new(RwLock::new(sstable_reader));
+        let snapshots = Arc::new(snapshot::SnapshotList::new());
+        let manifest_ptr = Arc::new(Mutex::new(manifest));
 
         // setup out list of memtables that we'll be reading from while they're still in the
         //process of beling flushed to disk
-        let mut flushing_memtables = vec![];
-        wal_recovery.flushing_memtables.into_iter().for_each(|v| {
-            let mt_ptr = Arc::new(v);
-            flushing_memtables.push(mt_ptr.clone());
-        });
-        let flushing_memtables_ptr = Arc::new(RwLock::new(flushing_memtables));
+        let flushing_memtables_ptr = Arc::new(RwLock::new(vec![]));
 
         // finally create the engine
-        let engine = Engine {
+        let shutdown = Arc::new(Shutdown::default());
+        let background_error = Arc::new(Mutex::new(None));
+        let write_controller = Arc::new(stall::WriteController::new());
+        let compaction_stats = Arc::new(Mutex::new(compact::CompactionStats::default()));
+        let compaction_lock = Arc::new(Mutex::new(()));
+        let mut engine = Engine {
             config: config.clone(),
             sstable_reader: sstable_reader_ptr.clone(),
             flushing_memtables: flushing_memtables_ptr.clone(),
-            flush_sender: Mutex::new(flush_sender),
-            writable_table: memtable,
-            writable_wal: wal,
+            flush_sender: Mutex::new(Some(flush_sender)),
+            writable_table: RwLock::new(Arc::new(memtable)),
+            writer: Mutex::new(Writer {
+                wal,
+                next_wal_seq: wal_recovery.next_wal_seq,
+            }),
+            last_seq: AtomicU64::new(last_seq),
+            snapshots: snapshots.clone(),
+            manifest: manifest_ptr.clone(),
+            _locks: locks,
+            shutdown: shutdown.clone(),
+            flush_handle: None,
+            compact_handle: None,
+            closed: false,
+            background_error: background_error.clone(),
+            write_controller: write_controller.clone(),
+            compaction_stats: compaction_stats.clone(),
+            compaction_filter: compaction_filter.clone(),
+            compaction_lock: compaction_lock.clone(),
         };
 
         // setup handlir for sending the memtables to be flushed and update internal state
         let flush_config = config.clone();
         let flush_reader_ref = sstable_reader_ptr.clone();
-        let _flush_handle = thread::spawn(move || {
-            while let Ok(value) = flush_receiver.recv() {
-                // flush the memtable
-                sstable::flush_to_sstable(&flush_config, &value, 0).unwrap();
-
-                // delete the WAL
-                let wal = wal::Wal::new(value.id.clone());
-                wal.delete().unwrap(); // TODO could handle this error
-
-                // signal to the reader that there's a new memtable to read
-                let mut reader = flush_reader_ref.write().unwrap();
-                reader.add_memtable(&value);
-
-                // remove the memtable from the list of flushing memtables
-                let mut memtables = flushing_memtables_ptr.write().unwrap();
-                let position_o = memtables.iter().position(|v| Arc::ptr_eq(v, &value));
-                if position_o.is_some() {
-                    let position = position_o.unwrap();
-                    log::debug!(
-                        "removing flushing memtable at position {:?}. There are now {:?} flushing memtables", 
-                        position,
-                        memtables.len() - 1
+        let flush_manifest = manifest_ptr.clone();
+        let flush_error = background_error.clone();
+        let flush_controller = write_controller.clone();
+        let flush_shutdown = shutdown.clone();
+        // the thread exits once the engine is closed and every queued memtable has been flushed
+        engine.flush_handle = Some(thread::spawn(move || {
+            // memtables are flushed oldest first. After a failure nothing more is flushed until
+            // the engine is resumed, otherwise a newer memtable could reach the sstables while an
+            // older one is still being read from the flushing memtables
+            let mut queue = VecDeque::new();
+            while let Ok(request) = flush_receiver.recv() {
+                if let FlushRequest::Flush(memtable) = request {
+                    queue.push_back(memtable);
+                }
+                while flush_error.lock().unwrap().is_none() {
+                    let memtable = match queue.front() {
+                        Some(memtable) => memtable,
+                        None => break,
+                    };
+                    // the memtable stays in the list of flushing memtables until it's flushed, so
+                    // it can still be read, and it's recovered from its WAL if the database stops
+                    let result = flush(
+                        &flush_config,
+                        &flush_manifest,
+                        &flush_reader_ref,
+                        &flushing_memtables_ptr,
+                        memtable,
                     );
-                    memtables.remove(position);
+                    match result {
+                        Ok(()) => {
+                            queue.pop_front();
+                        }
+                        Err(err) => set_background_error(
+                            &flush_error,
+                            format!("error flushing memtable {}: {}", memtable.id, err),
+                        ),
+                    }
+
+                    // compact level 0 right away if it has enough tables to slow down writes,
+                    // rather than waiting for the next scheduled check
+                    let l0_files = flush_manifest.lock().unwrap().files_at_level(0).len();
+                    if l0_files >= flush_config.l0_files_slowdown {
+                        flush_shutdown.wake();
+                    }
+                    flush_controller.notify_progress();
                 }
             }
-        });
+        }));
 
         // set up handler for periodically compacting memtables
         let compact_config = config.clone();
         let compact_reader_ptr = sstable_reader_ptr.clone();
-        let _compact_handle = thread::spawn(move || {
-            let sleep_millies =
-                std::time::Duration::from_millis(compact_config.compaction_check_period);
-            loop {
-                thread::sleep(sleep_millies);
-                for level in 0..compact_config.compaction_max_levels {
-                    let compact_result_o = compact::compact(&compact_config, level);
-
-                    if compact_result_o.is_some() {
-                        let (new_memtable, compacted_memtable_ids) = compact_result_o.unwrap();
-                        let mut reader = compact_reader_ptr.write().unwrap();
-                        reader.add_memtable(&new_memtable);
-
-                        for sstable_id in &compacted_memtable_ids {
-                            reader.remove_memtable(sstable_id);
-                            sstable::delete_by_id(&compact_config, sstable_id).unwrap();
+        let compact_manifest = manifest_ptr.clone();
+        let compact_error = background_error.clone();
+        let compact_controller = write_controller.clone();
+        engine.compact_handle = Some(thread::spawn(move || {
+            let sleep_millies = time::Duration::from_millis(compact_config.compaction_check_period);
+            while !shutdown.wait(sleep_millies) {
+                // size-tiered compaction also merges tables within the bottommost level
+                for level in 0..=compact_config.compaction_max_levels {
+                    // stop between compactions so none is left half done, and don't compact while
+                    // the engine is read only
+                    if shutdown.is_stopped() || compact_error.lock().unwrap().is_some() {
+                        break;
+                    }
+                    // below level 0 each compaction only moves one table down, so keep going until
+                    // the level is back under its target size
+                    loop {
+                        let _compacting = compaction_lock.lock().unwrap();
+                        let result = compact(
+                            &compact_config,
+                            &compact_manifest,
+                            compact_reader_ptr.clone(),
+                            level,
+                            &snapshots.seqs(),
+                            compaction_filter.as_deref(),
+                            &compaction_stats,
+                        );
+                        compact_controller.notify_progress();
+                        match result {
+                            Ok(true) if !shutdown.is_stopped() => continue,
+                            Ok(_) => break,
+                            Err(err) => {
+                                set_background_error(
+                                    &compact_error,
+                                    format!("error compacting level {}: {}", level, err),
+                                );
+                                break;
+                            }
                         }
                     }
                 }
             }
-        });
+        }));
 
-        // send all the flushing memtables that we read during tecovery to be flushed..
-        // the flush didn't complete before the last shutdown so we'll retry it
-        engine
-            .flushing_memtables
-            .read()
-            .unwrap()
-            .iter()
-            .for_each(|v| {
-                engine.flush_sender.lock().unwrap().send(v.clone()).unwrap();
-            });
+        Ok(engine)
+    }
+
+    // stop accepting writes and shut down the background threads. If flush is true the writable
+    // memtable is flushed first, otherwise it's recovered from its WAL when the database is next
+    // opened. Blocks until every memtable waiting to be flushed has been flushed and any running
+    // compaction has finished. If the engine is read only, the memtables that weren't flushed are
+    // recovered from their WALs instead
+    pub fn close(&mut self, flush: bool) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        log::info!("closing engine");
+
+        let mut writer = self.writer.lock().unwrap();
+        writer.wal.sync()?;
+        if flush && !self.writable_table().is_empty() {
+            self.flush_writable_memtable(&mut writer)?;
+        }
+        drop(writer);
+
+        // dropping the sender ends the flush thread once the queue is empty
+        self.flush_sender.lock().unwrap().take();
+        self.shutdown.stop();
+        for handle in [self.flush_handle.take(), self.compact_handle.take()]
+            .into_iter()
+            .flatten()
+        {
+            if handle.join().is_err() {
+                return Err(Error::Io(io::Error::other("background thread panicked")));
+            }
+        }
 
-        return engine;
+        log::info!("engine closed");
+        Ok(())
     }
 
-    pub fn force_compact(&self) {
+    pub fn force_compact(&self) -> Result<()> {
+        self.check_open()?;
         let mut cfg = self.config.clone();
         cfg.compaction_threshold = 0;
-        for level in 0..cfg.compaction_max_levels {
-            compact(&cfg, self.sstable_reader.clone(), level);
+        cfg.size_tiered_min_tables = 2;
+        // the compaction thread waits until every level has been compacted
+        let _compacting = self.compaction_lock.lock().unwrap();
+        for level in 0..=cfg.compaction_max_levels {
+            compact(
+                &cfg,
+                &self.manifest,
+                self.sstable_reader.clone(),
+                level,
+                &self.snapshots.seqs(),
+                self.compaction_filter.as_deref(),
+                &self.compaction_stats,
+            )?;
         }
+        Ok(())
+    }
+
+    pub fn force_flush(&self) -> Result<()> {
+        self.check_open()?;
+        self.flush_writable_memtable(&mut self.writer.lock().unwrap())
     }
 
-    pub fn force_flush(&mut self) {
-        self.flush_writable_memtable()
+    // quarantine or delete any files that don't belong to a live sstable or WAL
+    pub fn collect_orphans(&self) -> Result<orphan::Report> {
+        // hold the locks while the files are checked so no sstables are added or removed
+        let reader = self.sstable_reader.read().unwrap();
+        let manifest = self.manifest.lock().unwrap();
+        let mut wal_ids = vec![self.writable_table().id.clone()];
+        for mt in self.flushing_memtables.read().unwrap().iter() {
+            wal_ids.push(mt.id.clone());
+        }
+        Ok(orphan::collect(
+            &self.config,
+            &live_files(&manifest, &reader, wal_ids),
+        )?)
     }
 
-    pub fn write(&mut self, key: &[u8], value: &[u8]) {
-        self.writable_wal.write(key, Some(value)).unwrap();
-        self.writable_table
-            .insert(key.to_vec(), Some(value.to_vec()));
+    // writes return a waiter that callers can use to block until the write is durable. If a ttl
+    // is given, the value is treated as deleted once it has passed
+    pub fn write(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: Option<time::Duration>,
+    ) -> Result<wal::SyncWaiter> {
+        self.stall_writes()?;
+        let expires_at =
+            ttl.map(|ttl| sstable::now_millis().saturating_add(ttl.as_millis() as u64));
+        let mut writer = self.writer.lock().unwrap();
+        let seq = self.last_seq.load(Ordering::SeqCst) + 1;
+        let waiter = writer
+            .wal
+            .write_expiring(key, Some(value), seq, expires_at)
+            .map_err(|err| self.wal_error(err))?;
+        let version = memtable::Version {
+            key: key.to_vec(),
+            value: Some(value.to_vec()),
+            seq,
+            expires_at,
+        };
+        self.writable_table()
+            .insert_version(version, &self.snapshots.seqs());
+        self.last_seq.store(seq, Ordering::SeqCst);
 
-        print!("size {}", self.writable_table.size());
-        if self.writable_table.size() > self.config.memtable_max_count {
-            self.flush_writable_memtable();
+        self.flush_if_full(&mut writer)?;
+        Ok(waiter)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<wal::SyncWaiter> {
+        self.stall_writes()?;
+        let mut writer = self.writer.lock().unwrap();
+        let seq = self.last_seq.load(Ordering::SeqCst) + 1;
+        let waiter = writer
+            .wal
+            .write(key, None, seq)
+            .map_err(|err| self.wal_error(err))?;
+        self.writable_table()
+            .insert_retaining(key.to_vec(), None, seq, &self.snapshots.seqs());
+        self.last_seq.store(seq, Ordering::SeqCst);
+        self.flush_if_full(&mut writer)?;
+        Ok(waiter)
+    }
+
+    // delete every key in [start, end) with a single range tombstone
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<wal::SyncWaiter> {
+        self.stall_writes()?;
+        let mut writer = self.writer.lock().unwrap();
+        if start >= end {
+            return Ok(writer.wal.sync_waiter());
+        }
+
+        let seq = self.last_seq.load(Ordering::SeqCst) + 1;
+        let waiter = writer
+            .wal
+            .delete_range(start, end, seq)
+            .map_err(|err| self.wal_error(err))?;
+        self.writable_table()
+            .delete_range(start.to_vec(), end.to_vec(), seq);
+        self.last_seq.store(seq, Ordering::SeqCst);
+        self.flush_if_full(&mut writer)?;
+        Ok(waiter)
+    }
+
+    // apply all the puts and deletes in the batch atomically
+    pub fn write_batch(&self, batch: &batch::WriteBatch) -> Result<wal::SyncWaiter> {
+        self.stall_writes()?;
+        let mut writer = self.writer.lock().unwrap();
+        if batch.is_empty() {
+            return Ok(writer.wal.sync_waiter());
+        }
+
+        let first_seq = self.last_seq.load(Ordering::SeqCst) + 1;
+        let waiter = writer
+            .wal
+            .write_batch(batch, first_seq)
+            .map_err(|err| self.wal_error(err))?;
+        let writable_table = self.writable_table();
+        let snapshots = self.snapshots.seqs();
+        let mut seq = first_seq;
+        for (key, value) in batch.iter() {
+            writable_table.insert_retaining(key.clone(), value.clone(), seq, &snapshots);
+            seq += 1;
+        }
+        // the whole batch becomes visible to snapshots at once
+        self.last_seq.store(seq - 1, Ordering::SeqCst);
+        self.flush_if_full(&mut writer)?;
+        Ok(waiter)
+    }
+
+    // a failed write may have left a torn record in the WAL, and recovery stops at the first bad
+    // record, so nothing more can be written to it. The engine is read only until it's resumed,
+    // which moves writes to a new WAL
+    fn wal_error(&self, err: io::Error) -> Error {
+        set_background_error(
+            &self.background_error,
+            format!("error writing to WAL: {}", err),
+        );
+        Error::Io(err)
+    }
+
+    fn writable_table(&self) -> Arc<memtable::Memtable> {
+        self.writable_table.read().unwrap().clone()
+    }
+
+    fn flush_if_full(&self, writer: &mut Writer) -> Result<()> {
+        if self.writable_table().approximate_size() as u64 >= self.config.memtable_max_bytes {
+            self.flush_writable_memtable(writer)?;
         }
+        Ok(())
     }
 
-    pub fn delete(&mut self, key: &[u8]) {
-        self.writable_wal.write(key, None).unwrap();
-        self.writable_table.insert(key.to_vec(), None);
-        if self.writable_table.size() > self.config.memtable_max_count {
-            self.flush_writable_memtable();
+    fn check_open(&self) -> Result<()> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        if let Some(err) = self.background_error() {
+            return Err(Error::ReadOnly(err));
         }
+        Ok(())
     }
 
-    fn flush_writable_memtable(&mut self) {
-        let mut tmp = memtable::Memtable::new();
-        let mut new_wal = wal::Wal::new(tmp.id.clone());
-        std::mem::swap(&mut self.writable_table, &mut tmp);
-        std::mem::swap(&mut self.writable_wal, &mut new_wal);
+    // delay or block the caller if flushing or compaction has fallen behind. The background
+    // threads don't need the engine, so they can make progress while a writer is blocked here
+    fn stall_writes(&self) -> Result<()> {
+        self.write_controller
+            .stall(&self.config, || self.write_condition())
+    }
+
+    fn write_condition(&self) -> Result<stall::Condition> {
+        self.check_open()?;
+        let immutable_memtables = self.flushing_memtables.read().unwrap().len();
+        let l0_files = self.manifest.lock().unwrap().files_at_level(0).len();
+        Ok(stall::condition(
+            &self.config,
+            immutable_memtables,
+            l0_files,
+        ))
+    }
 
-        log::debug!("sending memtable to flush (id: {:?})", tmp.id);
-        let mt_pointer = Arc::new(tmp);
+    pub fn stall_stats(&self) -> StallStats {
+        self.write_controller.stats()
+    }
+
+    pub fn compaction_stats(&self) -> compact::CompactionStats {
+        self.compaction_stats.lock().unwrap().clone()
+    }
+
+    // the error that made the engine read only, if there is one
+    pub fn background_error(&self) -> Option<String> {
+        self.background_error.lock().unwrap().clone()
+    }
+
+    // clear the background error once the cause has been fixed, so the engine accepts writes
+    // again. Flushes that were stopped by the error are tried again
+    pub fn resume(&self) -> Result<()> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(err) = self.background_error.lock().unwrap().take() {
+            log::info!("resuming after background error: {}", err);
+        }
+        // the memtable has every write that succeeded, so flushing it makes them durable
+        // whatever state its WAL was left in
+        if writer.wal.error().is_some() {
+            self.flush_writable_memtable(&mut writer)?;
+        }
+        self.send_flush_request(FlushRequest::Retry)
+    }
+
+    fn send_flush_request(&self, request: FlushRequest) -> Result<()> {
+        let sender = self.flush_sender.lock().unwrap();
+        let flush_result = sender.as_ref().unwrap().send(request);
+        flush_result.map_err(|_| Error::Io(io::Error::other("flush thread has stopped")))
+    }
+
+    // the writer lock must be held, so nothing is written to the memtable once it's been sent to
+    // be flushed
+    fn flush_writable_memtable(&self, writer: &mut Writer) -> Result<()> {
+        let new_table = memtable::Memtable::with_rep(&self.config.memtable_rep);
+        writer.wal = wal::Wal::new(&self.config, writer.next_wal_seq, new_table.id.clone())?;
+        writer.next_wal_seq += 1;
+
+        // the memtable is added to the flushing memtables before it's replaced, so a reader
+        // always finds it in one or the other
+        let mt_pointer = self.writable_table();
+        log::debug!("sending memtable to flush (id: {:?})", mt_pointer.id);
         self.flushing_memtables
             .write()
             .unwrap()
             .push(mt_pointer.clone());
-        let sender = self.flush_sender.lock().unwrap();
-        let flush_result = sender.send(mt_pointer.clone());
-        flush_result.unwrap();
+        *self.writable_table.write().unwrap() = Arc::new(new_table);
+        self.send_flush_request(FlushRequest::Flush(mt_pointer))
+    }
+
+    // take a snapshot of the current state of the database. Reads made with the snapshot won't
+    // see any writes made after it was taken. Older versions of keys are kept around until the
+    // snapshot is dropped
+    pub fn snapshot(&self) -> snapshot::Snapshot {
+        // hold the writer lock so a write that's in progress can't drop a version the snapshot
+        // still needs
+        let _writer = self.writer.lock().unwrap();
+        self.snapshots.acquire(self.last_seq.load(Ordering::SeqCst))
     }
 
-    pub fn find(&self, key: &[u8]) -> Option<Vec<u8>> {
+    // find the value of the key. If a snapshot is given, the value as of when the snapshot was
+    // taken is returned
+    pub fn find(
+        &self,
+        key: &[u8],
+        snapshot: Option<&snapshot::Snapshot>,
+    ) -> Result<Option<Vec<u8>>> {
         log::debug!("searching for key {:?}", key);
-        let (val_found, found) = self.writable_table.search(key);
+        let read_seq = snapshot.map_or(u64::MAX, |s| s.seq());
+        let writable_table = self.writable_table();
+        let (val_found, found) = writable_table.search_at(key, read_seq);
         if val_found.is_some() {
             if log::log_enabled!(log::Level::Debug) {
                 log::debug!(
                     "found '{:?}' in writable memtable (id: {}). value: '{:?}'",
                     key,
-                    self.writable_table.id,
+                    writable_table.id,
                     val_found.as_ref().unwrap()
                 );
             }
-            return val_found;
+            return Ok(val_found);
         };
         if found {
-            return None;
+            return Ok(None);
         }
 
+        // search the flushing memtables newest to oldest
         let mts: &Vec<Arc<memtable::Memtable>> = &self.flushing_memtables.read().unwrap();
-        for mt in mts {
-            let (val_found, found) = mt.search(&key);
+        for mt in mts.iter().rev() {
+            let (val_found, found) = mt.search_at(key, read_seq);
             if val_found.is_some() {
                 if log::log_enabled!(log::Level::Debug) {
                     log::debug!(
@@ -210,14 +627,14 @@ impl Engine {
                         val_found.as_ref().unwrap()
                     );
                 }
-                return val_found;
+                return Ok(val_found);
             }
             if found {
-                return None;
+                return Ok(None);
             }
         }
 
-        let disk_result = self.sstable_reader.read().unwrap().find(key);
+        let disk_result = self.sstable_reader.read().unwrap().find_at(key, read_seq)?;
         if disk_result.is_some() {
             if log::log_enabled!(log::Level::Debug) {
                 log::debug!(
@@ -226,28 +643,489 @@ impl Engine {
                     disk_result.as_ref().unwrap()
                 );
             }
-            return disk_result;
+            return Ok(disk_result);
         }
 
         log::debug!("key '{:?}' not found", key);
-        return None;
+        Ok(None)
+    }
+
+    // iterate the keys in the range [start, end) in ascending order. If start or end are None the
+    // range is unbounded on that side. If a snapshot is given, the values as of when the snapshot
+    // was taken are returned. The iterator returns an error if an sstable can't be read
+    pub fn scan(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        snapshot: Option<&snapshot::Snapshot>,
+    ) -> Result<merge::ScanIterator> {
+        self.scan_range(start, end, false, snapshot)
+    }
+
+    // iterate the keys in the range [start, end) in descending order
+    pub fn scan_reverse(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        snapshot: Option<&snapshot::Snapshot>,
+    ) -> Result<merge::ScanIterator> {
+        self.scan_range(start, end, true, snapshot)
+    }
+
+    fn scan_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        reverse: bool,
+        snapshot: Option<&snapshot::Snapshot>,
+    ) -> Result<merge::ScanIterator> {
+        let memtable_iter = |mt: &memtable::Memtable| {
+            if reverse {
+                merge::memtable_source(mt.iter_rev_from(end))
+            } else {
+                merge::memtable_source(mt.iter_from(start))
+            }
+        };
+
+        // sources are ordered newest to oldest. The flushing memtables must be read before the
+        // sstables, otherwise a memtable that finishes flushing in between could be missed
+        let writable_table = self.writable_table();
+        let mut sources = vec![memtable_iter(&writable_table)];
+        let mut range_tombstones = writable_table.range_tombstones();
+        for mt in self.flushing_memtables.read().unwrap().iter().rev() {
+            sources.push(memtable_iter(mt));
+            range_tombstones.extend(mt.range_tombstones());
+        }
+
+        let sstable_reader = self.sstable_reader.read().unwrap();
+        for iter in sstable_reader.iters(start, end, reverse)? {
+            sources.push(merge::sstable_source(iter));
+        }
+        range_tombstones.extend(sstable_reader.range_tombstones());
+
+        let merged = merge::MergeIterator::new(sources, reverse, snapshot.map(|s| s.seq()))
+            .with_range_tombstones(range_tombstones);
+        Ok(merge::ScanIterator::new(merged, start, end, reverse))
+    }
+}
+
+impl Drop for Engine {
+    // close without flushing, the writable memtable is recovered from its WAL on the next open
+    fn drop(&mut self) {
+        if let Err(err) = self.close(false) {
+            log::error!("error closing engine: {:?}", err);
+        }
+    }
+}
+
+// the sstables and WALs that are in use. The writable and flushing memtables' WALs must be given
+fn live_files(
+    manifest: &manifest::Manifest,
+    reader: &sstable::reader::Reader,
+    wal_ids: Vec<String>,
+) -> orphan::LiveFiles {
+    let mut sstable_ids: BTreeSet<String> = manifest.files().map(|file| file.id.clone()).collect();
+    // tables that were compacted are still read from until their files are deleted
+    sstable_ids.extend(reader.ids());
+    orphan::LiveFiles {
+        sstable_ids,
+        pending_sstable_ids: manifest.pending().cloned().collect(),
+        wal_ids: wal_ids.into_iter().collect(),
     }
 }
 
+fn set_background_error(background_error: &Mutex<Option<String>>, err: String) {
+    log::error!("{}. The engine is read only until it's resumed", err);
+    background_error.lock().unwrap().get_or_insert(err);
+}
+
+// flush the memtable to a new sstable and commit it to the MANIFEST, then move it from the
+// flushing memtables to the reader
+fn flush(
+    config: &config::Config,
+    manifest: &Mutex<manifest::Manifest>,
+    reader_ptr: &RwLock<sstable::reader::Reader>,
+    flushing_memtables: &RwLock<Vec<Arc<memtable::Memtable>>>,
+    memtable: &Arc<memtable::Memtable>,
+) -> Result<()> {
+    manifest.lock().unwrap().add_pending(&memtable.id);
+    let file_meta = sstable::flush_to_sstable(config, memtable, 0)?;
+
+    // commit the new sstable. Once it's in the MANIFEST the WAL isn't needed anymore
+    let edit = manifest::VersionEdit {
+        added: vec![file_meta],
+        removed: vec![],
+    };
+    manifest.lock().unwrap().apply(edit)?;
+
+    // a WAL that can't be deleted now is deleted during recovery, because its sstable is live
+    if let Err(err) = wal::delete_by_id(config, &memtable.id) {
+        log::warn!("error deleting WAL of memtable {}: {:?}", memtable.id, err);
+    }
+
+    // signal to the reader that there's a new memtable to read
+    let mut reader = reader_ptr.write().unwrap();
+    reader.add_memtable(memtable)?;
+
+    // remove the memtable from the list of flushing memtables
+    let mut memtables = flushing_memtables.write().unwrap();
+    if let Some(position) = memtables.iter().position(|v| Arc::ptr_eq(v, memtable)) {
+        log::debug!(
+            "removing flushing memtable at position {:?}. There are now {:?} flushing memtables",
+            position,
+            memtables.len() - 1
+        );
+        memtables.remove(position);
+    }
+    Ok(())
+}
+
+// compact the level and swap the new tables for the old ones in the reader. returns whether the
+// level needed compaction
 pub fn compact(
     config: &config::Config,
+    manifest: &Mutex<manifest::Manifest>,
     compact_reader_ptr: Arc<RwLock<sstable::reader::Reader>>,
     level: u8,
-) {
-    let compact_result_o = compact::compact(&config, level);
-    if compact_result_o.is_some() {
-        let (new_memtable, compacted_memtable_ids) = compact_result_o.unwrap();
-        let mut reader = compact_reader_ptr.write().unwrap();
-        reader.add_memtable(&new_memtable);
+    snapshots: &[u64],
+    filter: Option<&dyn compact::CompactionFilter>,
+    stats: &Mutex<compact::CompactionStats>,
+) -> Result<bool> {
+    let compacted = compact::compact(config, manifest, level, snapshots, filter, stats)?;
+    let Some((new_memtables, compacted_memtable_ids)) = compacted else {
+        return Ok(false);
+    };
+
+    let mut reader = compact_reader_ptr.write().unwrap();
+    for new_memtable in &new_memtables {
+        reader.add_memtable(new_memtable)?;
+    }
+    for sstable_id in &compacted_memtable_ids {
+        reader.remove_memtable(sstable_id);
+        // the table isn't live anymore, so files that can't be deleted now are orphans
+        if let Err(err) = sstable::delete_by_id(config, sstable_id) {
+            log::warn!("error deleting compacted sstable {}: {:?}", sstable_id, err);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod engine_tests {
+    use super::*;
+    use std::fs;
+
+    fn test_config(test_name: &str) -> config::Config {
+        let data_dir = format!("/tmp/engine_tests/{}", test_name);
+        fs::remove_dir_all(&data_dir).ok();
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = data_dir;
+        config.memtable_max_bytes = 1 << 20;
+        config
+    }
+
+    #[test]
+    fn it_flushes_on_close() {
+        let config = test_config("it_flushes_on_close");
+        let mut engine = Engine::new(config.clone()).unwrap();
+        engine.write(b"abc", b"1", None).unwrap().wait().unwrap();
+        engine.write(b"def", b"2", None).unwrap().wait().unwrap();
+        engine.close(true).unwrap();
+
+        // writes are rejected once the engine is closed, but reads still work
+        assert_eq!(true, engine.write(b"ghi", b"3", None).is_err());
+        assert_eq!(Some(b"1".to_vec()), engine.find(b"abc", None).unwrap());
+        drop(engine);
+
+        // the memtable was flushed, so nothing is left to recover from the WAL
+        assert_eq!(
+            1,
+            manifest::Manifest::open(&config).unwrap().files().count()
+        );
+        let engine = Engine::new(config.clone()).unwrap();
+        assert_eq!(0, engine.writable_table().size());
+        assert_eq!(Some(b"2".to_vec()), engine.find(b"def", None).unwrap());
+        drop(engine);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_recovers_unflushed_writes_after_being_dropped() {
+        let config = test_config("it_recovers_unflushed_writes_after_being_dropped");
+        let engine = Engine::new(config.clone()).unwrap();
+        engine.write(b"abc", b"1", None).unwrap().wait().unwrap();
+        drop(engine);
+
+        let engine = Engine::new(config.clone()).unwrap();
+        assert_eq!(0, engine.manifest.lock().unwrap().files().count());
+        assert_eq!(Some(b"1".to_vec()), engine.find(b"abc", None).unwrap());
+        drop(engine);
 
-        for sstable_id in &compacted_memtable_ids {
-            reader.remove_memtable(sstable_id);
-            sstable::delete_by_id(&config, sstable_id).unwrap();
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    // poll until the condition holds, so tests don't depend on how fast the flush thread is
+    fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(10));
         }
+        panic!("timed out waiting for condition");
+    }
+
+    #[test]
+    fn it_is_read_only_after_a_flush_fails_until_resumed() {
+        let config = test_config("it_is_read_only_after_a_flush_fails_until_resumed");
+        let engine = Engine::new(config.clone()).unwrap();
+        engine.write(b"abc", b"1", None).unwrap().wait().unwrap();
+
+        // a directory where the sstable should be written makes the flush fail
+        let blocker = format!(
+            "{}/sstable-data-{}{}",
+            config.data_dir,
+            engine.writable_table().id,
+            sstable::TMP_SUFFIX
+        );
+        fs::create_dir(&blocker).unwrap();
+        engine.force_flush().unwrap();
+        wait_until(|| engine.background_error().is_some());
+
+        // writes are rejected, but the memtable that failed to flush can still be read
+        let result = engine.write(b"def", b"2", None);
+        assert_eq!(true, matches!(result, Err(Error::ReadOnly(_))));
+        assert_eq!(Some(b"1".to_vec()), engine.find(b"abc", None).unwrap());
+
+        // once the problem is fixed, resuming retries the flush
+        fs::remove_dir(&blocker).unwrap();
+        engine.resume().unwrap();
+        wait_until(|| engine.flushing_memtables.read().unwrap().is_empty());
+        assert_eq!(None, engine.background_error());
+        assert_eq!(1, engine.manifest.lock().unwrap().files().count());
+        engine.write(b"def", b"2", None).unwrap().wait().unwrap();
+        assert_eq!(Some(b"1".to_vec()), engine.find(b"abc", None).unwrap());
+        drop(engine);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_deletes_ranges_across_memtables_and_sstables() {
+        let config = test_config("it_deletes_ranges_across_memtables_and_sstables");
+        let engine = Engine::new(config.clone()).unwrap();
+        for key in [b"a", b"b", b"c", b"d"] {
+            engine.write(key, b"old", None).unwrap().wait().unwrap();
+        }
+        engine.force_flush().unwrap();
+        wait_until(|| engine.flushing_memtables.read().unwrap().is_empty());
+
+        let snapshot = engine.snapshot();
+        engine.delete_range(b"b", b"d").unwrap().wait().unwrap();
+        engine.write(b"c", b"new", None).unwrap().wait().unwrap();
+
+        let scan = |engine: &Engine| -> Vec<(Vec<u8>, Vec<u8>)> {
+            engine
+                .scan(None, None, None)
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap()
+        };
+        let expected = vec![
+            (b"a".to_vec(), b"old".to_vec()),
+            (b"c".to_vec(), b"new".to_vec()),
+            (b"d".to_vec(), b"old".to_vec()),
+        ];
+        assert_eq!(None, engine.find(b"b", None).unwrap());
+        assert_eq!(Some(b"new".to_vec()), engine.find(b"c", None).unwrap());
+        assert_eq!(expected, scan(&engine));
+        assert_eq!(
+            Some(b"old".to_vec()),
+            engine.find(b"b", Some(&snapshot)).unwrap()
+        );
+        drop(snapshot);
+        drop(engine);
+
+        // the tombstone is recovered from the WAL, and then flushed with the memtable
+        let mut engine = Engine::new(config.clone()).unwrap();
+        assert_eq!(None, engine.find(b"b", None).unwrap());
+        assert_eq!(expected, scan(&engine));
+        engine.close(true).unwrap();
+        drop(engine);
+
+        let engine = Engine::new(config.clone()).unwrap();
+        assert_eq!(true, engine.writable_table().is_empty());
+        assert_eq!(None, engine.find(b"b", None).unwrap());
+        assert_eq!(expected, scan(&engine));
+        drop(engine);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_accepts_writes_from_many_threads() {
+        let mut config = test_config("it_accepts_writes_from_many_threads");
+        // small enough that memtables are swapped while the threads are writing
+        config.memtable_max_bytes = 1 << 10;
+        let engine = Engine::new(config.clone()).unwrap();
+        thread::scope(|scope| {
+            for i in 0..4 {
+                let engine = &engine;
+                scope.spawn(move || {
+                    for j in 0..100 {
+                        let key = format!("{}-{}", i, j);
+                        engine.write(key.as_bytes(), b"1", None).unwrap();
+                        assert_eq!(
+                            Some(b"1".to_vec()),
+                            engine.find(key.as_bytes(), None).unwrap()
+                        );
+                    }
+                });
+            }
+        });
+
+        // every write got its own sequence number
+        assert_eq!(400, engine.last_seq.load(Ordering::SeqCst));
+        let scan = engine.scan(None, None, None).unwrap();
+        assert_eq!(400, scan.collect::<Result<Vec<_>>>().unwrap().len());
+        drop(engine);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_is_read_only_after_a_wal_write_fails_until_resumed() {
+        let config = test_config("it_is_read_only_after_a_wal_write_fails_until_resumed");
+        let engine = Engine::new(config.clone()).unwrap();
+        engine.write(b"abc", b"1", None).unwrap().wait().unwrap();
+
+        engine.writer.lock().unwrap().wal.fail_writes();
+        assert_eq!(true, engine.write(b"def", b"2", None).is_err());
+        assert_eq!(true, engine.background_error().is_some());
+        let result = engine.write(b"ghi", b"3", None);
+        assert_eq!(true, matches!(result, Err(Error::ReadOnly(_))));
+
+        // resuming moves writes to a new WAL, and the memtable is flushed with the writes that
+        // made it into the old one
+        engine.resume().unwrap();
+        engine.write(b"ghi", b"3", None).unwrap().wait().unwrap();
+        wait_until(|| engine.flushing_memtables.read().unwrap().is_empty());
+        drop(engine);
+
+        let engine = Engine::new(config.clone()).unwrap();
+        assert_eq!(Some(b"1".to_vec()), engine.find(b"abc", None).unwrap());
+        assert_eq!(None, engine.find(b"def", None).unwrap());
+        assert_eq!(Some(b"3".to_vec()), engine.find(b"ghi", None).unwrap());
+        drop(engine);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_refuses_to_force_a_compaction_once_closed() {
+        let config = test_config("it_refuses_to_force_a_compaction_once_closed");
+        let mut engine = Engine::new(config.clone()).unwrap();
+        engine.write(b"abc", b"1", None).unwrap().wait().unwrap();
+        engine.close(true).unwrap();
+
+        let result = engine.force_compact();
+        assert_eq!(true, matches!(result, Err(Error::Closed)));
+        assert_eq!(1, engine.manifest.lock().unwrap().files_at_level(0).len());
+        drop(engine);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    struct DropEverything;
+
+    impl compact::CompactionFilter for DropEverything {
+        fn filter(&self, _level: u8, _key: &[u8], _value: &[u8]) -> compact::FilterDecision {
+            compact::FilterDecision::Remove
+        }
+    }
+
+    #[test]
+    fn it_applies_the_compaction_filter_when_compacting() {
+        let config = test_config("it_applies_the_compaction_filter_when_compacting");
+        let engine =
+            Engine::with_compaction_filter(config.clone(), Arc::new(DropEverything)).unwrap();
+        engine.write(b"abc", b"1", None).unwrap().wait().unwrap();
+        engine.force_flush().unwrap();
+        wait_until(|| engine.flushing_memtables.read().unwrap().is_empty());
+        assert_eq!(Some(b"1".to_vec()), engine.find(b"abc", None).unwrap());
+
+        engine.force_compact().unwrap();
+        assert_eq!(None, engine.find(b"abc", None).unwrap());
+        drop(engine);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_waits_for_a_running_compaction_when_forced() {
+        let config = test_config("it_waits_for_a_running_compaction_when_forced");
+        let mut engine = Engine::new(config.clone()).unwrap();
+        engine.write(b"abc", b"1", None).unwrap().wait().unwrap();
+        engine.force_flush().unwrap();
+        wait_until(|| engine.flushing_memtables.read().unwrap().is_empty());
+
+        // stands in for the compaction thread being in the middle of a compaction
+        let compacting = engine.compaction_lock.lock().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| sender.send(engine.force_compact()).unwrap());
+            let timeout = time::Duration::from_millis(100);
+            assert_eq!(true, receiver.recv_timeout(timeout).is_err());
+            drop(compacting);
+            assert_eq!(true, receiver.recv().unwrap().is_ok());
+        });
+        {
+            let manifest = engine.manifest.lock().unwrap();
+            assert_eq!(0, manifest.files_at_level(0).len());
+            assert_eq!(1, manifest.files().count());
+        }
+        engine.close(false).unwrap();
+        drop(engine);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_treats_expired_values_as_missing() {
+        let config = test_config("it_treats_expired_values_as_missing");
+        let engine = Engine::new(config.clone()).unwrap();
+        engine.write(b"a", b"old", None).unwrap().wait().unwrap();
+        engine.force_flush().unwrap();
+        wait_until(|| engine.flushing_memtables.read().unwrap().is_empty());
+
+        let ttl = time::Duration::from_millis(50);
+        engine.write(b"a", b"1", Some(ttl)).unwrap().wait().unwrap();
+        engine.write(b"b", b"2", Some(ttl)).unwrap().wait().unwrap();
+        engine.write(b"c", b"3", None).unwrap().wait().unwrap();
+        assert_eq!(Some(b"1".to_vec()), engine.find(b"a", None).unwrap());
+
+        // expired values hide older versions, whether they're in a memtable or an sstable
+        thread::sleep(ttl);
+        let scan = |engine: &Engine| -> Vec<(Vec<u8>, Vec<u8>)> {
+            engine
+                .scan(None, None, None)
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap()
+        };
+        assert_eq!(None, engine.find(b"a", None).unwrap());
+        assert_eq!(None, engine.find(b"b", None).unwrap());
+        assert_eq!(vec![(b"c".to_vec(), b"3".to_vec())], scan(&engine));
+
+        engine.force_flush().unwrap();
+        wait_until(|| engine.flushing_memtables.read().unwrap().is_empty());
+        assert_eq!(None, engine.find(b"a", None).unwrap());
+        assert_eq!(vec![(b"c".to_vec(), b"3".to_vec())], scan(&engine));
+        drop(engine);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
     }
 }
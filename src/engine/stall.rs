@@ -0,0 +1,168 @@
+// Slows down and then stops writes when flushing or compaction falls behind, so that memtables
+// waiting to be flushed and sstables at level 0 can't pile up without bound. Stopped writers wait
+// until the background threads report progress and the condition clears.
+
+use serde::Serialize;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time;
+
+use crate::config;
+use crate::error::Result;
+
+// how long a stopped writer waits before checking the condition again, in case it missed a
+// notification
+const STOP_RECHECK_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    Normal,
+    Slowdown,
+    Stop,
+}
+
+// decide how writes should be throttled given the number of memtables waiting to be flushed and
+// the number of sstables at level 0
+pub fn condition(
+    config: &config::Config,
+    immutable_memtables: usize,
+    l0_files: usize,
+) -> Condition {
+    if immutable_memtables >= config.immutable_memtables_stop || l0_files >= config.l0_files_stop {
+        Condition::Stop
+    } else if immutable_memtables >= config.immutable_memtables_slowdown
+        || l0_files >= config.l0_files_slowdown
+    {
+        Condition::Slowdown
+    } else {
+        Condition::Normal
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StallStats {
+    // number of writes that were delayed and the total time they were delayed for (millis)
+    pub slowdowns: u64,
+    pub slowdown_ms: u64,
+    // number of writes that had to wait for the background work to catch up and the total time
+    // they waited for (millis)
+    pub stops: u64,
+    pub stop_ms: u64,
+}
+
+#[derive(Default)]
+pub struct WriteController {
+    stats: Mutex<StallStats>,
+    // notified whenever a flush or compaction finishes. The mutex only exists for the condvar,
+    // and the check is made without holding it so it can take the engine's locks
+    progress_lock: Mutex<()>,
+    progress: Condvar,
+}
+
+impl WriteController {
+    pub fn new() -> Self {
+        WriteController::default()
+    }
+
+    // wake up stopped writers so they check the condition again
+    pub fn notify_progress(&self) {
+        let _guard = self.progress_lock.lock().unwrap();
+        self.progress.notify_all();
+    }
+
+    pub fn stats(&self) -> StallStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    // delay or block the calling writer depending on the condition. check is called again each
+    // time the background work makes progress, and any error it returns is returned to the writer
+    pub fn stall(
+        &self,
+        config: &config::Config,
+        check: impl Fn() -> Result<Condition>,
+    ) -> Result<()> {
+        match check()? {
+            Condition::Normal => Ok(()),
+            Condition::Slowdown => {
+                let delay = time::Duration::from_millis(config.write_slowdown_delay);
+                thread::sleep(delay);
+                let mut stats = self.stats.lock().unwrap();
+                stats.slowdowns += 1;
+                stats.slowdown_ms += delay.as_millis() as u64;
+                Ok(())
+            }
+            Condition::Stop => {
+                log::warn!("stopping writes until flushing and compaction catch up");
+                let start = time::Instant::now();
+                let result = loop {
+                    let guard = self.progress_lock.lock().unwrap();
+                    drop(
+                        self.progress
+                            .wait_timeout(guard, STOP_RECHECK_INTERVAL)
+                            .unwrap(),
+                    );
+                    match check() {
+                        Ok(Condition::Stop) => continue,
+                        Ok(_) => break Ok(()),
+                        Err(err) => break Err(err),
+                    }
+                };
+                let elapsed = start.elapsed();
+                let mut stats = self.stats.lock().unwrap();
+                stats.stops += 1;
+                stats.stop_ms += elapsed.as_millis() as u64;
+                log::info!("writes resumed after {:?}", elapsed);
+                result
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod stall_tests {
+    use super::*;
+    use crate::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn it_throttles_writes_by_the_amount_of_pending_work() {
+        let config = config::Config::new();
+        assert_eq!(Condition::Normal, condition(&config, 1, 7));
+        assert_eq!(Condition::Slowdown, condition(&config, 2, 0));
+        assert_eq!(Condition::Slowdown, condition(&config, 0, 8));
+        assert_eq!(Condition::Stop, condition(&config, 4, 0));
+        assert_eq!(Condition::Stop, condition(&config, 0, 12));
+    }
+
+    #[test]
+    fn it_stops_writes_until_there_is_progress() {
+        let config = config::Config::new();
+        let controller = Arc::new(WriteController::new());
+        let pending = Arc::new(AtomicUsize::new(4));
+
+        let background = controller.clone();
+        let background_pending = pending.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(20));
+            background_pending.store(1, Ordering::SeqCst);
+            background.notify_progress();
+        });
+
+        let check = || Ok(condition(&config, pending.load(Ordering::SeqCst), 0));
+        controller.stall(&config, check).unwrap();
+        handle.join().unwrap();
+        controller
+            .stall(&config, || Ok(Condition::Slowdown))
+            .unwrap();
+
+        let stats = controller.stats();
+        assert_eq!(1, stats.stops);
+        assert_eq!(true, stats.stop_ms >= 20);
+        assert_eq!(1, stats.slowdowns);
+
+        // errors end the stall
+        let result = controller.stall(&config, || Err(Error::Closed));
+        assert_eq!(true, matches!(result, Err(Error::Closed)));
+    }
+}
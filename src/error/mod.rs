@@ -0,0 +1,91 @@
+// The errors returned by the database. Most of them come from the file system, but errors that
+// mean the data on disk can't be trusted are kept apart so callers can tell them from an I/O error
+// that may go away if retried.
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    // reading or writing a file failed
+    Io(io::Error),
+    // data on disk couldn't be decoded, e.g. a truncated block or an invalid meta file
+    Corruption(String),
+    // a file that should exist doesn't, e.g. a live sstable whose files are gone
+    NotFound(String),
+    // the config is invalid
+    Config(String),
+    // the engine was closed and doesn't accept writes anymore
+    Closed,
+    // a flush or compaction failed, so the engine doesn't accept writes until it's resumed
+    ReadOnly(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Corruption(msg) => write!(f, "corruption: {}", msg),
+            Error::NotFound(msg) => write!(f, "not found: {}", msg),
+            Error::Config(msg) => write!(f, "invalid config: {}", msg),
+            Error::Closed => write!(f, "engine is closed"),
+            Error::ReadOnly(msg) => write!(f, "engine is read only: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// the decoders in this crate report bad data as InvalidData or UnexpectedEof, so those are
+// corruption rather than I/O errors
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                Error::Corruption(err.to_string())
+            }
+            io::ErrorKind::NotFound => Error::NotFound(err.to_string()),
+            _ => Error::Io(err),
+        }
+    }
+}
+
+// lets the frontends, which deal in io::Result, use ? on the engine's results
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::Corruption(_) => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+            Error::NotFound(_) => io::Error::new(io::ErrorKind::NotFound, err.to_string()),
+            Error::Config(_) => io::Error::new(io::ErrorKind::InvalidInput, err.to_string()),
+            Error::Closed | Error::ReadOnly(_) => io::Error::other(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::*;
+
+    #[test]
+    fn it_classifies_io_errors() {
+        let err: Error = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated").into();
+        assert_eq!(true, matches!(err, Error::Corruption(_)));
+        let err: Error = io::Error::new(io::ErrorKind::NotFound, "missing").into();
+        assert_eq!(true, matches!(err, Error::NotFound(_)));
+        let err: Error = io::Error::new(io::ErrorKind::PermissionDenied, "denied").into();
+        assert_eq!(true, matches!(err, Error::Io(_)));
+
+        let err: io::Error = Error::Closed.into();
+        assert_eq!("engine is closed", err.to_string());
+    }
+}
@@ -3,13 +3,17 @@ use std::io::Result;
 
 use futures::lock::Mutex;
 use log;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::str;
 use std::sync::{Arc, RwLock};
+use std::time;
 
+use crate::batch::WriteBatch;
 use crate::config::Config;
 use crate::engine::Engine;
+use crate::error::Error;
 use crate::ring;
+use crate::wal::SyncWaiter;
 
 pub async fn start(config: Config) -> Result<()> {
     env_logger::init();
@@ -29,30 +33,38 @@ pub async fn start(config: Config) -> Result<()> {
             .unwrap();
     });
 
+    // the engine is shared by all the workers. It locks the data directory, so there can only
+    // be one
+    let engine = Engine::new(config.clone())?;
+    let mmt_arc = Arc::new(RwLock::new(engine));
+    let server_mmt_arc = mmt_arc.clone();
+
     let web_cfg = config.clone();
     let server = HttpServer::new(move || {
         App::new()
             .configure(|cfg| {
-              configure(web_cfg.clone(), cfg, ring_arc.clone())
+              configure(web_cfg.clone(), cfg, server_mmt_arc.clone(), ring_arc.clone())
             })
     });
 
-    server
+    // the server handles SIGTERM (and SIGINT) by finishing the requests in flight and returning.
+    // Then flush what's in memory and wait for the background work to stop before exiting
+    let result = server
         // TODO add this port to config
         .bind(format!("127.0.0.1:{}", config.http_listen_port))
         .expect("error binding server")
         .run()
-        .await
+        .await;
+    mmt_arc.write().unwrap().close(true)?;
+    result
 }
 
 pub fn configure(
     config: Config,
     cfg: &mut web::ServiceConfig,
+    mmt_arc: Arc<RwLock<Engine>>,
     ring_arc: Arc<Mutex<ring::Ring>>,
 ) {
-  let memtable_mgr = Engine::new(config.clone());
-  let mmt_arc = Arc::new(RwLock::new(memtable_mgr));
-
 
   let web_cfg = config.clone();
   cfg
@@ -61,21 +73,70 @@ pub fn configure(
     .data(ring_arc.clone())
     .route("/force_flush", web::post().to(force_flush))
     .route("/force_compact", web::post().to(force_compact))
+    .route("/collect_orphans", web::post().to(collect_orphans))
+    .route("/resume", web::post().to(resume))
+    .route("/stall_stats", web::post().to(stall_stats))
+    .route("/compaction_stats", web::post().to(compaction_stats))
     .route("/ring-join", web::post().to(ring_join))
     .route("/node-status", web::post().to(node_status))
     .route("/write", web::post().to(handle_write))
     .route("/read", web::post().to(handle_read))
-    .route("/delete", web::post().to(handle_delete));
+    .route("/scan", web::post().to(handle_scan))
+    .route("/delete", web::post().to(handle_delete))
+    .route("/delete_range", web::post().to(handle_delete_range))
+    .route("/batch", web::post().to(handle_batch));
 }
 
 fn force_flush(mmt_arc: web::Data<Arc<RwLock<Engine>>>) -> HttpResponse {
-    mmt_arc.write().unwrap().force_flush();
-    HttpResponse::Ok().body("nice")
+    match mmt_arc.read().unwrap().force_flush() {
+        Ok(()) => HttpResponse::Ok().body("nice"),
+        Err(err) => error_response(err),
+    }
 }
 
 fn force_compact(mtt_arc: web::Data<Arc<RwLock<Engine>>>) -> HttpResponse {
-    mtt_arc.read().unwrap().force_compact();
-    HttpResponse::Ok().body("nice")
+    match mtt_arc.read().unwrap().force_compact() {
+        Ok(()) => HttpResponse::Ok().body("nice"),
+        Err(err) => error_response(err),
+    }
+}
+
+fn collect_orphans(mmt_arc: web::Data<Arc<RwLock<Engine>>>) -> HttpResponse {
+    match mmt_arc.read().unwrap().collect_orphans() {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(err) => error_response(err),
+    }
+}
+
+// make the engine writable again after a background error has been fixed
+fn resume(mmt_arc: web::Data<Arc<RwLock<Engine>>>) -> HttpResponse {
+    match mmt_arc.read().unwrap().resume() {
+        Ok(()) => HttpResponse::Ok().body("nice"),
+        Err(err) => error_response(err),
+    }
+}
+
+// how often and for how long writes have been slowed down or stopped
+fn stall_stats(mmt_arc: web::Data<Arc<RwLock<Engine>>>) -> HttpResponse {
+    HttpResponse::Ok().json(mmt_arc.read().unwrap().stall_stats())
+}
+
+fn compaction_stats(mmt_arc: web::Data<Arc<RwLock<Engine>>>) -> HttpResponse {
+    HttpResponse::Ok().json(mmt_arc.read().unwrap().compaction_stats())
+}
+
+// map an error from the engine to a response with a matching status code
+fn error_response(err: Error) -> HttpResponse {
+    log::error!("request failed: {}", err);
+    match err {
+        Error::Closed | Error::ReadOnly(_) => {
+            HttpResponse::ServiceUnavailable().body(err.to_string())
+        }
+        // a live sstable's files are missing, which is data loss rather than a missing key
+        Error::NotFound(_) | Error::Io(_) | Error::Corruption(_) | Error::Config(_) => {
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
 }
 
 fn ring_join(cfg: web::Data<Config>, ring_arc: web::Data<Arc<Mutex<ring::Ring>>>) -> HttpResponse {
@@ -98,17 +159,39 @@ fn node_status(ring: web::Data<Arc<RwLock<ring::Ring>>>) -> HttpResponse {
 pub struct WritePayload {
     key: String,
     value: String,
+    // the value is treated as deleted this many millis after it's written
+    ttl_ms: Option<u64>,
 }
 
 fn handle_write(
     mmt_arc: web::Data<Arc<RwLock<Engine>>>,
     req: web::Json<WritePayload>,
 ) -> HttpResponse {
-    mmt_arc
-        .write()
+    // writes only need a shared lock on the engine, so a stalled write doesn't block reads. The
+    // lock is released before waiting for the write to be durable
+    let waiter = mmt_arc
+        .read()
         .unwrap()
-        .write(req.key.as_bytes(), req.value.as_bytes());
-    HttpResponse::Ok().body("nice")
+        .write(
+            req.key.as_bytes(),
+            req.value.as_bytes(),
+            req.ttl_ms.map(time::Duration::from_millis),
+        );
+    durable_response(waiter, "nice")
+}
+
+fn durable_response(waiter: crate::Result<SyncWaiter>, body: &'static str) -> HttpResponse {
+    let waiter = match waiter {
+        Ok(waiter) => waiter,
+        Err(err) => return error_response(err),
+    };
+    match waiter.wait() {
+        Ok(()) => HttpResponse::Ok().body(body),
+        Err(err) => {
+            log::error!("write could not be made durable: {:?}", err);
+            HttpResponse::InternalServerError().body("write could not be made durable")
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -120,9 +203,12 @@ fn handle_read(
     mmt_arc: web::Data<Arc<RwLock<Engine>>>,
     req: web::Json<ReadPayload>,
 ) -> HttpResponse {
-    let found = mmt_arc.read().unwrap().find(req.key.as_bytes());
+    let found = match mmt_arc.read().unwrap().find(req.key.as_bytes(), None) {
+        Ok(found) => found,
+        Err(err) => return error_response(err),
+    };
     if !matches!(found, None) {
-        let value = String::from_utf8(found.unwrap()).unwrap();
+        let value = String::from_utf8_lossy(&found.unwrap()).into_owned();
         HttpResponse::Ok().body(value)
     } else {
         HttpResponse::Ok().body("biffed it")
@@ -138,6 +224,198 @@ fn handle_delete(
     mmt_arc: web::Data<Arc<RwLock<Engine>>>,
     req: web::Json<DeletePayload>,
 ) -> HttpResponse {
-    mmt_arc.write().unwrap().delete(req.key.as_bytes());
-    HttpResponse::Ok().body("OK")
+    let waiter = mmt_arc.read().unwrap().delete(req.key.as_bytes());
+    durable_response(waiter, "OK")
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeleteRangePayload {
+    // inclusive start of the range
+    start: String,
+    // exclusive end of the range
+    end: String,
+}
+
+fn handle_delete_range(
+    mmt_arc: web::Data<Arc<RwLock<Engine>>>,
+    req: web::Json<DeleteRangePayload>,
+) -> HttpResponse {
+    let waiter = mmt_arc
+        .read()
+        .unwrap()
+        .delete_range(req.start.as_bytes(), req.end.as_bytes());
+    durable_response(waiter, "OK")
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOperation {
+    Put { key: String, value: String },
+    Delete { key: String },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BatchPayload {
+    operations: Vec<BatchOperation>,
+}
+
+fn handle_batch(
+    mmt_arc: web::Data<Arc<RwLock<Engine>>>,
+    req: web::Json<BatchPayload>,
+) -> HttpResponse {
+    let mut batch = WriteBatch::new();
+    for operation in &req.operations {
+        match operation {
+            BatchOperation::Put { key, value } => batch.put(key.as_bytes(), value.as_bytes()),
+            BatchOperation::Delete { key } => batch.delete(key.as_bytes()),
+        }
+    }
+    let waiter = mmt_arc.read().unwrap().write_batch(&batch);
+    durable_response(waiter, "OK")
+}
+
+// default and maximum number of records returned in one page of a scan
+const SCAN_DEFAULT_LIMIT: usize = 100;
+const SCAN_MAX_LIMIT: usize = 1000;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScanPayload {
+    // return only keys starting with this prefix. Can't be combined with start/end
+    prefix: Option<String>,
+    // inclusive start of the range
+    start: Option<String>,
+    // exclusive end of the range
+    end: Option<String>,
+    // max number of records to return in the page
+    limit: Option<usize>,
+    // cursor returned with the previous page
+    cursor: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScanRecord {
+    key: String,
+    value: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScanResponse {
+    records: Vec<ScanRecord>,
+    // pass this back in the next request to get the next page. None if there are no more records
+    cursor: Option<String>,
+}
+
+fn handle_scan(
+    mmt_arc: web::Data<Arc<RwLock<Engine>>>,
+    req: web::Json<ScanPayload>,
+) -> HttpResponse {
+    if req.prefix.is_some() && (req.start.is_some() || req.end.is_some()) {
+        return HttpResponse::BadRequest().body("prefix can't be combined with start or end");
+    }
+
+    let (mut start, end) = match &req.prefix {
+        Some(prefix) => (
+            Some(prefix.as_bytes().to_vec()),
+            prefix_end(prefix.as_bytes()),
+        ),
+        None => (
+            req.start.as_ref().map(|k| k.as_bytes().to_vec()),
+            req.end.as_ref().map(|k| k.as_bytes().to_vec()),
+        ),
+    };
+
+    // the cursor is the key after which the previous page stopped. A cursor from before the start
+    // of the range doesn't widen it
+    if let Some(cursor) = &req.cursor {
+        match decode_cursor(cursor) {
+            Some(cursor_key) => start = start.max(Some(cursor_key)),
+            None => return HttpResponse::BadRequest().body("invalid cursor"),
+        }
+    }
+
+    let limit = req
+        .limit
+        .unwrap_or(SCAN_DEFAULT_LIMIT)
+        .clamp(1, SCAN_MAX_LIMIT);
+
+    // read one extra record to find out if there's another page
+    let scanned = mmt_arc
+        .read()
+        .unwrap()
+        .scan(start.as_deref(), end.as_deref(), None)
+        .and_then(|scan| scan.take(limit + 1).collect::<std::result::Result<Vec<_>, _>>());
+    let mut found = match scanned {
+        Ok(found) => found,
+        Err(err) => return error_response(err),
+    };
+
+    let mut cursor = None;
+    if found.len() > limit {
+        found.truncate(limit);
+        let (last_key, _) = &found[limit - 1];
+        cursor = Some(encode_cursor(last_key));
+    }
+
+    let records = found
+        .into_iter()
+        .map(|(key, value)| ScanRecord {
+            key: String::from_utf8_lossy(&key).into_owned(),
+            value: String::from_utf8_lossy(&value).into_owned(),
+        })
+        .collect();
+
+    HttpResponse::Ok().json(ScanResponse { records, cursor })
+}
+
+// the smallest key that is greater than every key starting with prefix. None if there is no such
+// key (prefix is empty or all 0xff)
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// cursors are the hex encoded key to resume from. The next page starts at the smallest key
+// greater than the last key returned, which is the last key with a zero byte appended
+fn encode_cursor(last_key: &[u8]) -> String {
+    let mut resume_key = last_key.to_vec();
+    resume_key.push(0);
+    resume_key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Option<Vec<u8>> {
+    cursor
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digits = str::from_utf8(pair).ok().filter(|d| d.len() == 2)?;
+            u8::from_str_radix(digits, 16).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod scan_tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_the_end_of_a_prefix() {
+        assert_eq!(Some(b"tenant/124".to_vec()), prefix_end(b"tenant/123"));
+        assert_eq!(Some(b"b".to_vec()), prefix_end(b"a\xff\xff"));
+        assert_eq!(None, prefix_end(b"\xff"));
+        assert_eq!(None, prefix_end(b""));
+    }
+
+    #[test]
+    fn it_round_trips_cursors() {
+        let cursor = encode_cursor(b"tenant/123");
+        assert_eq!(Some(b"tenant/123\x00".to_vec()), decode_cursor(&cursor));
+        assert_eq!(None, decode_cursor("abc"));
+        assert_eq!(None, decode_cursor("zz"));
+    }
 }
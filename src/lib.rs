@@ -1,11 +1,19 @@
+pub mod batch;
 pub mod bloom;
 pub mod compact;
 pub mod config;
 pub mod engine;
+pub mod error;
 pub mod frontend;
+pub mod manifest;
 pub mod memtable;
+pub mod merge;
+pub mod orphan;
+pub mod snapshot;
 pub mod sstable;
 pub mod wal;
 
+pub use error::{Error, Result};
+
 // TODO this should not be public forever
 pub mod ring;
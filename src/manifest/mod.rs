@@ -0,0 +1,401 @@
+// The MANIFEST is an append-only log of version edits. Each edit adds and/or removes sstables, and
+// replaying the edits gives the set of live sstables. It is the single source of truth for which
+// sstables exist: an sstable that isn't in the MANIFEST is ignored even if its files are in the
+// data directory. Because each edit is written as a single record, a flush or a compaction takes
+// effect atomically when its edit is written.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::io::{Seek, Write};
+use std::path;
+
+use crate::config;
+
+const MANIFEST_FILENAME: &str = "MANIFEST";
+
+const RECORD_HEADER_SIZE: usize = 8;
+
+// describes one sstable
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileMeta {
+    pub id: String,
+    pub level: u8,
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+    // the highest sequence number of any entry in the table
+    pub max_seq: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct VersionEdit {
+    pub added: Vec<FileMeta>,
+    // ids of the sstables that were removed
+    pub removed: Vec<String>,
+}
+
+pub struct Manifest {
+    file: fs::File,
+    // the live sstables by id
+    files: BTreeMap<String, FileMeta>,
+    // ids of sstables that are being written but haven't been added yet. Their files exist
+    // without being live, so they must not be mistaken for orphans
+    pending: BTreeSet<String>,
+    // length of the log up to the last edit that was written completely
+    length: u64,
+    // set when a failed edit couldn't be cut off the end of the log. Replay stops at the partial
+    // record, so any edit written after it would be lost on the next open
+    poisoned: bool,
+}
+
+impl Manifest {
+    // open the MANIFEST in the data directory, creating it if it doesn't exist, and replay the
+    // edits to find the live sstables. The log is then rewritten as a single edit so that it
+    // doesn't keep growing
+    pub fn open(config: &config::Config) -> io::Result<Self> {
+        let path = path::Path::new(&config.data_dir).join(MANIFEST_FILENAME);
+        let mut files = BTreeMap::new();
+        match fs::read(&path) {
+            Ok(contents) => replay(&path, &contents, &mut files),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => check_no_sstables(config)?,
+            Err(err) => return Err(err),
+        }
+
+        // write the new log next to the old one and rename it into place, so that a crash while
+        // rewriting leaves the old log intact
+        let tmp_path = path.with_file_name(format!("{}.tmp", MANIFEST_FILENAME));
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let edit = VersionEdit {
+            added: files.values().cloned().collect(),
+            removed: vec![],
+        };
+        write_record(&mut file, &edit)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        fs::File::open(&config.data_dir)?.sync_all()?;
+
+        log::info!("opened MANIFEST with {} live sstables", files.len());
+        Ok(Manifest {
+            length: file.stream_position()?,
+            file,
+            files,
+            pending: BTreeSet::new(),
+            poisoned: false,
+        })
+    }
+
+    // durably append the edit to the log and then apply it to the live sstables. If the edit
+    // can't be written, whatever part of it was is cut off the end of the log again
+    pub fn apply(&mut self, edit: VersionEdit) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::other(
+                "MANIFEST has a partially written edit, no more edits can be applied",
+            ));
+        }
+        let written = write_record(&mut self.file, &edit).and_then(|()| self.file.sync_data());
+        if let Err(err) = written {
+            if let Err(undo_err) = self.undo_partial_write() {
+                log::error!("error removing partial edit from MANIFEST: {}", undo_err);
+                self.poisoned = true;
+            }
+            return Err(err);
+        }
+        self.length = self.file.stream_position()?;
+        for file in &edit.added {
+            self.pending.remove(&file.id);
+        }
+        apply_edit(&mut self.files, edit);
+        Ok(())
+    }
+
+    // record that an sstable with the given id is about to be written. It stays pending until an
+    // edit adds it
+    pub fn add_pending(&mut self, id: &str) {
+        self.pending.insert(id.to_owned());
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &String> {
+        self.pending.iter()
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &FileMeta> {
+        self.files.values()
+    }
+
+    pub fn files_at_level(&self, level: u8) -> Vec<&FileMeta> {
+        self.files().filter(|file| file.level == level).collect()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.files.contains_key(id)
+    }
+
+    fn undo_partial_write(&mut self) -> io::Result<()> {
+        self.file.set_len(self.length)?;
+        self.file.seek(io::SeekFrom::Start(self.length))?;
+        self.file.sync_all()
+    }
+}
+
+// without a MANIFEST no sstable is live, so any already in the data directory would be treated as
+// orphans. They were either written before there was a MANIFEST or it was lost, so refuse to open
+// rather than hide them
+fn check_no_sstables(config: &config::Config) -> io::Result<()> {
+    for file in fs::read_dir(&config.data_dir)? {
+        if file?.file_name().to_string_lossy().starts_with("sstable-") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "data directory {} has sstables but no {}",
+                    config.data_dir, MANIFEST_FILENAME
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn apply_edit(files: &mut BTreeMap<String, FileMeta>, edit: VersionEdit) {
+    for id in &edit.removed {
+        files.remove(id);
+    }
+    for file in edit.added {
+        files.insert(file.id.clone(), file);
+    }
+}
+
+// records are written like WAL records: the length of the payload, the CRC32 of the payload and
+// then the payload, which is the edit encoded as JSON
+fn write_record(file: &mut fs::File, edit: &VersionEdit) -> io::Result<()> {
+    let payload = serde_json::to_vec(edit)?;
+    let mut buffer = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    buffer.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    buffer.extend_from_slice(&payload);
+    file.write_all(&buffer)
+}
+
+// apply the edits in the log. Replay stops at the first record that is incomplete or fails its
+// checksum, which can only be an edit that was being written when the database crashed
+fn replay(path: &path::Path, contents: &[u8], files: &mut BTreeMap<String, FileMeta>) {
+    let mut offset = 0;
+    while offset < contents.len() {
+        match read_record(&contents[offset..]) {
+            Ok((edit, record_length)) => {
+                apply_edit(files, edit);
+                offset += record_length;
+            }
+            Err(err) => {
+                log::warn!(
+                    "invalid record in MANIFEST {:?} at offset {}: {}",
+                    path,
+                    offset,
+                    err
+                );
+                break;
+            }
+        }
+    }
+}
+
+fn read_record(bytes: &[u8]) -> io::Result<(VersionEdit, usize)> {
+    if bytes.len() < RECORD_HEADER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "record is incomplete",
+        ));
+    }
+    let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let crc = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let payload = match bytes.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + length) {
+        Some(payload) => payload,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "record is incomplete",
+            ))
+        }
+    };
+    if crc32fast::hash(payload) != crc {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record checksum mismatch",
+        ));
+    }
+
+    let edit = serde_json::from_slice(payload)?;
+    Ok((edit, RECORD_HEADER_SIZE + length))
+}
+
+#[cfg(test)]
+mod manifest_tests {
+    use super::*;
+
+    fn test_config(test_name: &str) -> config::Config {
+        let data_dir = format!("/tmp/manifest_tests/{}", test_name);
+        fs::remove_dir_all(&data_dir).ok();
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = data_dir;
+        config
+    }
+
+    fn file_meta(id: &str, level: u8) -> FileMeta {
+        FileMeta {
+            id: String::from(id),
+            level,
+            smallest_key: "a".bytes().collect(),
+            largest_key: "z".bytes().collect(),
+            max_seq: 1,
+        }
+    }
+
+    #[test]
+    fn it_replays_edits_when_reopened() {
+        let config = test_config("it_replays_edits_when_reopened");
+        let mut manifest = Manifest::open(&config).unwrap();
+        manifest
+            .apply(VersionEdit {
+                added: vec![file_meta("1", 0), file_meta("2", 0)],
+                removed: vec![],
+            })
+            .unwrap();
+        manifest
+            .apply(VersionEdit {
+                added: vec![file_meta("3", 1)],
+                removed: vec![String::from("1"), String::from("2")],
+            })
+            .unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(&config).unwrap();
+        assert_eq!(
+            vec![&file_meta("3", 1)],
+            manifest.files().collect::<Vec<_>>()
+        );
+        assert_eq!(0, manifest.files_at_level(0).len());
+        assert_eq!(true, manifest.contains("3"));
+        assert_eq!(false, manifest.contains("1"));
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_tracks_pending_sstables_until_they_are_added() {
+        let config = test_config("it_tracks_pending_sstables_until_they_are_added");
+        let mut manifest = Manifest::open(&config).unwrap();
+        manifest.add_pending("1");
+        assert_eq!(vec!["1"], manifest.pending().collect::<Vec<_>>());
+        assert_eq!(false, manifest.contains("1"));
+
+        manifest
+            .apply(VersionEdit {
+                added: vec![file_meta("1", 0)],
+                removed: vec![],
+            })
+            .unwrap();
+        assert_eq!(0, manifest.pending().count());
+        assert_eq!(true, manifest.contains("1"));
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_refuses_to_open_sstables_without_a_manifest() {
+        let config = test_config("it_refuses_to_open_sstables_without_a_manifest");
+        fs::write(format!("{}/sstable-meta-1", config.data_dir), "").unwrap();
+
+        let err = Manifest::open(&config).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        // nothing was written, so opening keeps failing until the sstables are dealt with
+        assert_eq!(
+            false,
+            path::Path::new(&format!("{}/{}", config.data_dir, MANIFEST_FILENAME)).exists()
+        );
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_ignores_a_torn_edit() {
+        let config = test_config("it_ignores_a_torn_edit");
+        let mut manifest = Manifest::open(&config).unwrap();
+        manifest
+            .apply(VersionEdit {
+                added: vec![file_meta("1", 0)],
+                removed: vec![],
+            })
+            .unwrap();
+        manifest
+            .apply(VersionEdit {
+                added: vec![file_meta("2", 0)],
+                removed: vec![],
+            })
+            .unwrap();
+        drop(manifest);
+
+        // simulate a crash part way through writing the last edit
+        let path = path::Path::new(&config.data_dir).join(MANIFEST_FILENAME);
+        let len = fs::metadata(&path).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let manifest = Manifest::open(&config).unwrap();
+        assert_eq!(true, manifest.contains("1"));
+        assert_eq!(false, manifest.contains("2"));
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_edits_applied_after_a_failed_one() {
+        let config = test_config("it_keeps_edits_applied_after_a_failed_one");
+        let mut manifest = Manifest::open(&config).unwrap();
+
+        // part of an edit that failed to be written is cut off again
+        let path = path::Path::new(&config.data_dir).join(MANIFEST_FILENAME);
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 1, 0, 1, 2]).unwrap();
+        manifest.undo_partial_write().unwrap();
+        manifest
+            .apply(VersionEdit {
+                added: vec![file_meta("1", 0)],
+                removed: vec![],
+            })
+            .unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(&config).unwrap();
+        assert_eq!(true, manifest.contains("1"));
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_refuses_edits_once_a_partial_edit_cant_be_removed() {
+        let config = test_config("it_refuses_edits_once_a_partial_edit_cant_be_removed");
+        let mut manifest = Manifest::open(&config).unwrap();
+
+        // neither writing nor truncating works through a read only handle
+        let path = path::Path::new(&config.data_dir).join(MANIFEST_FILENAME);
+        manifest.file = fs::File::open(&path).unwrap();
+        let edit = VersionEdit {
+            added: vec![file_meta("1", 0)],
+            removed: vec![],
+        };
+        assert_eq!(true, manifest.apply(edit.clone()).is_err());
+        assert_eq!(true, manifest.poisoned);
+
+        manifest.file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        assert_eq!(true, manifest.apply(edit).is_err());
+        assert_eq!(false, manifest.contains("1"));
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+}
@@ -0,0 +1,452 @@
+// A memtable that keeps its entries in a few large allocations instead of allocating for every
+// node, key and value. Keys and values are copied into chunks of bytes, and the skiplist nodes and
+// versions that refer to them are kept in flat vectors and linked by index. Nothing is freed until
+// the whole memtable is dropped after its flush, so replaced versions still count towards its size.
+
+use rand::prelude::*;
+use std::cmp::Reverse;
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+use super::{MemtableIterator, MemtableRep, Version};
+use crate::snapshot;
+
+// size of the chunks keys and values are copied into
+const CHUNK_SIZE: usize = 4096;
+
+const MAX_HEIGHT: usize = 12;
+
+// each node is on the next level up with a probability of 1 / BRANCHING
+const BRANCHING: u32 = 4;
+
+// links to nodes and versions are indexes into the vectors. NIL means there is nothing, and HEAD
+// is the start of the list, which comes before every node
+const NIL: u32 = u32::MAX;
+const HEAD: u32 = u32::MAX - 1;
+
+// where some bytes were copied to in the arena
+#[derive(Clone, Copy, Debug)]
+struct Span {
+    chunk: u32,
+    offset: u32,
+    len: u32,
+}
+
+#[derive(Debug, Default)]
+struct Arena {
+    chunks: Vec<Vec<u8>>,
+    // the chunk small allocations are made from
+    current: usize,
+    // number of bytes copied into the arena
+    used: usize,
+}
+
+impl Arena {
+    fn alloc(&mut self, bytes: &[u8]) -> Span {
+        // big values get a chunk of their own, so they don't waste the rest of the current chunk
+        let chunk = if bytes.len() > CHUNK_SIZE / 4 {
+            self.chunks.push(Vec::with_capacity(bytes.len()));
+            self.chunks.len() - 1
+        } else {
+            let fits = self
+                .chunks
+                .get(self.current)
+                .is_some_and(|chunk| chunk.capacity() - chunk.len() >= bytes.len());
+            if !fits {
+                self.chunks.push(Vec::with_capacity(CHUNK_SIZE));
+                self.current = self.chunks.len() - 1;
+            }
+            self.current
+        };
+
+        let offset = self.chunks[chunk].len();
+        self.chunks[chunk].extend_from_slice(bytes);
+        self.used += bytes.len();
+        Span {
+            chunk: chunk as u32,
+            offset: offset as u32,
+            len: bytes.len() as u32,
+        }
+    }
+
+    fn get(&self, span: Span) -> &[u8] {
+        let start = span.offset as usize;
+        &self.chunks[span.chunk as usize][start..start + span.len as usize]
+    }
+}
+
+#[derive(Debug)]
+struct ListNode {
+    key: Span,
+    // the newest version of the key. Older versions are linked from it
+    newest: u32,
+    // where the node's links to the next node on each of its levels start in links
+    tower: u32,
+}
+
+#[derive(Debug)]
+struct VersionEntry {
+    seq: u64,
+    value: Option<Span>,
+    older: u32,
+}
+
+#[derive(Debug)]
+struct ArenaList {
+    arena: Arena,
+    head: [u32; MAX_HEIGHT],
+    // number of levels in use
+    height: usize,
+    nodes: Vec<ListNode>,
+    versions: Vec<VersionEntry>,
+    links: Vec<u32>,
+    max_seq: u64,
+}
+
+impl ArenaList {
+    fn new() -> Self {
+        ArenaList {
+            arena: Arena::default(),
+            head: [NIL; MAX_HEIGHT],
+            height: 1,
+            nodes: Vec::new(),
+            versions: Vec::new(),
+            links: Vec::new(),
+            max_seq: 0,
+        }
+    }
+
+    fn key(&self, node: u32) -> &[u8] {
+        self.arena.get(self.nodes[node as usize].key)
+    }
+
+    fn next(&self, node: u32, level: usize) -> u32 {
+        if node == HEAD {
+            return self.head[level];
+        }
+        self.links[self.nodes[node as usize].tower as usize + level]
+    }
+
+    fn set_next(&mut self, node: u32, level: usize, next: u32) {
+        if node == HEAD {
+            self.head[level] = next;
+        } else {
+            let tower = self.nodes[node as usize].tower as usize;
+            self.links[tower + level] = next;
+        }
+    }
+
+    // find the first node with a key >= key, or NIL if there isn't one. prev is set to the last
+    // node before it on each level
+    fn find_greater_or_equal(&self, key: &[u8], prev: &mut [u32; MAX_HEIGHT]) -> u32 {
+        let mut node = HEAD;
+        let mut level = self.height - 1;
+        loop {
+            let next = self.next(node, level);
+            if next != NIL && self.key(next) < key {
+                node = next;
+                continue;
+            }
+            prev[level] = node;
+            if level == 0 {
+                return next;
+            }
+            level -= 1;
+        }
+    }
+
+    // find the last node with a key < key, or HEAD if there isn't one
+    fn find_less_than(&self, key: &[u8]) -> u32 {
+        let mut prev = [HEAD; MAX_HEIGHT];
+        self.find_greater_or_equal(key, &mut prev);
+        prev[0]
+    }
+
+    // find the last node, or HEAD if the list is empty
+    fn find_last(&self) -> u32 {
+        let mut node = HEAD;
+        for level in (0..self.height).rev() {
+            while self.next(node, level) != NIL {
+                node = self.next(node, level);
+            }
+        }
+        node
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, seq: u64, snapshots: &[u64]) {
+        self.max_seq = self.max_seq.max(seq);
+        let value = value.map(|value| self.arena.alloc(&value));
+        let version = self.versions.len() as u32;
+        self.versions.push(VersionEntry {
+            seq,
+            value,
+            older: NIL,
+        });
+
+        let mut prev = [HEAD; MAX_HEIGHT];
+        let found = self.find_greater_or_equal(&key, &mut prev);
+        if found != NIL && self.key(found) == key.as_slice() {
+            self.add_version(found, version, snapshots);
+            return;
+        }
+
+        let height = random_height();
+        self.height = self.height.max(height);
+        let node = self.nodes.len() as u32;
+        let key = self.arena.alloc(&key);
+        self.nodes.push(ListNode {
+            key,
+            newest: version,
+            tower: self.links.len() as u32,
+        });
+        for (level, prev) in prev.iter().enumerate().take(height) {
+            let next = self.next(*prev, level);
+            self.links.push(next);
+        }
+        for (level, prev) in prev.iter().enumerate().take(height) {
+            self.set_next(*prev, level, node);
+        }
+    }
+
+    // link a new version into the versions of a node. Like the treap, the newest version is
+    // always kept and older versions are only kept while one of the snapshots can still see them
+    fn add_version(&mut self, node: u32, version: u32, snapshots: &[u64]) {
+        let mut versions = vec![version];
+        let mut older = self.nodes[node as usize].newest;
+        while older != NIL {
+            versions.push(older);
+            older = self.versions[older as usize].older;
+        }
+
+        // order newest first. The sort is stable, so if a version with the same sequence number
+        // is added again, it replaces the existing one
+        versions.sort_by_key(|v| Reverse(self.versions[*v as usize].seq));
+        versions.dedup_by_key(|v| self.versions[*v as usize].seq);
+
+        let mut kept = vec![versions[0]];
+        for pair in versions.windows(2) {
+            let newer_seq = self.versions[pair[0] as usize].seq;
+            let seq = self.versions[pair[1] as usize].seq;
+            if snapshot::is_needed(seq, newer_seq, snapshots) {
+                kept.push(pair[1]);
+            }
+        }
+
+        for pair in kept.windows(2) {
+            self.versions[pair[0] as usize].older = pair[1];
+        }
+        self.versions[*kept.last().unwrap() as usize].older = NIL;
+        self.nodes[node as usize].newest = kept[0];
+    }
+
+    fn search_at(&self, key: &[u8], seq: u64) -> (Option<Vec<u8>>, bool) {
+        let mut prev = [HEAD; MAX_HEIGHT];
+        let node = self.find_greater_or_equal(key, &mut prev);
+        if node == NIL || self.key(node) != key {
+            return (None, false);
+        }
+
+        let mut version = self.nodes[node as usize].newest;
+        while version != NIL {
+            let entry = &self.versions[version as usize];
+            if entry.seq <= seq {
+                return (
+                    entry.value.map(|value| self.arena.get(value).to_vec()),
+                    true,
+                );
+            }
+            version = entry.older;
+        }
+        (None, false)
+    }
+
+    fn approximate_size(&self) -> usize {
+        // the unused end of the current chunk isn't counted, so that the size of a memtable
+        // doesn't jump by a whole chunk at a time
+        self.arena.used
+            + self.nodes.len() * mem::size_of::<ListNode>()
+            + self.versions.len() * mem::size_of::<VersionEntry>()
+            + self.links.len() * mem::size_of::<u32>()
+    }
+}
+
+fn random_height() -> usize {
+    let mut rng = rand::thread_rng();
+    let mut height = 1;
+    while height < MAX_HEIGHT && rng.gen_range(0..BRANCHING) == 0 {
+        height += 1;
+    }
+    height
+}
+
+// the list is behind a single lock like the treap. Nodes are never removed, so iterators can keep
+// their position by index between calls
+#[derive(Debug)]
+pub struct ArenaRep {
+    list: Arc<RwLock<ArenaList>>,
+}
+
+impl ArenaRep {
+    pub fn new() -> Self {
+        ArenaRep {
+            list: Arc::new(RwLock::new(ArenaList::new())),
+        }
+    }
+
+    fn iter_at(&self, node: u32, reverse: bool) -> MemtableIterator {
+        let list = self.list.read().unwrap();
+        let node = if node == HEAD { NIL } else { node };
+        let version = match node {
+            NIL => NIL,
+            node => list.nodes[node as usize].newest,
+        };
+        Box::new(ArenaIterator {
+            list: self.list.clone(),
+            node,
+            version,
+            reverse,
+        })
+    }
+}
+
+impl Default for ArenaRep {
+    fn default() -> Self {
+        ArenaRep::new()
+    }
+}
+
+impl MemtableRep for ArenaRep {
+    fn insert(&self, key: Vec<u8>, value: Option<Vec<u8>>, seq: u64, snapshots: &[u64]) {
+        self.list
+            .write()
+            .unwrap()
+            .insert(key, value, seq, snapshots);
+    }
+
+    fn search_at(&self, key: &[u8], seq: u64) -> (Option<Vec<u8>>, bool) {
+        self.list.read().unwrap().search_at(key, seq)
+    }
+
+    fn iter_from(&self, start: Option<&[u8]>) -> MemtableIterator {
+        let node = {
+            let list = self.list.read().unwrap();
+            match start {
+                Some(start) => list.find_greater_or_equal(start, &mut [HEAD; MAX_HEIGHT]),
+                None => list.head[0],
+            }
+        };
+        self.iter_at(node, false)
+    }
+
+    fn iter_rev_from(&self, end: Option<&[u8]>) -> MemtableIterator {
+        let node = {
+            let list = self.list.read().unwrap();
+            match end {
+                Some(end) => list.find_less_than(end),
+                None => list.find_last(),
+            }
+        };
+        self.iter_at(node, true)
+    }
+
+    fn size(&self) -> u32 {
+        self.list.read().unwrap().nodes.len() as u32
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.list.read().unwrap().approximate_size()
+    }
+
+    fn max_seq(&self) -> u64 {
+        self.list.read().unwrap().max_seq
+    }
+}
+
+pub struct ArenaIterator {
+    list: Arc<RwLock<ArenaList>>,
+    // the node whose versions are being returned, or NIL once the iterator is done
+    node: u32,
+    // the next version of the node to return, or NIL once they've all been returned
+    version: u32,
+    reverse: bool,
+}
+
+impl Iterator for ArenaIterator {
+    type Item = Version;
+
+    // every version of a key is returned, newest first
+    fn next(&mut self) -> Option<Self::Item> {
+        let list = self.list.read().unwrap();
+        while self.node != NIL {
+            if self.version != NIL {
+                let entry = &list.versions[self.version as usize];
+                self.version = entry.older;
+                return Some(Version {
+                    key: list.key(self.node).to_vec(),
+                    value: entry.value.map(|value| list.arena.get(value).to_vec()),
+                    seq: entry.seq,
+                    expires_at: None,
+                });
+            }
+
+            self.node = if self.reverse {
+                match list.find_less_than(list.key(self.node)) {
+                    HEAD => NIL,
+                    node => node,
+                }
+            } else {
+                list.next(self.node, 0)
+            };
+            if self.node != NIL {
+                self.version = list.nodes[self.node as usize].newest;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod arena_tests {
+    use super::*;
+
+    #[test]
+    fn it_packs_small_keys_and_values_into_chunks() {
+        let rep = ArenaRep::new();
+        for i in 0..1000u64 {
+            rep.insert(format!("{:04}", i).into_bytes(), Some(vec![0; 4]), i, &[]);
+        }
+
+        // 1000 keys and values of 4 bytes each fit in two chunks
+        let list = rep.list.read().unwrap();
+        assert_eq!(2, list.arena.chunks.len());
+        assert_eq!(8000, list.arena.used);
+        assert_eq!(1000, list.nodes.len());
+    }
+
+    #[test]
+    fn it_gives_big_values_their_own_chunk() {
+        let rep = ArenaRep::new();
+        rep.insert(b"a".to_vec(), Some(vec![1; CHUNK_SIZE]), 1, &[]);
+        rep.insert(b"b".to_vec(), Some(vec![2; 10]), 2, &[]);
+
+        // the keys and the small value share a chunk
+        let list = rep.list.read().unwrap();
+        assert_eq!(2, list.arena.chunks.len());
+        assert_eq!(CHUNK_SIZE, list.arena.chunks[0].len());
+        assert_eq!(12, list.arena.chunks[1].len());
+        drop(list);
+        assert_eq!((Some(vec![1; CHUNK_SIZE]), true), rep.search_at(b"a", 1));
+    }
+
+    #[test]
+    fn it_counts_replaced_values_until_it_is_dropped() {
+        let rep = ArenaRep::new();
+        rep.insert(b"a".to_vec(), Some(vec![0; 100]), 1, &[]);
+        let before = rep.approximate_size();
+        rep.insert(b"a".to_vec(), Some(vec![0; 10]), 2, &[]);
+        assert_eq!(true, rep.approximate_size() > before);
+        assert_eq!(1, rep.size());
+        assert_eq!((Some(vec![0; 10]), true), rep.search_at(b"a", 2));
+        assert_eq!((None, false), rep.search_at(b"a", 0));
+    }
+}
@@ -1,885 +1,537 @@
-// Memtable is implemented as a binary treap using random priority to ensure balance
+// Memtables hold the most recent writes in memory until they're flushed to an sstable. How the
+// entries are stored is up to the MemtableRep: a lock-free skiplist, which lets readers run
+// alongside writers, an arena, which cuts the memory used per entry, or a treap, which is kept for
+// comparison. Range tombstones are kept next to the rep, since there are only ever a few of them.
+// Reps store values as opaque bytes, so the memtable appends a tag to each value, followed by the
+// expiry of values written with a TTL
 
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::mem;
 use std::sync::RwLock;
 
+use crate::config;
+use crate::sstable;
+
+mod arena;
 mod node;
-use crate::memtable::node::{Link, Node, NodeMethods};
+mod skiplist;
+mod treap;
+
+pub use arena::ArenaRep;
+pub use skiplist::SkipListRep;
+pub use treap::{Treap, TreapRep};
+
+// a version of a key, written by the mutation with sequence number seq. A value of None means the
+// key was deleted
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    pub seq: u64,
+    // millis since the epoch after which the value is treated as deleted
+    pub expires_at: Option<u64>,
+}
+
+impl Version {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+// tags stored after each value in the rep
+const TAG_NO_EXPIRY: u8 = 0;
+const TAG_EXPIRES: u8 = 1;
+
+// deletes every key in [start, end) written before seq
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub seq: u64,
+}
+
+impl RangeTombstone {
+    pub fn contains(&self, key: &[u8]) -> bool {
+        *self.start <= *key && *key < *self.end
+    }
+
+    // whether the version of the key written at seq is deleted by the tombstone
+    pub fn covers(&self, key: &[u8], seq: u64) -> bool {
+        seq < self.seq && self.contains(key)
+    }
+}
+
+// the sequence number of the newest tombstone visible at read_seq that contains the key
+pub fn newest_covering(tombstones: &[RangeTombstone], key: &[u8], read_seq: u64) -> Option<u64> {
+    tombstones
+        .iter()
+        .filter(|tombstone| tombstone.seq <= read_seq && tombstone.contains(key))
+        .map(|tombstone| tombstone.seq)
+        .max()
+}
+
+// iterates versions in key order. Every version of a key is returned, newest first
+pub type MemtableIterator = Box<dyn Iterator<Item = Version> + Send>;
+
+// the data structure that stores a memtable's entries. Inserts only need a shared reference, so
+// reps decide for themselves how much readers and writers can overlap
+pub trait MemtableRep: Debug + Send + Sync {
+    // insert the value for the key, keeping the older versions of the key that are still visible
+    // to any of the snapshots. snapshots are the sequence numbers of the live snapshots
+    fn insert(&self, key: Vec<u8>, value: Option<Vec<u8>>, seq: u64, snapshots: &[u64]);
+
+    // search for the newest version of the key written at or before seq. Returns the value and
+    // whether a version of the key was found
+    fn search_at(&self, key: &[u8], seq: u64) -> (Option<Vec<u8>>, bool);
+
+    // iterate the keys in ascending order starting at the first key >= start, or from the
+    // smallest key if start is None
+    fn iter_from(&self, start: Option<&[u8]>) -> MemtableIterator;
+
+    // iterate the keys in descending order starting at the last key < end, or from the largest
+    // key if end is None
+    fn iter_rev_from(&self, end: Option<&[u8]>) -> MemtableIterator;
+
+    // number of distinct keys
+    fn size(&self) -> u32;
+
+    // approximate number of bytes of memory used by the keys, values and the rep's own overhead
+    fn approximate_size(&self) -> usize;
+
+    // the highest sequence number of any write
+    fn max_seq(&self) -> u64;
+}
 
 #[derive(Debug)]
 pub struct Memtable {
-    root: Link,
-    size: u32,
+    rep: Box<dyn MemtableRep>,
+    range_tombstones: RwLock<Vec<RangeTombstone>>,
     pub id: String,
 }
 
 impl Memtable {
     pub fn new() -> Self {
+        Memtable::with_rep(&config::MemtableRepKind::default())
+    }
+
+    pub fn with_rep(kind: &config::MemtableRepKind) -> Self {
         // TODO needs a better implementation of random ID (collsions would be a disaster)
         let mut rng = rand::thread_rng();
         let id: u32 = rng.gen();
+        let rep: Box<dyn MemtableRep> = match kind {
+            config::MemtableRepKind::SkipList => Box::new(SkipListRep::new()),
+            config::MemtableRepKind::Treap => Box::new(TreapRep::new()),
+            config::MemtableRepKind::Arena => Box::new(ArenaRep::new()),
+        };
         Memtable {
             id: format!("{:?}", id),
-            root: None,
-            size: 0,
+            rep,
+            range_tombstones: RwLock::new(Vec::new()),
         }
     }
 
     pub fn size(&self) -> u32 {
-        return self.size;
+        self.rep.size()
     }
 
-    pub fn search(&self, key: &[u8]) -> (Option<Vec<u8>>, bool) {
-        if matches!(self.root, None) {
-            return (None, false);
-        }
+    // whether the memtable holds no keys and no range tombstones
+    pub fn is_empty(&self) -> bool {
+        self.size() == 0 && self.range_tombstones.read().unwrap().is_empty()
+    }
 
-        let node = self.root.as_ref().unwrap();
-        let (value, found) = node.search(&key.to_vec());
-        if value.is_some() {
-            return (Some(value.unwrap()), true);
-        }
-        return (None, found);
+    // approximate number of bytes of memory used by the memtable
+    pub fn approximate_size(&self) -> usize {
+        let tombstones: usize = self
+            .range_tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .map(|tombstone| {
+                mem::size_of::<RangeTombstone>() + tombstone.start.len() + tombstone.end.len()
+            })
+            .sum();
+        self.rep.approximate_size() + tombstones
     }
 
-    pub fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) {
-        let mut rng = rand::thread_rng();
-        let priority: f64 = rng.gen();
-        self.insert_with_priority(priority, key, value);
+    // the highest sequence number of any write in the memtable
+    pub fn max_seq(&self) -> u64 {
+        let tombstones = self.range_tombstones.read().unwrap();
+        let tombstone_seq = tombstones.iter().map(|tombstone| tombstone.seq).max();
+        self.rep.max_seq().max(tombstone_seq.unwrap_or(0))
     }
 
-    pub fn insert_with_priority(
-        &mut self,
-        priority: f64,
-        key: Vec<u8>,
-        mut value: Option<Vec<u8>>,
-    ) {
-        // the tree is empty - new node is the root
-        if matches!(self.root, None) {
-            let new_node = Arc::new(RwLock::new(Node {
-                key,
-                value,
-                priority,
-                left: None,
-                right: None,
-                parent: None,
-            }));
-            self.size += 1;
-            self.root = Some(new_node);
-            return;
-        }
+    pub fn search(&self, key: &[u8]) -> (Option<Vec<u8>>, bool) {
+        self.search_at(key, u64::MAX)
+    }
 
-        // find the parent of the node we're going to insert
-        let mut node_link: Link = Some(self.root.as_ref().unwrap().clone());
-        let mut parent_link: Link = None;
-        let mut replace = false;
-        while !matches!(node_link, None) {
-            let node = node_link.as_ref().unwrap().clone();
-            parent_link = Some(node.clone());
-
-            if key == node.read().unwrap().key {
-                replace = true;
-                break;
-            } else if key > node.read().unwrap().key {
-                node_link = node.get_right();
-            } else {
-                node_link = node.get_left();
+    // search for the newest version of the key written at or before seq. Like search, it returns
+    // the value and whether a version of the key was found. A key deleted by a range tombstone or
+    // with an expired value is found with a value of None, so older tables aren't searched
+    pub fn search_at(&self, key: &[u8], seq: u64) -> (Option<Vec<u8>>, bool) {
+        let tombstone_seq = newest_covering(&self.range_tombstones.read().unwrap(), key, seq);
+        let (value, found) = match tombstone_seq {
+            None => self.rep.search_at(key, seq),
+            Some(tombstone_seq) => {
+                // the key may have been written again after the range was deleted
+                let newest = self
+                    .rep
+                    .iter_from(Some(key))
+                    .take_while(|version| version.key == key)
+                    .find(|version| version.seq <= seq);
+                match newest {
+                    Some(version) if version.seq > tombstone_seq => (version.value, true),
+                    _ => (None, true),
+                }
             }
-        }
-
-        if replace {
-            std::mem::swap(&mut value, &mut parent_link.unwrap().write().unwrap().value);
-            return;
-        }
+        };
 
-        let new_node = Arc::new(RwLock::new(Node {
-            key,
-            value,
-            priority,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        self.size += 1;
-        let parent = parent_link.as_ref().unwrap().clone();
-        if parent.read().unwrap().key <= new_node.read().unwrap().key {
-            parent.set_right(Some(new_node.clone()))
-        } else {
-            parent.set_left(Some(new_node.clone()));
-        }
-        new_node.set_parent(Some(parent.clone()));
-
-        while new_node.is_heap_invariant() {
-            let parent = new_node.get_parent().unwrap();
-            if parent.is_left_child(Some(new_node.clone())) {
-                self.rotate_right(&mut new_node.clone());
-            } else {
-                self.rotate_left(&mut new_node.clone());
-            }
+        let (value, expires_at) = decode_value(value);
+        if expires_at.is_some_and(|expires_at| expires_at <= sstable::now_millis()) {
+            return (None, true);
         }
+        (value, found)
     }
 
-    fn rotate_left(&mut self, x: &mut Arc<RwLock<Node>>) {
-        if matches!(x.get_parent(), None) {
-            panic!("cannot rorate root of tree");
-        }
-
-        let y = x.get_parent().unwrap();
-        if y.is_left_child(Some(x.clone())) {
-            panic!("cannot rotate_left on a left child");
-        }
+    // insert the value for the key. If the key is already in the memtable, the value is only
+    // replaced if seq is newer than the sequence number of the existing value
+    pub fn insert(&self, key: Vec<u8>, value: Option<Vec<u8>>, seq: u64) {
+        self.rep.insert(key, encode_value(value, None), seq, &[]);
+    }
 
-        if matches!(y.get_parent(), None) {
-            x.set_parent(None);
-            self.root = Some(x.clone());
-        } else {
-            let p = y.get_parent().unwrap();
-            if p.is_left_child(Some(y.clone())) {
-                p.set_left(Some(x.clone()));
-            } else {
-                p.set_right(Some(x.clone()));
-            }
-            x.set_parent(Some(p.clone()));
-        }
+    // insert the version, keeping the older versions of the key that are still visible to any of
+    // the snapshots
+    pub fn insert_version(&self, version: Version, snapshots: &[u64]) {
+        let value = encode_value(version.value, version.expires_at);
+        self.rep.insert(version.key, value, version.seq, snapshots);
+    }
 
-        y.set_right(x.get_left());
-        if !matches!(x.get_left(), None) {
-            let x_left = x.get_left().unwrap();
-            x_left.set_parent(Some(y.clone()));
-        }
+    // insert the value for the key, keeping the older versions of the key that are still visible
+    // to any of the snapshots. snapshots are the sequence numbers of the live snapshots
+    pub fn insert_retaining(
+        &self,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        seq: u64,
+        snapshots: &[u64],
+    ) {
+        self.rep
+            .insert(key, encode_value(value, None), seq, snapshots);
+    }
 
-        x.set_left(Some(y.clone()));
-        y.set_parent(Some(x.clone()));
+    // delete every key in [start, end) written before seq
+    pub fn delete_range(&self, start: Vec<u8>, end: Vec<u8>, seq: u64) {
+        self.add_range_tombstone(RangeTombstone { start, end, seq });
     }
 
-    fn rotate_right(&mut self, x: &mut Arc<RwLock<Node>>) {
-        if matches!(x.get_parent(), None) {
-            panic!("cannot rotate the root of the tree");
+    pub fn add_range_tombstone(&self, tombstone: RangeTombstone) {
+        let mut tombstones = self.range_tombstones.write().unwrap();
+        if !tombstones.contains(&tombstone) {
+            tombstones.push(tombstone);
         }
+    }
 
-        let y = x.get_parent().unwrap();
-
-        if y.is_right_child(Some(x.clone())) {
-            panic!("cannot rotate_right on a right child");
-        }
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().unwrap().clone()
+    }
 
-        if matches!(y.get_parent(), None) {
-            x.set_parent(None);
-            self.root = Some(x.clone());
-        } else {
-            let p = y.get_parent().unwrap();
-            if p.is_left_child(Some(y.clone())) {
-                p.set_left(Some(x.clone()));
-            } else {
-                p.set_right(Some(x.clone()));
-            }
-            x.set_parent(Some(p.clone()));
-        }
+    // expired values are returned with their expiry, it's up to the caller to skip them
+    pub fn iter(&self) -> MemtableIterator {
+        decode_versions(self.rep.iter_from(None))
+    }
 
-        y.set_left(x.get_right());
-        if !matches!(x.get_right(), None) {
-            let x_right = x.get_right().unwrap();
-            x_right.set_parent(Some(y.clone()));
-        }
+    // iterate the keys in ascending order starting at the first key >= start. If start is None
+    // it will iterate from the smallest key. Callers are responsible for stopping at the end bound
+    pub fn iter_from(&self, start: Option<&[u8]>) -> MemtableIterator {
+        decode_versions(self.rep.iter_from(start))
+    }
 
-        x.set_right(Some(y.clone()));
-        y.set_parent(Some(x.clone()));
+    // iterate the keys in descending order starting at the last key < end. If end is None it
+    // will iterate from the largest key. Callers are responsible for stopping at the start bound
+    pub fn iter_rev_from(&self, end: Option<&[u8]>) -> MemtableIterator {
+        decode_versions(self.rep.iter_rev_from(end))
     }
 }
 
-#[cfg(test)]
-mod memtable_tests {
-    use super::*;
-
-    #[test]
-    fn it_returns_false_if_empty() {
-        let memtable = Memtable::new();
-        let (val, found) = memtable.search(&"albert".as_bytes());
-        assert_eq!(0, memtable.size());
-        assert_eq!(val, None);
-        assert_eq!(found, false);
+impl IntoIterator for Memtable {
+    type Item = Version;
+    type IntoIter = MemtableIterator;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
+}
 
-    #[test]
-    fn it_can_find_and_delete_the_root_value() {
-        let mut memtable = Memtable::new();
-        assert_eq!(0, memtable.size());
-        memtable.insert(
-            String::from("guy").into_bytes(),
-            Some(String::from("tim").into_bytes()),
-        );
-        assert_eq!(1, memtable.size());
-        let (val_o, found) = memtable.search(&"guy".as_bytes());
-        assert_eq!(val_o.is_some(), true);
-        let val = val_o.unwrap();
-        assert_eq!(val, String::from("tim").into_bytes());
-        assert_eq!(found, true);
-
-        memtable.insert(String::from("guy").into_bytes(), None);
-        assert_eq!(1, memtable.size());
-        let (val_o, found) = memtable.search(&"guy".as_bytes());
-        assert_eq!(val_o, None);
-        assert_eq!(found, true);
+fn encode_value(value: Option<Vec<u8>>, expires_at: Option<u64>) -> Option<Vec<u8>> {
+    let mut value = value?;
+    match expires_at {
+        Some(expires_at) => {
+            value.extend_from_slice(&expires_at.to_be_bytes());
+            value.push(TAG_EXPIRES);
+        }
+        None => value.push(TAG_NO_EXPIRY),
     }
+    Some(value)
+}
 
-    #[test]
-    fn it_can_put_many_children_in_itself() {
-        let mut memtable = Memtable::new();
-        assert_eq!(0, memtable.size());
-        memtable.insert(
-            String::from("a").into_bytes(),
-            Some(String::from("1").into_bytes()),
-        );
-        assert_eq!(1, memtable.size());
-
-        memtable.insert(
-            String::from("b").into_bytes(),
-            Some(String::from("2").into_bytes()),
-        );
-        assert_eq!(2, memtable.size());
-
-        memtable.insert(
-            String::from("c").into_bytes(),
-            Some(String::from("3").into_bytes()),
-        );
-        assert_eq!(3, memtable.size());
-
-        memtable.insert(
-            String::from("d").into_bytes(),
-            Some(String::from("4").into_bytes()),
-        );
-        assert_eq!(4, memtable.size());
-
-        let (val_o, found) = memtable.search(&"a".as_bytes());
-        assert_eq!(val_o.is_some(), true);
-        assert_eq!(val_o.unwrap(), String::from("1").as_bytes());
-        assert_eq!(found, true);
-
-        let (val_o, found) = memtable.search(&"b".as_bytes());
-        assert_eq!(val_o.is_some(), true);
-        assert_eq!(val_o.unwrap(), String::from("2").as_bytes());
-        assert_eq!(found, true);
-
-        let (val_o, found) = memtable.search(&"c".as_bytes());
-        assert_eq!(val_o.is_some(), true);
-        assert_eq!(val_o.unwrap(), String::from("3").as_bytes());
-        assert_eq!(found, true);
-
-        let (val_o, found) = memtable.search(&"d".as_bytes());
-        assert_eq!(val_o.is_some(), true);
-        assert_eq!(val_o.unwrap(), String::from("4").as_bytes());
-        assert_eq!(found, true);
-
-        // ensure we can update all the values
-        memtable.insert(
-            String::from("a").into_bytes(),
-            Some(String::from("5").into_bytes()),
-        );
-        assert_eq!(4, memtable.size());
-
-        memtable.insert(
-            String::from("b").into_bytes(),
-            Some(String::from("6").into_bytes()),
-        );
-        assert_eq!(4, memtable.size());
-
-        memtable.insert(
-            String::from("c").into_bytes(),
-            Some(String::from("7").into_bytes()),
-        );
-        assert_eq!(4, memtable.size());
-
-        memtable.insert(
-            String::from("d").into_bytes(),
-            Some(String::from("8").into_bytes()),
-        );
-        assert_eq!(4, memtable.size());
-
-        let (val_o, found) = memtable.search(&"a".as_bytes());
-        assert_eq!(val_o.is_some(), true);
-        assert_eq!(val_o.unwrap(), String::from("5").as_bytes());
-        assert_eq!(found, true);
-
-        let (val_o, found) = memtable.search(&"b".as_bytes());
-        assert_eq!(val_o.is_some(), true);
-        assert_eq!(val_o.unwrap(), String::from("6").as_bytes());
-        assert_eq!(found, true);
-
-        let (val_o, found) = memtable.search(&"c".as_bytes());
-        assert_eq!(val_o.is_some(), true);
-        assert_eq!(val_o.unwrap(), String::from("7").as_bytes());
-        assert_eq!(found, true);
-
-        let (val_o, found) = memtable.search(&"d".as_bytes());
-        assert_eq!(val_o.is_some(), true);
-        assert_eq!(val_o.unwrap(), String::from("8").as_bytes());
-        assert_eq!(found, true);
-
-        // ensure can delete all the values
-        memtable.insert(String::from("a").into_bytes(), None);
-        assert_eq!(4, memtable.size());
-        let (val_o, found) = memtable.search(&"a".as_bytes());
-        assert_eq!(val_o.is_none(), true);
-        assert_eq!(found, true);
-
-        memtable.insert(String::from("b").into_bytes(), None);
-        assert_eq!(4, memtable.size());
-        assert_eq!(4, memtable.size());
-        let (val_o, found) = memtable.search(&"a".as_bytes());
-        assert_eq!(val_o.is_none(), true);
-        assert_eq!(found, true);
-
-        memtable.insert(String::from("c").into_bytes(), None);
-        assert_eq!(4, memtable.size());
-        assert_eq!(4, memtable.size());
-        let (val_o, found) = memtable.search(&"a".as_bytes());
-        assert_eq!(val_o.is_none(), true);
-        assert_eq!(found, true);
-
-        memtable.insert(String::from("d").into_bytes(), None);
-        assert_eq!(4, memtable.size());
-        assert_eq!(4, memtable.size());
-        let (val_o, found) = memtable.search(&"a".as_bytes());
-        assert_eq!(val_o.is_none(), true);
-        assert_eq!(found, true);
-    }
+// split a value stored in the rep into the value and its expiry
+fn decode_value(value: Option<Vec<u8>>) -> (Option<Vec<u8>>, Option<u64>) {
+    let mut value = match value {
+        Some(value) => value,
+        None => return (None, None),
+    };
+    let expires_at = match value.pop() {
+        Some(TAG_EXPIRES) => {
+            let mut expires_at = [0; 8];
+            expires_at.copy_from_slice(&value[value.len() - 8..]);
+            value.truncate(value.len() - 8);
+            Some(u64::from_be_bytes(expires_at))
+        }
+        _ => None,
+    };
+    (Some(value), expires_at)
 }
 
-#[cfg(test)]
-mod insert_tests {
-    use super::*;
+fn decode_versions(iter: MemtableIterator) -> MemtableIterator {
+    Box::new(iter.map(|mut version| {
+        (version.value, version.expires_at) = decode_value(version.value);
+        version
+    }))
+}
 
-    #[test]
-    fn test_insert_some_rotations() {
-        let mut m = Memtable::new();
-        let p = Arc::new(RwLock::new(Node {
-            key: String::from("50").into_bytes(),
-            value: Some(String::from("50").into_bytes()),
-            priority: 50f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let y = Arc::new(RwLock::new(Node {
-            key: String::from("40").into_bytes(),
-            value: Some(String::from("40").into_bytes()),
-            priority: 40f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("30").into_bytes(),
-            value: Some(String::from("30").into_bytes()),
-            priority: 30f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        m.root = Some(p.clone());
-
-        p.set_left(Some(y.clone()));
-        y.set_parent(Some(p.clone()));
-
-        y.set_left(Some(x.clone()));
-        x.set_parent(Some(y.clone()));
-
-        m.insert_with_priority(
-            45f64,
-            String::from("35").into_bytes(),
-            Some(String::from("45").into_bytes()),
-        );
-
-        assert_eq!(true, Arc::ptr_eq(&p, m.root.as_ref().unwrap()));
-
-        let new_node = p.get_left().unwrap().clone();
-        assert_eq!(
-            String::from("35").into_bytes(),
-            new_node.read().unwrap().key
-        );
-
-        assert_eq!(true, new_node.is_left_child(Some(x.clone())));
-        assert_eq!(true, x.is_parent(Some(new_node.clone())));
-
-        assert_eq!(true, new_node.is_right_child(Some(y.clone())));
-        assert_eq!(true, y.is_parent(Some(new_node.clone())));
-    }
+// memory used by a value, or nothing for a deletion
+fn value_size(value: &Option<Vec<u8>>) -> usize {
+    value.as_ref().map_or(0, |value| value.len())
 }
 
 #[cfg(test)]
-mod rotate_left_tests {
+mod iterator_tests {
     use super::*;
+    use std::sync::Arc;
+    use std::thread;
 
-    #[test]
-    fn test_rotate_left_full_rotation() {
-        let mut m = Memtable::new();
-        let p = Arc::new(RwLock::new(Node {
-            key: String::from("0").into_bytes(),
-            value: Some(String::from("0").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let y = Arc::new(RwLock::new(Node {
-            key: String::from("1").into_bytes(),
-            value: Some(String::from("1").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("2").into_bytes(),
-            value: Some(String::from("2").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x_left = Arc::new(RwLock::new(Node {
-            key: String::from("3").into_bytes(),
-            value: Some(String::from("3").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        m.root = Some(p.clone());
-        p.set_right(Some(y.clone()));
-        y.set_parent(Some(p.clone()));
-
-        y.set_right(Some(x.clone()));
-        x.set_parent(Some(y.clone()));
-
-        x.set_left(Some(x_left.clone()));
-        x_left.set_parent(Some(y.clone()));
-
-        m.rotate_left(&mut x.clone());
-
-        // check x has now replaced y as the right child of P
-        assert_eq!(false, matches!(p.get_right(), None));
-        assert_eq!(true, p.is_right_child(Some(x.clone())));
-        assert_eq!(false, matches!(x.get_parent(), None));
-        assert_eq!(true, x.is_parent(Some(p.clone())));
-
-        // check Y is now the left child of X
-        assert_eq!(false, matches!(x.get_left(), None));
-        assert_eq!(true, x.is_left_child(Some(y.clone())));
-        assert_eq!(false, matches!(y.get_parent(), None));
-        assert_eq!(true, y.is_parent(Some(x)));
-
-        // check X's left is now the right child of Y
-        assert_eq!(false, matches!(y.get_right(), None));
-        assert_eq!(true, y.is_right_child(Some(x_left.clone())));
-        assert_eq!(false, matches!(x_left.get_parent(), None));
-        assert_eq!(true, x_left.is_parent(Some(y)));
+    fn keys(iter: MemtableIterator) -> Vec<String> {
+        iter.map(|version| String::from_utf8(version.key).unwrap())
+            .collect()
     }
 
-    #[test]
-    fn test_rotate_left_full_y_is_left_child_of_p() {
-        let mut m = Memtable::new();
-        let p = Arc::new(RwLock::new(Node {
-            key: String::from("0").into_bytes(),
-            value: Some(String::from("0").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let y = Arc::new(RwLock::new(Node {
-            key: String::from("1").into_bytes(),
-            value: Some(String::from("1").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("2").into_bytes(),
-            value: Some(String::from("2").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x_left = Arc::new(RwLock::new(Node {
-            key: String::from("3").into_bytes(),
-            value: Some(String::from("3").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        m.root = Some(p.clone());
-        p.set_left(Some(y.clone()));
-        y.set_parent(Some(p.clone()));
-
-        y.set_right(Some(x.clone()));
-        x.set_parent(Some(y.clone()));
-
-        x.set_left(Some(x_left.clone()));
-        x_left.set_parent(Some(y.clone()));
-
-        m.rotate_left(&mut x.clone());
-
-        // check x has now replaced y as the right child of P
-        assert_eq!(false, matches!(p.get_left(), None));
-        assert_eq!(true, p.is_left_child(Some(x.clone())));
-        assert_eq!(false, matches!(x.get_parent(), None));
-        assert_eq!(true, x.is_parent(Some(p.clone())));
-
-        // check Y is now the left child of X
-        assert_eq!(false, matches!(x.get_left(), None));
-        assert_eq!(true, x.is_left_child(Some(y.clone())));
-        assert_eq!(false, matches!(y.get_parent(), None));
-        assert_eq!(true, y.is_parent(Some(x)));
-
-        // check X's left is now the right child of Y
-        assert_eq!(false, matches!(y.get_right(), None));
-        assert_eq!(true, y.is_right_child(Some(x_left.clone())));
-        assert_eq!(false, matches!(x_left.get_parent(), None));
-        assert_eq!(true, x_left.is_parent(Some(y)));
+    // every test runs against each rep
+    const REPS: [config::MemtableRepKind; 3] = [
+        config::MemtableRepKind::SkipList,
+        config::MemtableRepKind::Treap,
+        config::MemtableRepKind::Arena,
+    ];
+
+    fn test_memtable(kind: &config::MemtableRepKind) -> Memtable {
+        let memtable = Memtable::with_rep(kind);
+        for key in ["d", "a", "f", "c", "b", "e"] {
+            memtable.insert(key.bytes().collect(), Some(key.bytes().collect()), 1);
+        }
+        memtable
     }
 
     #[test]
-    fn test_rotate_left_parent_is_root() {
-        let mut m = Memtable::new();
-        let p = Arc::new(RwLock::new(Node {
-            key: String::from("0").into_bytes(),
-            value: Some(String::from("0").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("2").into_bytes(),
-            value: Some(String::from("2").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        m.root = Some(p.clone());
-        p.set_right(Some(x.clone()));
-        x.set_parent(Some(p.clone()));
-
-        m.rotate_left(&mut x.clone());
-
-        assert_eq!(true, Arc::ptr_eq(&x, m.root.as_ref().unwrap()));
-        assert_eq!(true, matches!(x.get_parent(), None));
-
-        assert_eq!(true, x.is_left_child(Some(p.clone())));
-        assert_eq!(true, p.is_parent(Some(x.clone())));
-
-        assert_eq!(true, matches!(p.get_right(), None));
+    fn it_iterates_in_order() {
+        for kind in &REPS {
+            let memtable = test_memtable(kind);
+            assert_eq!(vec!["a", "b", "c", "d", "e", "f"], keys(memtable.iter()));
+            assert_eq!(
+                vec!["a", "b", "c", "d", "e", "f"],
+                keys(memtable.iter_from(None))
+            );
+            assert_eq!(
+                vec!["f", "e", "d", "c", "b", "a"],
+                keys(memtable.iter_rev_from(None))
+            );
+        }
     }
 
     #[test]
-    #[should_panic]
-    fn test_rotate_left_panics_if_x_is_root() {
-        let mut m = Memtable::new();
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("0").into_bytes(),
-            value: Some(String::from("0").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        m.root = Some(x.clone());
-        m.rotate_left(&mut x.clone());
+    fn it_keeps_the_newest_version() {
+        for kind in &REPS {
+            let memtable = Memtable::with_rep(kind);
+            memtable.insert("a".bytes().collect(), Some("new".bytes().collect()), 2);
+            memtable.insert("a".bytes().collect(), Some("old".bytes().collect()), 1);
+            assert_eq!(
+                (Some("new".bytes().collect()), true),
+                memtable.search("a".as_bytes())
+            );
+            assert_eq!(2, memtable.iter().next().unwrap().seq);
+            assert_eq!(2, memtable.max_seq());
+        }
     }
 
     #[test]
-    #[should_panic]
-    fn test_rotate_left_panics_if_x_is_left_child() {
-        let mut m = Memtable::new();
-        let p = Arc::new(RwLock::new(Node {
-            key: String::from("0").into_bytes(),
-            value: Some(String::from("0").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("2").into_bytes(),
-            value: Some(String::from("2").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        m.root = Some(p.clone());
-        p.set_left(Some(x.clone()));
-        x.set_parent(Some(p.clone()));
-        m.rotate_left(&mut x.clone());
-    }
-}
-
-#[cfg(test)]
-mod rotate_right_tests {
-    use super::*;
+    fn it_tracks_its_approximate_size() {
+        for kind in &REPS {
+            let memtable = Memtable::with_rep(kind);
+            assert_eq!(0, memtable.approximate_size());
+
+            memtable.insert("a".bytes().collect(), Some(vec![0; 1000]), 1);
+            let one_key = memtable.approximate_size();
+            assert_eq!(true, one_key > 1001);
+
+            // a bigger value takes more space than a smaller one, whatever the number of keys.
+            // The arena doesn't free the replaced value until it's dropped
+            memtable.insert("a".bytes().collect(), Some(vec![0; 10]), 2);
+            if *kind != config::MemtableRepKind::Arena {
+                assert_eq!(one_key - 990, memtable.approximate_size());
+            }
+            memtable.insert("b".bytes().collect(), Some(vec![0; 10]), 3);
+            assert_eq!(true, memtable.approximate_size() < one_key * 2);
 
-    #[test]
-    #[should_panic]
-    fn panics_if_x_is_root() {
-        let mut m = Memtable::new();
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("2").into_bytes(),
-            value: Some(String::from("2").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        m.root = Some(x.clone());
-        m.rotate_right(&mut x.clone());
+            // older versions kept for a snapshot count too
+            let before = memtable.approximate_size();
+            memtable.insert_retaining("b".bytes().collect(), None, 4, &[3]);
+            assert_eq!(true, memtable.approximate_size() > before);
+        }
     }
 
     #[test]
-    #[should_panic]
-    fn panics_if_x_is_right_child() {
-        let mut m = Memtable::new();
-        let p = Arc::new(RwLock::new(Node {
-            key: String::from("0").into_bytes(),
-            value: Some(String::from("0").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("2").into_bytes(),
-            value: Some(String::from("2").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        m.root = Some(p.clone());
-        p.set_right(Some(x.clone()));
-        x.set_parent(Some(p.clone()));
-
-        m.rotate_right(&mut x.clone());
+    fn it_keeps_versions_visible_to_snapshots() {
+        for kind in &REPS {
+            let memtable = Memtable::with_rep(kind);
+            let snapshots = [2];
+            memtable.insert_retaining(
+                "a".bytes().collect(),
+                Some("1".bytes().collect()),
+                1,
+                &snapshots,
+            );
+            memtable.insert_retaining(
+                "a".bytes().collect(),
+                Some("3".bytes().collect()),
+                3,
+                &snapshots,
+            );
+            memtable.insert_retaining("a".bytes().collect(), None, 4, &snapshots);
+            memtable.insert_retaining(
+                "b".bytes().collect(),
+                Some("5".bytes().collect()),
+                5,
+                &snapshots,
+            );
+
+            assert_eq!((None, true), memtable.search("a".as_bytes()));
+            assert_eq!(
+                (Some("1".bytes().collect()), true),
+                memtable.search_at("a".as_bytes(), 2)
+            );
+            assert_eq!((None, false), memtable.search_at("b".as_bytes(), 2));
+
+            // the version written at 3 isn't visible to the snapshot, so it isn't kept
+            let versions: Vec<(String, u64)> = memtable
+                .iter()
+                .map(|v| (String::from_utf8(v.key).unwrap(), v.seq))
+                .collect();
+            assert_eq!(
+                vec![
+                    (String::from("a"), 4),
+                    (String::from("a"), 1),
+                    (String::from("b"), 5)
+                ],
+                versions
+            );
+
+            // versions of a key are returned newest first in reverse too
+            let seqs: Vec<u64> = memtable.iter_rev_from(None).map(|v| v.seq).collect();
+            assert_eq!(vec![5, 4, 1], seqs);
+        }
     }
 
     #[test]
-    fn handles_case_where_x_parent_is_root() {
-        let mut m = Memtable::new();
-        let p = Arc::new(RwLock::new(Node {
-            key: String::from("0").into_bytes(),
-            value: Some(String::from("0").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("2").into_bytes(),
-            value: Some(String::from("2").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        m.root = Some(p.clone());
-        p.set_left(Some(x.clone()));
-        x.set_parent(Some(p.clone()));
-
-        m.rotate_right(&mut x.clone());
-
-        assert_eq!(true, Arc::ptr_eq(&x, m.root.as_ref().unwrap()));
-        assert_eq!(true, matches!(x.get_parent(), None));
-
-        assert_eq!(true, x.is_right_child(Some(p.clone())));
-        assert_eq!(true, p.is_parent(Some(x.clone())));
-
-        assert_eq!(true, matches!(p.get_left(), None));
+    fn it_allows_reads_while_writing() {
+        for kind in &REPS {
+            let memtable = Arc::new(Memtable::with_rep(kind));
+            let writer = memtable.clone();
+            let handle = thread::spawn(move || {
+                // out of order, so the treap rotates nodes while it's being read
+                for i in 1..=10000u64 {
+                    let key = format!("{:05}", i * 7919 % 10000);
+                    writer.insert(key.into_bytes(), Some(vec![0; 10]), i);
+                }
+            });
+
+            // readers always see every key once and in order
+            while memtable.size() < 10000 {
+                let keys = keys(memtable.iter());
+                let mut sorted = keys.clone();
+                sorted.sort();
+                sorted.dedup();
+                assert_eq!(sorted, keys);
+                let seq = memtable.max_seq();
+                if seq > 0 {
+                    let key = format!("{:05}", seq * 7919 % 10000);
+                    assert_eq!(true, memtable.search(key.as_bytes()).1);
+                }
+            }
+            handle.join().unwrap();
+            assert_eq!(10000, keys(memtable.iter()).len());
+        }
     }
 
     #[test]
-    fn full_rotate_y_is_p_right_child() {
-        let mut m = Memtable::new();
-        let p = Arc::new(RwLock::new(Node {
-            key: String::from("0").into_bytes(),
-            value: Some(String::from("0").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let y = Arc::new(RwLock::new(Node {
-            key: String::from("1").into_bytes(),
-            value: Some(String::from("1").into_bytes()),
-            priority: 1f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("2").into_bytes(),
-            value: Some(String::from("2").into_bytes()),
-            priority: 2f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x_right = Arc::new(RwLock::new(Node {
-            key: String::from("3").into_bytes(),
-            value: Some(String::from("3").into_bytes()),
-            priority: 3f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        m.root = Some(p.clone());
-
-        p.set_right(Some(y.clone()));
-        y.set_parent(Some(p.clone()));
-
-        y.set_left(Some(x.clone()));
-        x.set_parent(Some(y.clone()));
-
-        x.set_right(Some(x_right.clone()));
-        x_right.set_parent(Some(x.clone()));
-
-        m.rotate_right(&mut x.clone());
-
-        assert_eq!(true, p.is_right_child(Some(x.clone())));
-        assert_eq!(true, x.is_parent(Some(p.clone())));
-
-        assert_eq!(true, x.is_right_child(Some(y.clone())));
-        assert_eq!(true, y.is_parent(Some(x.clone())));
-
-        assert_eq!(true, y.is_left_child(Some(x_right.clone())));
-        assert_eq!(true, x_right.is_parent(Some(y.clone())));
+    fn it_can_seek_to_start_key() {
+        for kind in &REPS {
+            let memtable = test_memtable(kind);
+            assert_eq!(
+                vec!["c", "d", "e", "f"],
+                keys(memtable.iter_from(Some("c".as_bytes())))
+            );
+            assert_eq!(
+                vec!["d", "e", "f"],
+                keys(memtable.iter_from(Some("cc".as_bytes())))
+            );
+            assert_eq!(0, keys(memtable.iter_from(Some("g".as_bytes()))).len());
+        }
     }
 
     #[test]
-    fn full_rotate_y_is_p_left_child() {
-        let mut m = Memtable::new();
-        let p = Arc::new(RwLock::new(Node {
-            key: String::from("0").into_bytes(),
-            value: Some(String::from("0").into_bytes()),
-            priority: 0f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let y = Arc::new(RwLock::new(Node {
-            key: String::from("1").into_bytes(),
-            value: Some(String::from("1").into_bytes()),
-            priority: 1f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x = Arc::new(RwLock::new(Node {
-            key: String::from("2").into_bytes(),
-            value: Some(String::from("2").into_bytes()),
-            priority: 2f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-        let x_right = Arc::new(RwLock::new(Node {
-            key: String::from("3").into_bytes(),
-            value: Some(String::from("3").into_bytes()),
-            priority: 3f64,
-            left: None,
-            right: None,
-            parent: None,
-        }));
-
-        m.root = Some(p.clone());
-
-        p.set_left(Some(y.clone()));
-        y.set_parent(Some(p.clone()));
-
-        y.set_left(Some(x.clone()));
-        x.set_parent(Some(y.clone()));
-
-        x.set_right(Some(x_right.clone()));
-        x_right.set_parent(Some(x.clone()));
-
-        m.rotate_right(&mut x.clone());
-
-        assert_eq!(true, p.is_left_child(Some(x.clone())));
-        assert_eq!(true, x.is_parent(Some(p.clone())));
-
-        assert_eq!(true, x.is_right_child(Some(y.clone())));
-        assert_eq!(true, y.is_parent(Some(x.clone())));
-
-        assert_eq!(true, y.is_left_child(Some(x_right.clone())));
-        assert_eq!(true, x_right.is_parent(Some(y.clone())));
-    }
-}
-
-#[derive(Debug)]
-pub struct MemtableIterator {
-    unvisited: Vec<Link>,
-}
-
-impl MemtableIterator {
-    fn push_left_edge(&mut self, link: &Link) {
-        if matches!(link, None) {
-            return;
-        }
-        let mut link = Some(link.as_ref().unwrap().clone());
-        while !matches!(link, None) {
-            let node = link.as_ref().unwrap();
-            self.unvisited.push(Some(node.clone()));
-            link = node.get_left();
+    fn it_can_seek_to_end_key_in_reverse() {
+        for kind in &REPS {
+            let memtable = test_memtable(kind);
+            assert_eq!(
+                vec!["b", "a"],
+                keys(memtable.iter_rev_from(Some("c".as_bytes())))
+            );
+            assert_eq!(
+                vec!["c", "b", "a"],
+                keys(memtable.iter_rev_from(Some("cc".as_bytes())))
+            );
+            assert_eq!(0, keys(memtable.iter_rev_from(Some("a".as_bytes()))).len());
         }
     }
-}
-
-impl Iterator for MemtableIterator {
-    type Item = (Vec<u8>, Option<Vec<u8>>);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let link = self.unvisited.pop()?;
-
-        let node = link.as_ref().unwrap();
-        self.push_left_edge(&node.get_right());
-        return Some((
-            node.read().unwrap().key.clone(),
-            node.read().unwrap().value.clone(),
-        ));
-    }
-}
-
-impl IntoIterator for Memtable {
-    type Item = (Vec<u8>, Option<Vec<u8>>);
-    type IntoIter = MemtableIterator;
-    fn into_iter(self) -> Self::IntoIter {
-        self.iter()
-    }
-}
-
-impl Memtable {
-    pub fn iter(&self) -> MemtableIterator {
-        let mut iter = MemtableIterator {
-            unvisited: Vec::new(),
-        };
-        iter.push_left_edge(&self.root);
-        iter
+    #[test]
+    fn it_hides_keys_deleted_by_a_range() {
+        for kind in &REPS {
+            let memtable = test_memtable(kind);
+            memtable.delete_range("b".bytes().collect(), "d".bytes().collect(), 2);
+            memtable.insert("c".bytes().collect(), Some("new".bytes().collect()), 3);
+
+            assert_eq!(
+                (Some("a".bytes().collect()), true),
+                memtable.search("a".as_bytes())
+            );
+            assert_eq!((None, true), memtable.search("b".as_bytes()));
+            assert_eq!(
+                (Some("new".bytes().collect()), true),
+                memtable.search("c".as_bytes())
+            );
+            assert_eq!(
+                (Some("d".bytes().collect()), true),
+                memtable.search("d".as_bytes())
+            );
+            // keys that were never written are deleted too, so older tables aren't searched
+            assert_eq!((None, true), memtable.search("bb".as_bytes()));
+
+            // reads from before the tombstone still see the old values
+            assert_eq!(
+                (Some("b".bytes().collect()), true),
+                memtable.search_at("b".as_bytes(), 1)
+            );
+            assert_eq!(3, memtable.max_seq());
+            assert_eq!(1, memtable.range_tombstones().len());
+        }
     }
 }
@@ -1,10 +1,18 @@
+use std::mem;
 use std::sync::Arc;
 use std::sync::RwLock;
 
+use super::value_size;
+use crate::snapshot;
+
 pub struct Node {
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
     pub priority: f64,
+    // sequence number of the write that set the value
+    pub seq: u64,
+    // older versions of the key that are still visible to a snapshot, newest first
+    pub history: Vec<(u64, Option<Vec<u8>>)>,
     pub left: Link,
     pub right: Link,
     pub parent: Link,
@@ -12,6 +20,68 @@ pub struct Node {
 
 pub type Link = Option<Arc<RwLock<Node>>>;
 
+// memory used by a node besides its key and values: the node itself inside its lock, plus the
+// reference counts of the Arc
+const NODE_OVERHEAD: usize = mem::size_of::<RwLock<Node>>() + 2 * mem::size_of::<usize>();
+
+// memory used by each older version besides its value
+const VERSION_OVERHEAD: usize = mem::size_of::<(u64, Option<Vec<u8>>)>();
+
+impl Node {
+    // approximate number of bytes of memory used by the node, including all of its versions
+    pub fn approximate_size(&self) -> usize {
+        let history: usize = self
+            .history
+            .iter()
+            .map(|(_, value)| VERSION_OVERHEAD + value_size(value))
+            .sum();
+        NODE_OVERHEAD + self.key.len() + value_size(&self.value) + history
+    }
+
+    // add a version of the key. The newest version becomes the value of the node and older
+    // versions are only kept while one of the snapshots can still see them
+    pub fn add_version(&mut self, value: Option<Vec<u8>>, seq: u64, snapshots: &[u64]) {
+        let mut versions = Vec::with_capacity(self.history.len() + 2);
+        versions.push((seq, value));
+        versions.push((self.seq, self.value.take()));
+        versions.append(&mut self.history);
+
+        // order newest first. The sort is stable, so if a version with the same sequence number
+        // is added again, it replaces the existing one
+        versions.sort_by_key(|(seq, _)| std::cmp::Reverse(*seq));
+        versions.dedup_by_key(|(seq, _)| *seq);
+
+        let mut versions = versions.into_iter();
+        let (newest_seq, newest_value) = versions.next().unwrap();
+        self.seq = newest_seq;
+        self.value = newest_value;
+
+        let mut newer_seq = newest_seq;
+        for (seq, value) in versions {
+            if snapshot::is_needed(seq, newer_seq, snapshots) {
+                self.history.push((seq, value));
+            }
+            newer_seq = seq;
+        }
+    }
+
+    // the newest version written at or before seq. Returns the value and whether there is such a
+    // version
+    pub fn version_at(&self, seq: u64) -> (Option<Vec<u8>>, bool) {
+        if self.seq <= seq {
+            return (self.value.clone(), true);
+        }
+        match self
+            .history
+            .iter()
+            .find(|(version_seq, _)| *version_seq <= seq)
+        {
+            Some((_, value)) => (value.clone(), true),
+            None => (None, false),
+        }
+    }
+}
+
 pub trait NodeMethods {
     fn get_parent(&self) -> Link;
 
@@ -41,6 +111,8 @@ impl std::fmt::Debug for Node {
         f.debug_struct("Node")
             .field("key", &self.key)
             .field("priority", &self.priority)
+            .field("seq", &self.seq)
+            .field("history", &self.history.len())
             .field("left", &self.left)
             .field("right", &self.right)
             .field(
@@ -0,0 +1,188 @@
+// A memtable implemented as a lock-free skiplist. Every version of a key is its own entry, ordered
+// by key and then newest first, so readers never wait for writers and writers only contend on the
+// entries they touch.
+
+use crossbeam_skiplist::map::Entry;
+use crossbeam_skiplist::SkipMap;
+use std::cmp::Reverse;
+use std::mem;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::{value_size, MemtableIterator, MemtableRep, Version};
+use crate::snapshot;
+
+// a key and the sequence number of the version. Reverse puts newer versions of a key first
+type VersionKey = (Vec<u8>, Reverse<u64>);
+
+type Map = SkipMap<VersionKey, Option<Vec<u8>>>;
+
+// memory used by an entry besides its key and value: the entry itself, plus the tower of links
+// and reference count of the skiplist node. Nodes have two links on average
+const ENTRY_OVERHEAD: usize =
+    mem::size_of::<(VersionKey, Option<Vec<u8>>)>() + 4 * mem::size_of::<usize>();
+
+#[derive(Debug, Default)]
+pub struct SkipListRep {
+    map: Arc<Map>,
+    // number of distinct keys
+    size: AtomicU32,
+    approximate_size: AtomicUsize,
+    max_seq: AtomicU64,
+}
+
+impl SkipListRep {
+    pub fn new() -> Self {
+        SkipListRep::default()
+    }
+
+    fn remove(&self, entry: &Entry<VersionKey, Option<Vec<u8>>>) {
+        if entry.remove() {
+            self.approximate_size
+                .fetch_sub(entry_size(entry), Ordering::SeqCst);
+        }
+    }
+}
+
+impl MemtableRep for SkipListRep {
+    // the engine serializes writes, so inserts don't need to coordinate with each other. Readers
+    // see the new version as soon as it's linked in
+    fn insert(&self, key: Vec<u8>, value: Option<Vec<u8>>, seq: u64, snapshots: &[u64]) {
+        if self.map.range(versions_of(&key)).next().is_none() {
+            self.size.fetch_add(1, Ordering::SeqCst);
+        }
+
+        // a version with the same sequence number is replaced
+        let version_key = (key.clone(), Reverse(seq));
+        if let Some(existing) = self.map.get(&version_key) {
+            self.remove(&existing);
+        }
+        let size = ENTRY_OVERHEAD + key.len() + value_size(&value);
+        self.map.insert(version_key, value);
+        self.approximate_size.fetch_add(size, Ordering::SeqCst);
+        // only after the version is readable, so anything up to max_seq can be found
+        self.max_seq.fetch_max(seq, Ordering::SeqCst);
+
+        // only keep the older versions that are still visible to one of the snapshots
+        let mut newer_seq = None;
+        for entry in self.map.range(versions_of(&key)) {
+            let Reverse(seq) = entry.key().1;
+            if let Some(newer_seq) = newer_seq {
+                if !snapshot::is_needed(seq, newer_seq, snapshots) {
+                    self.remove(&entry);
+                }
+            }
+            newer_seq = Some(seq);
+        }
+    }
+
+    fn search_at(&self, key: &[u8], seq: u64) -> (Option<Vec<u8>>, bool) {
+        let start = (key.to_vec(), Reverse(seq));
+        match self.map.lower_bound(Bound::Included(&start)) {
+            Some(entry) if entry.key().0 == key => (entry.value().clone(), true),
+            _ => (None, false),
+        }
+    }
+
+    fn iter_from(&self, start: Option<&[u8]>) -> MemtableIterator {
+        let next = match start {
+            Some(start) => Bound::Included((start.to_vec(), Reverse(u64::MAX))),
+            None => Bound::Unbounded,
+        };
+        Box::new(SkipListIterator {
+            map: self.map.clone(),
+            next,
+            pending: Vec::new(),
+            reverse: false,
+        })
+    }
+
+    fn iter_rev_from(&self, end: Option<&[u8]>) -> MemtableIterator {
+        let next = match end {
+            Some(end) => Bound::Excluded((end.to_vec(), Reverse(u64::MAX))),
+            None => Bound::Unbounded,
+        };
+        Box::new(SkipListIterator {
+            map: self.map.clone(),
+            next,
+            pending: Vec::new(),
+            reverse: true,
+        })
+    }
+
+    fn size(&self) -> u32 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.approximate_size.load(Ordering::SeqCst)
+    }
+
+    fn max_seq(&self) -> u64 {
+        self.max_seq.load(Ordering::SeqCst)
+    }
+}
+
+// iterates the skiplist without holding on to any entry. Each step seeks from the last entry that
+// was returned, so entries can be inserted and removed while the iterator is in use
+pub struct SkipListIterator {
+    map: Arc<Map>,
+    // where the next entry is looked for. Forwards it's a lower bound, in reverse an upper bound
+    next: Bound<VersionKey>,
+    // older versions of the last key returned in reverse, which are returned before moving to
+    // the next key
+    pending: Vec<Version>,
+    reverse: bool,
+}
+
+impl Iterator for SkipListIterator {
+    type Item = Version;
+
+    // every version of a key is returned, newest first
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(version) = self.pending.pop() {
+            return Some(version);
+        }
+
+        if !self.reverse {
+            let entry = self.map.lower_bound(self.next.as_ref())?;
+            self.next = Bound::Excluded(entry.key().clone());
+            return Some(to_version(&entry));
+        }
+
+        // the entries of a key are newest first, so in reverse the oldest version is found
+        // first. Collect all of them to return them in the same order as going forwards
+        let key = self.map.upper_bound(self.next.as_ref())?.key().0.clone();
+        self.pending = self
+            .map
+            .range(versions_of(&key))
+            .map(|entry| to_version(&entry))
+            .collect();
+        self.pending.reverse();
+        self.next = Bound::Excluded((key, Reverse(u64::MAX)));
+        self.pending.pop()
+    }
+}
+
+// the range of entries holding the versions of the key
+fn versions_of(key: &[u8]) -> (Bound<VersionKey>, Bound<VersionKey>) {
+    (
+        Bound::Included((key.to_vec(), Reverse(u64::MAX))),
+        Bound::Included((key.to_vec(), Reverse(0))),
+    )
+}
+
+fn to_version(entry: &Entry<VersionKey, Option<Vec<u8>>>) -> Version {
+    let (key, Reverse(seq)) = entry.key();
+    Version {
+        key: key.clone(),
+        value: entry.value().clone(),
+        seq: *seq,
+        expires_at: None,
+    }
+}
+
+fn entry_size(entry: &Entry<VersionKey, Option<Vec<u8>>>) -> usize {
+    ENTRY_OVERHEAD + entry.key().0.len() + value_size(entry.value())
+}
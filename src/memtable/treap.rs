@@ -0,0 +1,1154 @@
+// A memtable implemented as a binary treap using random priority to ensure balance. Inserts rotate
+// nodes and rewrite their parent pointers, so TreapRep puts the whole tree behind a single lock
+
+use rand::prelude::*;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use super::node::{Link, Node, NodeMethods};
+use super::{MemtableIterator, MemtableRep, Version};
+
+#[derive(Debug)]
+pub struct Treap {
+    root: Link,
+    size: u32,
+    // approximate memory used by the keys, values and nodes, in bytes
+    approximate_size: usize,
+    max_seq: u64,
+}
+
+impl Treap {
+    pub fn new() -> Self {
+        Treap {
+            root: None,
+            size: 0,
+            approximate_size: 0,
+            max_seq: 0,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        return self.size;
+    }
+
+    // approximate number of bytes of memory used by the memtable
+    pub fn approximate_size(&self) -> usize {
+        self.approximate_size
+    }
+
+    // the highest sequence number of any write in the memtable
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq
+    }
+
+    pub fn search(&self, key: &[u8]) -> (Option<Vec<u8>>, bool) {
+        if matches!(self.root, None) {
+            return (None, false);
+        }
+
+        let node = self.root.as_ref().unwrap();
+        let (value, found) = node.search(&key.to_vec());
+        if value.is_some() {
+            return (Some(value.unwrap()), true);
+        }
+        return (None, found);
+    }
+
+    // search for the newest version of the key written at or before seq. Like search, it returns
+    // the value and whether a version of the key was found
+    pub fn search_at(&self, key: &[u8], seq: u64) -> (Option<Vec<u8>>, bool) {
+        let mut link = self.root.clone();
+        while let Some(node) = link {
+            let node = node.read().unwrap();
+            if *node.key == *key {
+                return node.version_at(seq);
+            }
+            link = if *key < *node.key {
+                node.left.clone()
+            } else {
+                node.right.clone()
+            };
+        }
+        (None, false)
+    }
+
+    // insert the value for the key. If the key is already in the memtable, the value is only
+    // replaced if seq is newer than the sequence number of the existing value
+    pub fn insert(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, seq: u64) {
+        self.insert_retaining(key, value, seq, &[]);
+    }
+
+    // insert the value for the key, keeping the older versions of the key that are still visible
+    // to any of the snapshots. snapshots are the sequence numbers of the live snapshots
+    pub fn insert_retaining(
+        &mut self,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        seq: u64,
+        snapshots: &[u64],
+    ) {
+        let mut rng = rand::thread_rng();
+        let priority: f64 = rng.gen();
+        self.insert_with_priority(priority, key, value, seq, snapshots);
+    }
+
+    pub fn insert_with_priority(
+        &mut self,
+        priority: f64,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        seq: u64,
+        snapshots: &[u64],
+    ) {
+        self.max_seq = self.max_seq.max(seq);
+
+        // the tree is empty - new node is the root
+        if matches!(self.root, None) {
+            let new_node = Arc::new(RwLock::new(Node {
+                key,
+                value,
+                priority,
+                seq,
+                history: vec![],
+                left: None,
+                right: None,
+                parent: None,
+            }));
+            self.size += 1;
+            self.approximate_size += new_node.read().unwrap().approximate_size();
+            self.root = Some(new_node);
+            return;
+        }
+
+        // find the parent of the node we're going to insert
+        let mut node_link: Link = Some(self.root.as_ref().unwrap().clone());
+        let mut parent_link: Link = None;
+        let mut replace = false;
+        while !matches!(node_link, None) {
+            let node = node_link.as_ref().unwrap().clone();
+            parent_link = Some(node.clone());
+
+            if key == node.read().unwrap().key {
+                replace = true;
+                break;
+            } else if key > node.read().unwrap().key {
+                node_link = node.get_right();
+            } else {
+                node_link = node.get_left();
+            }
+        }
+
+        if replace {
+            let parent = parent_link.unwrap();
+            let mut node = parent.write().unwrap();
+            // older versions may be dropped, so the node can shrink as well as grow
+            self.approximate_size -= node.approximate_size();
+            node.add_version(value, seq, snapshots);
+            self.approximate_size += node.approximate_size();
+            return;
+        }
+
+        let new_node = Arc::new(RwLock::new(Node {
+            key,
+            value,
+            priority,
+            seq,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        self.size += 1;
+        self.approximate_size += new_node.read().unwrap().approximate_size();
+        let parent = parent_link.as_ref().unwrap().clone();
+        if parent.read().unwrap().key <= new_node.read().unwrap().key {
+            parent.set_right(Some(new_node.clone()))
+        } else {
+            parent.set_left(Some(new_node.clone()));
+        }
+        new_node.set_parent(Some(parent.clone()));
+
+        while new_node.is_heap_invariant() {
+            let parent = new_node.get_parent().unwrap();
+            if parent.is_left_child(Some(new_node.clone())) {
+                self.rotate_right(&mut new_node.clone());
+            } else {
+                self.rotate_left(&mut new_node.clone());
+            }
+        }
+    }
+
+    fn rotate_left(&mut self, x: &mut Arc<RwLock<Node>>) {
+        if matches!(x.get_parent(), None) {
+            panic!("cannot rorate root of tree");
+        }
+
+        let y = x.get_parent().unwrap();
+        if y.is_left_child(Some(x.clone())) {
+            panic!("cannot rotate_left on a left child");
+        }
+
+        if matches!(y.get_parent(), None) {
+            x.set_parent(None);
+            self.root = Some(x.clone());
+        } else {
+            let p = y.get_parent().unwrap();
+            if p.is_left_child(Some(y.clone())) {
+                p.set_left(Some(x.clone()));
+            } else {
+                p.set_right(Some(x.clone()));
+            }
+            x.set_parent(Some(p.clone()));
+        }
+
+        y.set_right(x.get_left());
+        if !matches!(x.get_left(), None) {
+            let x_left = x.get_left().unwrap();
+            x_left.set_parent(Some(y.clone()));
+        }
+
+        x.set_left(Some(y.clone()));
+        y.set_parent(Some(x.clone()));
+    }
+
+    fn rotate_right(&mut self, x: &mut Arc<RwLock<Node>>) {
+        if matches!(x.get_parent(), None) {
+            panic!("cannot rotate the root of the tree");
+        }
+
+        let y = x.get_parent().unwrap();
+
+        if y.is_right_child(Some(x.clone())) {
+            panic!("cannot rotate_right on a right child");
+        }
+
+        if matches!(y.get_parent(), None) {
+            x.set_parent(None);
+            self.root = Some(x.clone());
+        } else {
+            let p = y.get_parent().unwrap();
+            if p.is_left_child(Some(y.clone())) {
+                p.set_left(Some(x.clone()));
+            } else {
+                p.set_right(Some(x.clone()));
+            }
+            x.set_parent(Some(p.clone()));
+        }
+
+        y.set_left(x.get_right());
+        if !matches!(x.get_right(), None) {
+            let x_right = x.get_right().unwrap();
+            x_right.set_parent(Some(y.clone()));
+        }
+
+        x.set_right(Some(y.clone()));
+        y.set_parent(Some(x.clone()));
+    }
+}
+
+#[cfg(test)]
+mod treap_tests {
+    use super::*;
+
+    #[test]
+    fn it_returns_false_if_empty() {
+        let memtable = Treap::new();
+        let (val, found) = memtable.search(&"albert".as_bytes());
+        assert_eq!(0, memtable.size());
+        assert_eq!(val, None);
+        assert_eq!(found, false);
+    }
+
+    #[test]
+    fn it_can_find_and_delete_the_root_value() {
+        let mut memtable = Treap::new();
+        assert_eq!(0, memtable.size());
+        memtable.insert(
+            String::from("guy").into_bytes(),
+            Some(String::from("tim").into_bytes()),
+            1,
+        );
+        assert_eq!(1, memtable.size());
+        let (val_o, found) = memtable.search(&"guy".as_bytes());
+        assert_eq!(val_o.is_some(), true);
+        let val = val_o.unwrap();
+        assert_eq!(val, String::from("tim").into_bytes());
+        assert_eq!(found, true);
+
+        memtable.insert(String::from("guy").into_bytes(), None, 2);
+        assert_eq!(1, memtable.size());
+        let (val_o, found) = memtable.search(&"guy".as_bytes());
+        assert_eq!(val_o, None);
+        assert_eq!(found, true);
+    }
+
+    #[test]
+    fn it_can_put_many_children_in_itself() {
+        let mut memtable = Treap::new();
+        assert_eq!(0, memtable.size());
+        memtable.insert(
+            String::from("a").into_bytes(),
+            Some(String::from("1").into_bytes()),
+            3,
+        );
+        assert_eq!(1, memtable.size());
+
+        memtable.insert(
+            String::from("b").into_bytes(),
+            Some(String::from("2").into_bytes()),
+            4,
+        );
+        assert_eq!(2, memtable.size());
+
+        memtable.insert(
+            String::from("c").into_bytes(),
+            Some(String::from("3").into_bytes()),
+            5,
+        );
+        assert_eq!(3, memtable.size());
+
+        memtable.insert(
+            String::from("d").into_bytes(),
+            Some(String::from("4").into_bytes()),
+            6,
+        );
+        assert_eq!(4, memtable.size());
+
+        let (val_o, found) = memtable.search(&"a".as_bytes());
+        assert_eq!(val_o.is_some(), true);
+        assert_eq!(val_o.unwrap(), String::from("1").as_bytes());
+        assert_eq!(found, true);
+
+        let (val_o, found) = memtable.search(&"b".as_bytes());
+        assert_eq!(val_o.is_some(), true);
+        assert_eq!(val_o.unwrap(), String::from("2").as_bytes());
+        assert_eq!(found, true);
+
+        let (val_o, found) = memtable.search(&"c".as_bytes());
+        assert_eq!(val_o.is_some(), true);
+        assert_eq!(val_o.unwrap(), String::from("3").as_bytes());
+        assert_eq!(found, true);
+
+        let (val_o, found) = memtable.search(&"d".as_bytes());
+        assert_eq!(val_o.is_some(), true);
+        assert_eq!(val_o.unwrap(), String::from("4").as_bytes());
+        assert_eq!(found, true);
+
+        // ensure we can update all the values
+        memtable.insert(
+            String::from("a").into_bytes(),
+            Some(String::from("5").into_bytes()),
+            7,
+        );
+        assert_eq!(4, memtable.size());
+
+        memtable.insert(
+            String::from("b").into_bytes(),
+            Some(String::from("6").into_bytes()),
+            8,
+        );
+        assert_eq!(4, memtable.size());
+
+        memtable.insert(
+            String::from("c").into_bytes(),
+            Some(String::from("7").into_bytes()),
+            9,
+        );
+        assert_eq!(4, memtable.size());
+
+        memtable.insert(
+            String::from("d").into_bytes(),
+            Some(String::from("8").into_bytes()),
+            10,
+        );
+        assert_eq!(4, memtable.size());
+
+        let (val_o, found) = memtable.search(&"a".as_bytes());
+        assert_eq!(val_o.is_some(), true);
+        assert_eq!(val_o.unwrap(), String::from("5").as_bytes());
+        assert_eq!(found, true);
+
+        let (val_o, found) = memtable.search(&"b".as_bytes());
+        assert_eq!(val_o.is_some(), true);
+        assert_eq!(val_o.unwrap(), String::from("6").as_bytes());
+        assert_eq!(found, true);
+
+        let (val_o, found) = memtable.search(&"c".as_bytes());
+        assert_eq!(val_o.is_some(), true);
+        assert_eq!(val_o.unwrap(), String::from("7").as_bytes());
+        assert_eq!(found, true);
+
+        let (val_o, found) = memtable.search(&"d".as_bytes());
+        assert_eq!(val_o.is_some(), true);
+        assert_eq!(val_o.unwrap(), String::from("8").as_bytes());
+        assert_eq!(found, true);
+
+        // ensure can delete all the values
+        memtable.insert(String::from("a").into_bytes(), None, 11);
+        assert_eq!(4, memtable.size());
+        let (val_o, found) = memtable.search(&"a".as_bytes());
+        assert_eq!(val_o.is_none(), true);
+        assert_eq!(found, true);
+
+        memtable.insert(String::from("b").into_bytes(), None, 12);
+        assert_eq!(4, memtable.size());
+        assert_eq!(4, memtable.size());
+        let (val_o, found) = memtable.search(&"a".as_bytes());
+        assert_eq!(val_o.is_none(), true);
+        assert_eq!(found, true);
+
+        memtable.insert(String::from("c").into_bytes(), None, 13);
+        assert_eq!(4, memtable.size());
+        assert_eq!(4, memtable.size());
+        let (val_o, found) = memtable.search(&"a".as_bytes());
+        assert_eq!(val_o.is_none(), true);
+        assert_eq!(found, true);
+
+        memtable.insert(String::from("d").into_bytes(), None, 14);
+        assert_eq!(4, memtable.size());
+        assert_eq!(4, memtable.size());
+        let (val_o, found) = memtable.search(&"a".as_bytes());
+        assert_eq!(val_o.is_none(), true);
+        assert_eq!(found, true);
+    }
+}
+
+#[cfg(test)]
+mod insert_tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_some_rotations() {
+        let mut m = Treap::new();
+        let p = Arc::new(RwLock::new(Node {
+            key: String::from("50").into_bytes(),
+            value: Some(String::from("50").into_bytes()),
+            priority: 50f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let y = Arc::new(RwLock::new(Node {
+            key: String::from("40").into_bytes(),
+            value: Some(String::from("40").into_bytes()),
+            priority: 40f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("30").into_bytes(),
+            value: Some(String::from("30").into_bytes()),
+            priority: 30f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        m.root = Some(p.clone());
+
+        p.set_left(Some(y.clone()));
+        y.set_parent(Some(p.clone()));
+
+        y.set_left(Some(x.clone()));
+        x.set_parent(Some(y.clone()));
+
+        m.insert_with_priority(
+            45f64,
+            String::from("35").into_bytes(),
+            Some(String::from("45").into_bytes()),
+            15,
+            &[],
+        );
+
+        assert_eq!(true, Arc::ptr_eq(&p, m.root.as_ref().unwrap()));
+
+        let new_node = p.get_left().unwrap().clone();
+        assert_eq!(
+            String::from("35").into_bytes(),
+            new_node.read().unwrap().key
+        );
+
+        assert_eq!(true, new_node.is_left_child(Some(x.clone())));
+        assert_eq!(true, x.is_parent(Some(new_node.clone())));
+
+        assert_eq!(true, new_node.is_right_child(Some(y.clone())));
+        assert_eq!(true, y.is_parent(Some(new_node.clone())));
+    }
+}
+
+#[cfg(test)]
+mod rotate_left_tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_left_full_rotation() {
+        let mut m = Treap::new();
+        let p = Arc::new(RwLock::new(Node {
+            key: String::from("0").into_bytes(),
+            value: Some(String::from("0").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let y = Arc::new(RwLock::new(Node {
+            key: String::from("1").into_bytes(),
+            value: Some(String::from("1").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("2").into_bytes(),
+            value: Some(String::from("2").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x_left = Arc::new(RwLock::new(Node {
+            key: String::from("3").into_bytes(),
+            value: Some(String::from("3").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        m.root = Some(p.clone());
+        p.set_right(Some(y.clone()));
+        y.set_parent(Some(p.clone()));
+
+        y.set_right(Some(x.clone()));
+        x.set_parent(Some(y.clone()));
+
+        x.set_left(Some(x_left.clone()));
+        x_left.set_parent(Some(y.clone()));
+
+        m.rotate_left(&mut x.clone());
+
+        // check x has now replaced y as the right child of P
+        assert_eq!(false, matches!(p.get_right(), None));
+        assert_eq!(true, p.is_right_child(Some(x.clone())));
+        assert_eq!(false, matches!(x.get_parent(), None));
+        assert_eq!(true, x.is_parent(Some(p.clone())));
+
+        // check Y is now the left child of X
+        assert_eq!(false, matches!(x.get_left(), None));
+        assert_eq!(true, x.is_left_child(Some(y.clone())));
+        assert_eq!(false, matches!(y.get_parent(), None));
+        assert_eq!(true, y.is_parent(Some(x)));
+
+        // check X's left is now the right child of Y
+        assert_eq!(false, matches!(y.get_right(), None));
+        assert_eq!(true, y.is_right_child(Some(x_left.clone())));
+        assert_eq!(false, matches!(x_left.get_parent(), None));
+        assert_eq!(true, x_left.is_parent(Some(y)));
+    }
+
+    #[test]
+    fn test_rotate_left_full_y_is_left_child_of_p() {
+        let mut m = Treap::new();
+        let p = Arc::new(RwLock::new(Node {
+            key: String::from("0").into_bytes(),
+            value: Some(String::from("0").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let y = Arc::new(RwLock::new(Node {
+            key: String::from("1").into_bytes(),
+            value: Some(String::from("1").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("2").into_bytes(),
+            value: Some(String::from("2").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x_left = Arc::new(RwLock::new(Node {
+            key: String::from("3").into_bytes(),
+            value: Some(String::from("3").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        m.root = Some(p.clone());
+        p.set_left(Some(y.clone()));
+        y.set_parent(Some(p.clone()));
+
+        y.set_right(Some(x.clone()));
+        x.set_parent(Some(y.clone()));
+
+        x.set_left(Some(x_left.clone()));
+        x_left.set_parent(Some(y.clone()));
+
+        m.rotate_left(&mut x.clone());
+
+        // check x has now replaced y as the right child of P
+        assert_eq!(false, matches!(p.get_left(), None));
+        assert_eq!(true, p.is_left_child(Some(x.clone())));
+        assert_eq!(false, matches!(x.get_parent(), None));
+        assert_eq!(true, x.is_parent(Some(p.clone())));
+
+        // check Y is now the left child of X
+        assert_eq!(false, matches!(x.get_left(), None));
+        assert_eq!(true, x.is_left_child(Some(y.clone())));
+        assert_eq!(false, matches!(y.get_parent(), None));
+        assert_eq!(true, y.is_parent(Some(x)));
+
+        // check X's left is now the right child of Y
+        assert_eq!(false, matches!(y.get_right(), None));
+        assert_eq!(true, y.is_right_child(Some(x_left.clone())));
+        assert_eq!(false, matches!(x_left.get_parent(), None));
+        assert_eq!(true, x_left.is_parent(Some(y)));
+    }
+
+    #[test]
+    fn test_rotate_left_parent_is_root() {
+        let mut m = Treap::new();
+        let p = Arc::new(RwLock::new(Node {
+            key: String::from("0").into_bytes(),
+            value: Some(String::from("0").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("2").into_bytes(),
+            value: Some(String::from("2").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        m.root = Some(p.clone());
+        p.set_right(Some(x.clone()));
+        x.set_parent(Some(p.clone()));
+
+        m.rotate_left(&mut x.clone());
+
+        assert_eq!(true, Arc::ptr_eq(&x, m.root.as_ref().unwrap()));
+        assert_eq!(true, matches!(x.get_parent(), None));
+
+        assert_eq!(true, x.is_left_child(Some(p.clone())));
+        assert_eq!(true, p.is_parent(Some(x.clone())));
+
+        assert_eq!(true, matches!(p.get_right(), None));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rotate_left_panics_if_x_is_root() {
+        let mut m = Treap::new();
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("0").into_bytes(),
+            value: Some(String::from("0").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        m.root = Some(x.clone());
+        m.rotate_left(&mut x.clone());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rotate_left_panics_if_x_is_left_child() {
+        let mut m = Treap::new();
+        let p = Arc::new(RwLock::new(Node {
+            key: String::from("0").into_bytes(),
+            value: Some(String::from("0").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("2").into_bytes(),
+            value: Some(String::from("2").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        m.root = Some(p.clone());
+        p.set_left(Some(x.clone()));
+        x.set_parent(Some(p.clone()));
+        m.rotate_left(&mut x.clone());
+    }
+}
+
+#[cfg(test)]
+mod rotate_right_tests {
+    use super::*;
+
+    #[test]
+    #[should_panic]
+    fn panics_if_x_is_root() {
+        let mut m = Treap::new();
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("2").into_bytes(),
+            value: Some(String::from("2").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        m.root = Some(x.clone());
+        m.rotate_right(&mut x.clone());
+    }
+
+    #[test]
+    #[should_panic]
+    fn panics_if_x_is_right_child() {
+        let mut m = Treap::new();
+        let p = Arc::new(RwLock::new(Node {
+            key: String::from("0").into_bytes(),
+            value: Some(String::from("0").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("2").into_bytes(),
+            value: Some(String::from("2").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        m.root = Some(p.clone());
+        p.set_right(Some(x.clone()));
+        x.set_parent(Some(p.clone()));
+
+        m.rotate_right(&mut x.clone());
+    }
+
+    #[test]
+    fn handles_case_where_x_parent_is_root() {
+        let mut m = Treap::new();
+        let p = Arc::new(RwLock::new(Node {
+            key: String::from("0").into_bytes(),
+            value: Some(String::from("0").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("2").into_bytes(),
+            value: Some(String::from("2").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        m.root = Some(p.clone());
+        p.set_left(Some(x.clone()));
+        x.set_parent(Some(p.clone()));
+
+        m.rotate_right(&mut x.clone());
+
+        assert_eq!(true, Arc::ptr_eq(&x, m.root.as_ref().unwrap()));
+        assert_eq!(true, matches!(x.get_parent(), None));
+
+        assert_eq!(true, x.is_right_child(Some(p.clone())));
+        assert_eq!(true, p.is_parent(Some(x.clone())));
+
+        assert_eq!(true, matches!(p.get_left(), None));
+    }
+
+    #[test]
+    fn full_rotate_y_is_p_right_child() {
+        let mut m = Treap::new();
+        let p = Arc::new(RwLock::new(Node {
+            key: String::from("0").into_bytes(),
+            value: Some(String::from("0").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let y = Arc::new(RwLock::new(Node {
+            key: String::from("1").into_bytes(),
+            value: Some(String::from("1").into_bytes()),
+            priority: 1f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("2").into_bytes(),
+            value: Some(String::from("2").into_bytes()),
+            priority: 2f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x_right = Arc::new(RwLock::new(Node {
+            key: String::from("3").into_bytes(),
+            value: Some(String::from("3").into_bytes()),
+            priority: 3f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        m.root = Some(p.clone());
+
+        p.set_right(Some(y.clone()));
+        y.set_parent(Some(p.clone()));
+
+        y.set_left(Some(x.clone()));
+        x.set_parent(Some(y.clone()));
+
+        x.set_right(Some(x_right.clone()));
+        x_right.set_parent(Some(x.clone()));
+
+        m.rotate_right(&mut x.clone());
+
+        assert_eq!(true, p.is_right_child(Some(x.clone())));
+        assert_eq!(true, x.is_parent(Some(p.clone())));
+
+        assert_eq!(true, x.is_right_child(Some(y.clone())));
+        assert_eq!(true, y.is_parent(Some(x.clone())));
+
+        assert_eq!(true, y.is_left_child(Some(x_right.clone())));
+        assert_eq!(true, x_right.is_parent(Some(y.clone())));
+    }
+
+    #[test]
+    fn full_rotate_y_is_p_left_child() {
+        let mut m = Treap::new();
+        let p = Arc::new(RwLock::new(Node {
+            key: String::from("0").into_bytes(),
+            value: Some(String::from("0").into_bytes()),
+            priority: 0f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let y = Arc::new(RwLock::new(Node {
+            key: String::from("1").into_bytes(),
+            value: Some(String::from("1").into_bytes()),
+            priority: 1f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x = Arc::new(RwLock::new(Node {
+            key: String::from("2").into_bytes(),
+            value: Some(String::from("2").into_bytes()),
+            priority: 2f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+        let x_right = Arc::new(RwLock::new(Node {
+            key: String::from("3").into_bytes(),
+            value: Some(String::from("3").into_bytes()),
+            priority: 3f64,
+            seq: 0,
+            history: vec![],
+            left: None,
+            right: None,
+            parent: None,
+        }));
+
+        m.root = Some(p.clone());
+
+        p.set_left(Some(y.clone()));
+        y.set_parent(Some(p.clone()));
+
+        y.set_left(Some(x.clone()));
+        x.set_parent(Some(y.clone()));
+
+        x.set_right(Some(x_right.clone()));
+        x_right.set_parent(Some(x.clone()));
+
+        m.rotate_right(&mut x.clone());
+
+        assert_eq!(true, p.is_left_child(Some(x.clone())));
+        assert_eq!(true, x.is_parent(Some(p.clone())));
+
+        assert_eq!(true, x.is_right_child(Some(y.clone())));
+        assert_eq!(true, y.is_parent(Some(x.clone())));
+
+        assert_eq!(true, y.is_left_child(Some(x_right.clone())));
+        assert_eq!(true, x_right.is_parent(Some(y.clone())));
+    }
+}
+
+#[derive(Debug)]
+pub struct TreapIterator {
+    unvisited: Vec<Link>,
+    // older versions of the last key returned, which are returned before moving to the next key
+    pending: Vec<Version>,
+    reverse: bool,
+}
+
+impl TreapIterator {
+    fn push_left_edge(&mut self, link: &Link) {
+        if matches!(link, None) {
+            return;
+        }
+        let mut link = Some(link.as_ref().unwrap().clone());
+        while !matches!(link, None) {
+            let node = link.as_ref().unwrap();
+            self.unvisited.push(Some(node.clone()));
+            link = node.get_left();
+        }
+    }
+
+    fn push_right_edge(&mut self, link: &Link) {
+        let mut link = link.clone();
+        while let Some(node) = link {
+            self.unvisited.push(Some(node.clone()));
+            link = node.get_right();
+        }
+    }
+
+    // position the iterator so the first key returned is the smallest key >= start
+    fn seek(&mut self, root: &Link, start: &[u8]) {
+        let mut link = root.clone();
+        while let Some(node) = link {
+            if *node.read().unwrap().key >= *start {
+                self.unvisited.push(Some(node.clone()));
+                link = node.get_left();
+            } else {
+                link = node.get_right();
+            }
+        }
+    }
+
+    // position the iterator so the first key returned is the largest key < end
+    fn seek_reverse(&mut self, root: &Link, end: &[u8]) {
+        let mut link = root.clone();
+        while let Some(node) = link {
+            if *node.read().unwrap().key < *end {
+                self.unvisited.push(Some(node.clone()));
+                link = node.get_right();
+            } else {
+                link = node.get_left();
+            }
+        }
+    }
+}
+
+impl Iterator for TreapIterator {
+    type Item = Version;
+
+    // every version of a key is returned, newest first
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(version) = self.pending.pop() {
+            return Some(version);
+        }
+
+        let link = self.unvisited.pop()?;
+
+        let node = link.as_ref().unwrap();
+        if self.reverse {
+            self.push_right_edge(&node.get_left());
+        } else {
+            self.push_left_edge(&node.get_right());
+        }
+        let node = node.read().unwrap();
+        self.pending = node
+            .history
+            .iter()
+            .rev()
+            .map(|(seq, value)| Version {
+                key: node.key.clone(),
+                value: value.clone(),
+                seq: *seq,
+                expires_at: None,
+            })
+            .collect();
+        Some(Version {
+            key: node.key.clone(),
+            value: node.value.clone(),
+            seq: node.seq,
+            expires_at: None,
+        })
+    }
+}
+
+impl Treap {
+    pub fn iter(&self) -> TreapIterator {
+        let mut iter = TreapIterator {
+            unvisited: Vec::new(),
+            pending: Vec::new(),
+            reverse: false,
+        };
+        iter.push_left_edge(&self.root);
+        iter
+    }
+
+    // iterate the keys in ascending order starting at the first key >= start. If start is None
+    // it will iterate from the smallest key. Callers are responsible for stopping at the end bound
+    pub fn iter_from(&self, start: Option<&[u8]>) -> TreapIterator {
+        let mut iter = TreapIterator {
+            unvisited: Vec::new(),
+            pending: Vec::new(),
+            reverse: false,
+        };
+        match start {
+            Some(start) => iter.seek(&self.root, start),
+            None => iter.push_left_edge(&self.root),
+        }
+        iter
+    }
+
+    // iterate the keys in descending order starting at the last key < end. If end is None it
+    // will iterate from the largest key. Callers are responsible for stopping at the start bound
+    pub fn iter_rev_from(&self, end: Option<&[u8]>) -> TreapIterator {
+        let mut iter = TreapIterator {
+            unvisited: Vec::new(),
+            pending: Vec::new(),
+            reverse: true,
+        };
+        match end {
+            Some(end) => iter.seek_reverse(&self.root, end),
+            None => iter.push_right_edge(&self.root),
+        }
+        iter
+    }
+}
+
+// a treap that can be shared between threads. Writers take the lock exclusively, so they block
+// readers for as long as an insert takes
+#[derive(Debug)]
+pub struct TreapRep {
+    tree: RwLock<Treap>,
+}
+
+impl TreapRep {
+    pub fn new() -> Self {
+        TreapRep {
+            tree: RwLock::new(Treap::new()),
+        }
+    }
+}
+
+impl Default for TreapRep {
+    fn default() -> Self {
+        TreapRep::new()
+    }
+}
+
+impl MemtableRep for TreapRep {
+    fn insert(&self, key: Vec<u8>, value: Option<Vec<u8>>, seq: u64, snapshots: &[u64]) {
+        self.tree
+            .write()
+            .unwrap()
+            .insert_retaining(key, value, seq, snapshots);
+    }
+
+    fn search_at(&self, key: &[u8], seq: u64) -> (Option<Vec<u8>>, bool) {
+        self.tree.read().unwrap().search_at(key, seq)
+    }
+
+    // inserts rotate the nodes in place, so following them once the lock is released could skip
+    // keys or return them twice. The versions are copied out while the lock is held instead
+    fn iter_from(&self, start: Option<&[u8]>) -> MemtableIterator {
+        let versions: Vec<Version> = self.tree.read().unwrap().iter_from(start).collect();
+        Box::new(versions.into_iter())
+    }
+
+    fn iter_rev_from(&self, end: Option<&[u8]>) -> MemtableIterator {
+        let versions: Vec<Version> = self.tree.read().unwrap().iter_rev_from(end).collect();
+        Box::new(versions.into_iter())
+    }
+
+    fn size(&self) -> u32 {
+        self.tree.read().unwrap().size()
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.tree.read().unwrap().approximate_size()
+    }
+
+    fn max_seq(&self) -> u64 {
+        self.tree.read().unwrap().max_seq()
+    }
+}
@@ -0,0 +1,387 @@
+// Merges several sorted iterators of key/value pairs into a single sorted stream. When the same
+// key appears in more than one source, the version with the highest sequence number wins. When
+// reading at a snapshot, versions newer than the snapshot are ignored. Versions deleted by a range
+// tombstone or with an expired value are returned as tombstones. Reading a source can fail, so the
+// iterators return a Result for every item and stop at the first error.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use crate::error::Result;
+use crate::memtable;
+use crate::sstable;
+
+// a version with a value of None is a tombstone
+pub type Source = Box<dyn Iterator<Item = Result<memtable::Version>> + Send>;
+
+struct HeapEntry {
+    version: memtable::Version,
+    source: usize,
+    reverse: bool,
+}
+
+impl Ord for HeapEntry {
+    // BinaryHeap is a max-heap, so the entry that should be returned next must compare greatest
+    fn cmp(&self, other: &Self) -> Ordering {
+        let key_order = if self.reverse {
+            self.version.key.cmp(&other.version.key)
+        } else {
+            other.version.key.cmp(&self.version.key)
+        };
+        key_order
+            .then_with(|| self.version.seq.cmp(&other.version.seq))
+            .then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+pub struct MergeIterator {
+    sources: Vec<Source>,
+    heap: BinaryHeap<HeapEntry>,
+    reverse: bool,
+    // versions with a higher sequence number than this are skipped
+    read_seq: u64,
+    range_tombstones: Vec<memtable::RangeTombstone>,
+    // values that expired by this time (millis since the epoch) are deleted
+    now: u64,
+    // sources that haven't been read from yet. They're read on the first call to try_next, so
+    // that creating the iterator can't fail
+    unread: Vec<usize>,
+}
+
+impl MergeIterator {
+    // sources must each be sorted ascending (or descending if reverse is true), with the versions
+    // of each key ordered newest first. If read_seq is set, only versions written at or before it
+    // are returned
+    pub fn new(sources: Vec<Source>, reverse: bool, read_seq: Option<u64>) -> Self {
+        MergeIterator {
+            unread: (0..sources.len()).collect(),
+            sources,
+            heap: BinaryHeap::new(),
+            reverse,
+            read_seq: read_seq.unwrap_or(u64::MAX),
+            range_tombstones: vec![],
+            now: sstable::now_millis(),
+        }
+    }
+
+    // delete the versions covered by the tombstones. Tombstones newer than read_seq are ignored
+    pub fn with_range_tombstones(mut self, tombstones: Vec<memtable::RangeTombstone>) -> Self {
+        self.range_tombstones = tombstones
+            .into_iter()
+            .filter(|tombstone| tombstone.seq <= self.read_seq)
+            .collect();
+        self
+    }
+
+    fn advance(&mut self, source: usize) -> Result<()> {
+        if let Some(version) = self.sources[source].next().transpose()? {
+            self.heap.push(HeapEntry {
+                version,
+                source,
+                reverse: self.reverse,
+            });
+        }
+        Ok(())
+    }
+
+    // returns the newest version of each key, including tombstones
+    pub fn try_next(&mut self) -> Result<Option<memtable::Version>> {
+        while let Some(source) = self.unread.pop() {
+            self.advance(source)?;
+        }
+        loop {
+            let newest = match self.heap.pop() {
+                Some(newest) => newest,
+                None => return Ok(None),
+            };
+            self.advance(newest.source)?;
+            if newest.version.seq > self.read_seq {
+                continue;
+            }
+
+            // skip older versions of the same key
+            while let Some(older) = self.heap.peek() {
+                if older.version.key != newest.version.key {
+                    break;
+                }
+                let source = older.source;
+                self.heap.pop();
+                self.advance(source)?;
+            }
+
+            let mut version = newest.version;
+            let deleted_at =
+                memtable::newest_covering(&self.range_tombstones, &version.key, self.read_seq);
+            if let Some(seq) = deleted_at.filter(|seq| *seq > version.seq) {
+                version.value = None;
+                version.seq = seq;
+            }
+            if version.is_expired(self.now) {
+                version.value = None;
+            }
+            return Ok(Some(version));
+        }
+    }
+}
+
+impl Iterator for MergeIterator {
+    type Item = Result<memtable::Version>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+pub fn memtable_source(iter: memtable::MemtableIterator) -> Source {
+    Box::new(iter.map(Ok))
+}
+
+// a block that can't be read is returned as an error rather than ending the source
+pub fn sstable_source(mut iter: sstable::reader::SstableIterator) -> Source {
+    Box::new(std::iter::from_fn(move || {
+        let entry = match iter.try_next() {
+            Ok(entry) => entry?,
+            Err(err) => return Some(Err(err)),
+        };
+        Some(Ok(memtable::Version {
+            value: if entry.deleted {
+                None
+            } else {
+                Some(entry.value)
+            },
+            key: entry.key,
+            seq: entry.seq,
+            expires_at: entry.expires_at,
+        }))
+    }))
+}
+
+// iterator over the live key/value pairs in a range. start is inclusive and end is exclusive.
+// Tombstones are not returned
+pub struct ScanIterator {
+    merged: MergeIterator,
+    start: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    reverse: bool,
+}
+
+impl ScanIterator {
+    pub fn new(
+        merged: MergeIterator,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        reverse: bool,
+    ) -> Self {
+        ScanIterator {
+            merged,
+            start: start.map(|k| k.to_vec()),
+            end: end.map(|k| k.to_vec()),
+            reverse,
+        }
+    }
+
+    fn past_bound(&self, key: &[u8]) -> bool {
+        if self.reverse {
+            matches!(&self.start, Some(start) if *key < **start)
+        } else {
+            matches!(&self.end, Some(end) if *key >= **end)
+        }
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some(version) = self.merged.try_next()? {
+            if self.past_bound(&version.key) {
+                return Ok(None);
+            }
+            if let Some(value) = version.value {
+                return Ok(Some((version.key, value)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for ScanIterator {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+#[cfg(test)]
+mod merge_tests {
+    use super::*;
+
+    fn source(entries: Vec<(&str, Option<&str>, u64)>) -> Source {
+        let items: Vec<memtable::Version> = entries
+            .into_iter()
+            .map(|(k, v, seq)| memtable::Version {
+                key: k.bytes().collect(),
+                value: v.map(|v| v.bytes().collect()),
+                seq,
+                expires_at: None,
+            })
+            .collect();
+        Box::new(items.into_iter().map(Ok))
+    }
+
+    fn collect(iter: ScanIterator) -> Vec<(String, String)> {
+        iter.map(|record| {
+            let (k, v) = record.unwrap();
+            (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap())
+        })
+        .collect()
+    }
+
+    #[test]
+    fn it_merges_sources_newest_wins() {
+        let newer = source(vec![("b", Some("new"), 4), ("d", None, 5)]);
+        let older = source(vec![
+            ("a", Some("old"), 1),
+            ("b", Some("old"), 2),
+            ("d", Some("old"), 3),
+        ]);
+        let merged = MergeIterator::new(vec![older, newer], false, None);
+
+        let results: Vec<memtable::Version> = merged.collect::<Result<_>>().unwrap();
+        assert_eq!(3, results.len());
+        assert_eq!("a".as_bytes(), &results[0].key[..]);
+        assert_eq!(Some("old".bytes().collect()), results[0].value);
+        assert_eq!("b".as_bytes(), &results[1].key[..]);
+        assert_eq!(Some("new".bytes().collect()), results[1].value);
+        assert_eq!(4, results[1].seq);
+        assert_eq!("d".as_bytes(), &results[2].key[..]);
+        assert_eq!(None, results[2].value);
+    }
+
+    #[test]
+    fn it_hides_tombstones_and_respects_bounds() {
+        let newer = source(vec![("b", None, 4), ("c", Some("new"), 5)]);
+        let older = source(vec![
+            ("a", Some("old"), 1),
+            ("b", Some("old"), 2),
+            ("e", Some("old"), 3),
+        ]);
+        let merged = MergeIterator::new(vec![newer, older], false, None);
+        let scan = ScanIterator::new(merged, Some("a".as_bytes()), Some("e".as_bytes()), false);
+
+        assert_eq!(
+            vec![
+                (String::from("a"), String::from("old")),
+                (String::from("c"), String::from("new"))
+            ],
+            collect(scan)
+        );
+    }
+
+    #[test]
+    fn it_deletes_versions_covered_by_range_tombstones() {
+        let newer = source(vec![("c", Some("new"), 5)]);
+        let older = source(vec![
+            ("a", Some("old"), 1),
+            ("b", Some("old"), 2),
+            ("c", Some("old"), 3),
+            ("d", Some("old"), 3),
+        ]);
+        let tombstones = vec![memtable::RangeTombstone {
+            start: "b".bytes().collect(),
+            end: "d".bytes().collect(),
+            seq: 4,
+        }];
+        let merged = MergeIterator::new(vec![newer, older], false, None)
+            .with_range_tombstones(tombstones.clone());
+        let scan = ScanIterator::new(merged, None, None, false);
+        assert_eq!(
+            vec![
+                (String::from("a"), String::from("old")),
+                (String::from("c"), String::from("new")),
+                (String::from("d"), String::from("old"))
+            ],
+            collect(scan)
+        );
+
+        // the tombstone isn't visible to reads from before it
+        let older = source(vec![("b", Some("old"), 2)]);
+        let merged =
+            MergeIterator::new(vec![older], false, Some(3)).with_range_tombstones(tombstones);
+        assert_eq!(
+            vec![(String::from("b"), String::from("old"))],
+            collect(ScanIterator::new(merged, None, None, false))
+        );
+    }
+
+    #[test]
+    fn it_merges_in_reverse() {
+        let newer = source(vec![("c", Some("new"), 5), ("b", None, 4)]);
+        let older = source(vec![
+            ("c", Some("old"), 3),
+            ("b", Some("old"), 2),
+            ("a", Some("old"), 1),
+        ]);
+        let merged = MergeIterator::new(vec![newer, older], true, None);
+        let scan = ScanIterator::new(merged, Some("b".as_bytes()), None, true);
+
+        assert_eq!(
+            vec![(String::from("c"), String::from("new"))],
+            collect(scan)
+        );
+    }
+
+    #[test]
+    fn it_ignores_versions_newer_than_the_read_seq() {
+        let newer = source(vec![("a", None, 4), ("b", Some("new"), 5)]);
+        let older = source(vec![
+            ("a", Some("old"), 1),
+            ("b", Some("mid"), 3),
+            ("b", Some("old"), 2),
+        ]);
+        let merged = MergeIterator::new(vec![newer, older], false, Some(3));
+        let scan = ScanIterator::new(merged, None, None, false);
+
+        assert_eq!(
+            vec![
+                (String::from("a"), String::from("old")),
+                (String::from("b"), String::from("mid"))
+            ],
+            collect(scan)
+        );
+    }
+
+    #[test]
+    fn it_stops_at_an_error_from_a_source() {
+        let failing: Source = Box::new(
+            vec![
+                Ok(memtable::Version {
+                    key: "a".bytes().collect(),
+                    value: Some("old".bytes().collect()),
+                    seq: 1,
+                    expires_at: None,
+                }),
+                Err(crate::error::Error::Corruption(String::from("bad block"))),
+            ]
+            .into_iter(),
+        );
+        let merged = MergeIterator::new(
+            vec![source(vec![("b", Some("new"), 2)]), failing],
+            false,
+            None,
+        );
+        let mut scan = ScanIterator::new(merged, None, None, false);
+        assert_eq!(true, scan.try_next().is_err());
+    }
+}
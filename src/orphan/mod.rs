@@ -0,0 +1,267 @@
+// Finds files in the data and WAL directories that don't belong to any live sstable or WAL. These
+// are left behind when the database crashes part way through a flush or a compaction, or when
+// deleting the files of a compacted table fails. Depending on the config, orphans are moved into a
+// quarantine directory next to them or deleted.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path;
+
+use crate::config;
+use crate::sstable;
+use crate::wal;
+
+const QUARANTINE_DIR: &str = "quarantine";
+
+const DATA_PREFIX: &str = "sstable-data-";
+const META_PREFIX: &str = "sstable-meta-";
+
+// the ids of everything that is live. Files are checked against these
+#[derive(Debug, Default)]
+pub struct LiveFiles {
+    // sstables that are in the MANIFEST or still being read from. These must have both files
+    pub sstable_ids: BTreeSet<String>,
+    // sstables that are being written. These may not have all their files yet
+    pub pending_sstable_ids: BTreeSet<String>,
+    // memtables whose WAL is still needed
+    pub wal_ids: BTreeSet<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    DataWithoutMeta,
+    MetaWithoutData,
+    // both files of the table exist, but it isn't live
+    NotLive,
+    // a table that was never finished being written
+    Temporary,
+    // the WAL of a memtable that isn't being written to or flushed anymore
+    StaleWal,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Orphan {
+    pub path: String,
+    pub reason: Reason,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Report {
+    // what was done with the orphans
+    pub action: config::OrphanFileAction,
+    pub orphans: Vec<Orphan>,
+    // ids of live sstables that are missing one or both of their files. Nothing can be done
+    // about these, so they're only reported
+    pub missing: Vec<String>,
+}
+
+// check every file in the data and WAL directories against the live files, and quarantine or
+// delete the ones that aren't live. The caller must make sure no tables or WALs are added or
+// removed while this runs
+pub fn collect(config: &config::Config, live: &LiveFiles) -> io::Result<Report> {
+    let mut report = Report {
+        action: config.orphan_file_action.clone(),
+        ..Default::default()
+    };
+
+    // the data file and meta file found for each sstable
+    let mut sstables: BTreeMap<String, (Option<path::PathBuf>, Option<path::PathBuf>)> =
+        BTreeMap::new();
+    for file in fs::read_dir(&config.data_dir)? {
+        let path = file?.path();
+        let filename = match path.file_name().and_then(|f| f.to_str()) {
+            Some(filename) => filename.to_owned(),
+            None => continue,
+        };
+
+        if let Some(name) = filename.strip_suffix(sstable::TMP_SUFFIX) {
+            let id = match name
+                .strip_prefix(DATA_PREFIX)
+                .or_else(|| name.strip_prefix(META_PREFIX))
+            {
+                Some(id) => id,
+                None => continue,
+            };
+            if !live.pending_sstable_ids.contains(id) {
+                report.orphans.push(orphan(path, Reason::Temporary));
+            }
+        } else if let Some(id) = filename.strip_prefix(DATA_PREFIX) {
+            sstables.entry(id.to_owned()).or_default().0 = Some(path);
+        } else if let Some(id) = filename.strip_prefix(META_PREFIX) {
+            sstables.entry(id.to_owned()).or_default().1 = Some(path);
+        }
+    }
+
+    for id in &live.sstable_ids {
+        match sstables.remove(id) {
+            Some((Some(_), Some(_))) => {}
+            _ => report.missing.push(id.clone()),
+        }
+    }
+    for (id, files) in sstables {
+        if live.pending_sstable_ids.contains(&id) {
+            continue;
+        }
+        match files {
+            (Some(data), Some(meta)) => {
+                report.orphans.push(orphan(data, Reason::NotLive));
+                report.orphans.push(orphan(meta, Reason::NotLive));
+            }
+            (Some(data), None) => report.orphans.push(orphan(data, Reason::DataWithoutMeta)),
+            (None, Some(meta)) => report.orphans.push(orphan(meta, Reason::MetaWithoutData)),
+            (None, None) => {}
+        }
+    }
+
+    for (_, id, path) in wal::list_wals(config)? {
+        if !live.wal_ids.contains(&id) {
+            report.orphans.push(orphan(path.into(), Reason::StaleWal));
+        }
+    }
+
+    for id in &report.missing {
+        log::error!("live sstable {} is missing files", id);
+    }
+    for orphan in &report.orphans {
+        let path = path::Path::new(&orphan.path);
+        match config.orphan_file_action {
+            config::OrphanFileAction::Quarantine => {
+                let dir = path.with_file_name(QUARANTINE_DIR);
+                fs::create_dir_all(&dir)?;
+                fs::rename(path, dir.join(path.file_name().unwrap()))?;
+                log::warn!("quarantined orphan {:?} ({:?})", path, orphan.reason);
+            }
+            config::OrphanFileAction::Delete => {
+                fs::remove_file(path)?;
+                log::warn!("deleted orphan {:?} ({:?})", path, orphan.reason);
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn orphan(path: path::PathBuf, reason: Reason) -> Orphan {
+    Orphan {
+        path: path.to_string_lossy().into_owned(),
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod orphan_tests {
+    use super::*;
+
+    fn test_config(test_name: &str) -> config::Config {
+        let data_dir = format!("/tmp/orphan_tests/{}", test_name);
+        fs::remove_dir_all(&data_dir).ok();
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = data_dir;
+        config
+    }
+
+    fn touch(config: &config::Config, filename: &str) {
+        fs::write(format!("{}/{}", config.data_dir, filename), "x").unwrap();
+    }
+
+    fn exists(config: &config::Config, filename: &str) -> bool {
+        path::Path::new(&config.data_dir).join(filename).exists()
+    }
+
+    fn ids(ids: &[&str]) -> BTreeSet<String> {
+        ids.iter().map(|id| String::from(*id)).collect()
+    }
+
+    #[test]
+    fn it_quarantines_orphans() {
+        let config = test_config("it_quarantines_orphans");
+        touch(&config, "sstable-data-1");
+        touch(&config, "sstable-meta-1");
+        touch(&config, "sstable-data-2");
+        touch(&config, "sstable-meta-3");
+        touch(&config, "sstable-data-4");
+        touch(&config, "sstable-meta-4");
+        touch(&config, "sstable-data-5.tmp");
+        touch(&config, "sstable-data-6.tmp");
+        touch(&config, "wal-00000000000000000001-7");
+        touch(&config, "wal-00000000000000000002-8");
+        touch(&config, "MANIFEST");
+
+        let live = LiveFiles {
+            sstable_ids: ids(&["1"]),
+            pending_sstable_ids: ids(&["6"]),
+            wal_ids: ids(&["8"]),
+        };
+        let report = collect(&config, &live).unwrap();
+
+        let reasons: Vec<(String, Reason)> = report
+            .orphans
+            .iter()
+            .map(|o| {
+                let filename = path::Path::new(&o.path).file_name().unwrap();
+                (filename.to_string_lossy().into_owned(), o.reason)
+            })
+            .collect();
+        let mut expected = vec![
+            (String::from("sstable-data-5.tmp"), Reason::Temporary),
+            (String::from("sstable-data-2"), Reason::DataWithoutMeta),
+            (String::from("sstable-meta-3"), Reason::MetaWithoutData),
+            (String::from("sstable-data-4"), Reason::NotLive),
+            (String::from("sstable-meta-4"), Reason::NotLive),
+            (String::from("wal-00000000000000000001-7"), Reason::StaleWal),
+        ];
+        let mut actual = reasons.clone();
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        actual.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(expected, actual);
+        assert_eq!(0, report.missing.len());
+
+        // live and pending files are left alone, while orphans are moved into quarantine
+        for filename in [
+            "sstable-data-1",
+            "sstable-meta-1",
+            "sstable-data-6.tmp",
+            "wal-00000000000000000002-8",
+            "MANIFEST",
+        ] {
+            assert_eq!(true, exists(&config, filename));
+        }
+        for (filename, _) in &expected {
+            assert_eq!(false, exists(&config, filename));
+            assert_eq!(
+                true,
+                exists(&config, &format!("{}/{}", QUARANTINE_DIR, filename))
+            );
+        }
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_deletes_orphans_and_reports_missing_files() {
+        let mut config = test_config("it_deletes_orphans_and_reports_missing_files");
+        config.orphan_file_action = config::OrphanFileAction::Delete;
+        touch(&config, "sstable-data-1");
+        touch(&config, "sstable-data-2");
+        touch(&config, "sstable-meta-2");
+
+        let live = LiveFiles {
+            sstable_ids: ids(&["1", "3"]),
+            ..Default::default()
+        };
+        let report = collect(&config, &live).unwrap();
+
+        assert_eq!(vec!["1", "3"], report.missing);
+        assert_eq!(2, report.orphans.len());
+        assert_eq!(false, exists(&config, "sstable-data-2"));
+        assert_eq!(false, exists(&config, "sstable-meta-2"));
+        assert_eq!(false, exists(&config, QUARANTINE_DIR));
+        assert_eq!(true, exists(&config, "sstable-data-1"));
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+}
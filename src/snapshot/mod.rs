@@ -0,0 +1,98 @@
+// A snapshot pins a sequence number so that reads made with it only see writes up to and
+// including that sequence number. While a snapshot is alive, the memtables and compaction keep any
+// older versions of a key that the snapshot can still see.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// the sequence numbers of the live snapshots
+#[derive(Debug, Default)]
+pub struct SnapshotList {
+    // number of live snapshots for each sequence number
+    live: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    pub fn new() -> Self {
+        SnapshotList {
+            live: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn acquire(self: &Arc<Self>, seq: u64) -> Snapshot {
+        *self.live.lock().unwrap().entry(seq).or_insert(0) += 1;
+        Snapshot {
+            seq,
+            list: self.clone(),
+        }
+    }
+
+    fn release(&self, seq: u64) {
+        let mut live = self.live.lock().unwrap();
+        if let Some(count) = live.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&seq);
+            }
+        }
+    }
+
+    // the sequence numbers of the live snapshots in ascending order
+    pub fn seqs(&self) -> Vec<u64> {
+        self.live.lock().unwrap().keys().copied().collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Snapshot {
+    seq: u64,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seq);
+    }
+}
+
+// check if a version written at seq, which was overwritten at newer_seq, is still visible to any
+// of the snapshots
+pub fn is_needed(seq: u64, newer_seq: u64, snapshots: &[u64]) -> bool {
+    snapshots.iter().any(|s| seq <= *s && *s < newer_seq)
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+
+    #[test]
+    fn it_tracks_live_snapshots() {
+        let list = Arc::new(SnapshotList::new());
+        let snapshot1 = list.acquire(5);
+        let snapshot2 = list.acquire(3);
+        let snapshot3 = list.acquire(5);
+        assert_eq!(vec![3, 5], list.seqs());
+
+        drop(snapshot1);
+        assert_eq!(vec![3, 5], list.seqs());
+        drop(snapshot3);
+        assert_eq!(vec![3], list.seqs());
+        assert_eq!(3, snapshot2.seq());
+        drop(snapshot2);
+        assert_eq!(Vec::<u64>::new(), list.seqs());
+    }
+
+    #[test]
+    fn it_only_needs_versions_visible_to_a_snapshot() {
+        assert_eq!(false, is_needed(2, 4, &[]));
+        assert_eq!(true, is_needed(2, 4, &[2]));
+        assert_eq!(true, is_needed(2, 4, &[1, 3]));
+        assert_eq!(false, is_needed(2, 4, &[1, 4, 6]));
+    }
+}
@@ -10,38 +10,99 @@ use std::time;
 
 use crate::bloom;
 use crate::config;
+use crate::error::{Error, Result};
+use crate::manifest;
 use crate::memtable;
 
 pub mod reader;
 
+// suffix of the files a table is written to before it's renamed into place
+pub const TMP_SUFFIX: &str = ".tmp";
+
+// version of the entry encoding in the data files. Tables without one were written before entries
+// had sequence numbers, and can't be read
+pub const FORMAT_VERSION: u32 = 1;
+
+// flag set on entries that are deletes
+const FLAG_DELETED: u8 = 1 << 6;
+
+// flag set on entries with an expiry, which follows the sequence number
+const FLAG_EXPIRES: u8 = 1 << 5;
+
 #[derive(Debug)]
 pub struct Entry {
     flags: u8,
+    // sequence number of the write that set the value
+    pub seq: u64,
     key_length: u32,
     pub key: Vec<u8>,
     value_length: u32,
     pub value: Vec<u8>,
     pub deleted: bool,
+    // millis since the epoch after which the value is treated as deleted
+    pub expires_at: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Entry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TableMeta {
+    #[serde(default)]
+    pub format_version: u32,
     blocks: Vec<BlockMeta>,
     bloom_filter: bloom::BloomFilter,
-    timestamp: u128,
+    // millis since the epoch by when every entry in the table had been written. Compacted tables
+    // keep the latest timestamp of the tables they replaced, so entries age across compactions
+    pub timestamp: u128,
     pub level: u8,
+    // the highest sequence number of any entry or range tombstone in the table
+    #[serde(default)]
+    pub max_seq: u64,
+    // range deletions flushed with the table. They can delete keys in this table and any older one
+    #[serde(default)]
+    pub range_tombstones: Vec<memtable::RangeTombstone>,
+    // the smallest and largest key in the table, including the ranges of its tombstones. None for
+    // tables written before key ranges were recorded
+    #[serde(default)]
+    pub key_range: Option<(Vec<u8>, Vec<u8>)>,
 }
 
 impl TableMeta {
-    fn new(level: u8) -> Self {
+    fn new(level: u8, timestamp: u128) -> Self {
         TableMeta {
+            format_version: FORMAT_VERSION,
             blocks: vec![],
             bloom_filter: bloom::BloomFilter::new(2048, 2142 /* <- random seed */, 3),
-            timestamp: time::SystemTime::now()
-                .duration_since(time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis(),
+            timestamp,
             level,
+            max_seq: 0,
+            range_tombstones: vec![],
+            key_range: None,
+        }
+    }
+
+    // whether the key is within the table's key range, so the table may have an entry or a range
+    // tombstone for it. The end of a range tombstone is exclusive, so this can be true for a key
+    // the table has nothing for
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        match &self.key_range {
+            Some((smallest, largest)) => **smallest <= *key && *key <= **largest,
+            None => true,
+        }
+    }
+
+    // whether the table's key range overlaps [start, end). None is unbounded
+    pub fn overlaps(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> bool {
+        match &self.key_range {
+            Some((smallest, largest)) => {
+                start.is_none_or(|start| **largest >= *start)
+                    && end.is_none_or(|end| **smallest < *end)
+            }
+            None => true,
         }
     }
 
@@ -54,7 +115,7 @@ impl TableMeta {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 struct BlockMeta {
     count: u32,
     size: u32,
@@ -63,25 +124,47 @@ struct BlockMeta {
     start_offset: u32,
 }
 
-//
-// TODO
-// - comment about what this is doing
+// returns a description of the new table. The table isn't live until this has been added to the
+// MANIFEST
 pub fn flush_to_sstable(
     config: &config::Config,
     memtable: &memtable::Memtable,
     level: u8,
-) -> io::Result<u32> {
+) -> Result<manifest::FileMeta> {
+    flush_to_sstable_at(config, memtable, level, now_millis() as u128)
+}
+
+// like flush_to_sstable, but for entries that had all been written by timestamp (millis since the
+// epoch), e.g. entries read back from older tables
+pub fn flush_to_sstable_at(
+    config: &config::Config,
+    memtable: &memtable::Memtable,
+    level: u8,
+    timestamp: u128,
+) -> Result<manifest::FileMeta> {
     log::info!(
-        "flushing memtable id = {}, size = {}",
+        "flushing memtable id = {}, size = {}, approximate bytes = {}",
         memtable.id,
-        memtable.size()
+        memtable.size(),
+        memtable.approximate_size()
     );
-    let mut table_meta = TableMeta::new(level);
+    let mut table_meta = TableMeta::new(level, timestamp);
+    table_meta.range_tombstones = memtable.range_tombstones();
+    for tombstone in &table_meta.range_tombstones {
+        table_meta.max_seq = table_meta.max_seq.max(tombstone.seq);
+    }
 
     let iter = memtable.iter();
     let entries: Vec<Entry> = iter
-        .map(|(key, value)| {
+        .map(|version| {
+            let memtable::Version {
+                key,
+                value,
+                seq,
+                expires_at,
+            } = version;
             table_meta.bloom_filter.insert(&key);
+            table_meta.max_seq = table_meta.max_seq.max(seq);
             let key_length = key.len() as u32;
             let mut value_length = 0;
             let mut entry_value = vec![];
@@ -94,25 +177,34 @@ pub fn flush_to_sstable(
 
             let mut flags: u8 = 0;
             if deleted {
-                flags += 1 << 6;
+                flags += FLAG_DELETED;
+            }
+            if expires_at.is_some() {
+                flags += FLAG_EXPIRES;
             }
 
             Entry {
                 flags,
+                seq,
                 key,
                 key_length,
                 value: entry_value,
                 value_length,
                 deleted,
+                expires_at,
             }
         })
         .collect();
 
+    // the table is written under temporary names and renamed into place once it's complete, so
+    // a crash part way through never leaves a data file without its meta file
     let filename = format!("{}/sstable-data-{}", config.data_dir, memtable.id);
-    let path = path::Path::new(&filename);
+    let tmp_filename = format!("{}{}", filename, TMP_SUFFIX);
+    let path = path::Path::new(&tmp_filename);
     let mut file = fs::OpenOptions::new()
         .write(true)
-        .create_new(true)
+        .create(true)
+        .truncate(true)
         .open(path)?;
 
     let mut current_block = BlockMeta {
@@ -126,23 +218,27 @@ pub fn flush_to_sstable(
     let mut total_bytes_written = 0u32;
     let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
 
-    for entry in &entries {
-        encoder.write(&[entry.flags])?;
-        encoder.write(&[
+    for (i, entry) in entries.iter().enumerate() {
+        encoder.write_all(&[entry.flags])?;
+        encoder.write_all(&entry.seq.to_be_bytes())?;
+        if let Some(expires_at) = entry.expires_at {
+            encoder.write_all(&expires_at.to_be_bytes())?;
+        }
+        encoder.write_all(&[
             (entry.key_length >> 24) as u8,
             (entry.key_length >> 16) as u8,
             (entry.key_length >> 8) as u8,
             entry.key_length as u8,
         ])?;
-        encoder.write(&entry.key)?;
+        encoder.write_all(&entry.key)?;
         if !entry.deleted {
-            encoder.write(&[
+            encoder.write_all(&[
                 (entry.value_length >> 24) as u8,
                 (entry.value_length >> 16) as u8,
                 (entry.value_length >> 8) as u8,
                 entry.value_length as u8,
             ])?;
-            encoder.write(&entry.value)?;
+            encoder.write_all(&entry.value)?;
         }
 
         if current_block.count == 0 {
@@ -155,8 +251,10 @@ pub fn flush_to_sstable(
             current_block.size += entry.value_length;
         }
 
-        // flush compressed block
-        if current_block.size >= config.sstable_block_size {
+        // flush compressed block. All the versions of a key are kept in the same block, so that
+        // lookups only need to read the block the key starts in
+        let last_version = entries.get(i + 1).is_none_or(|next| next.key != entry.key);
+        if last_version && current_block.size >= config.sstable_block_size {
             let bytes: Vec<u8> = encoder.finish()?;
             current_block.size_compressed = bytes.len() as u32;
             log::debug!(
@@ -169,7 +267,7 @@ pub fn flush_to_sstable(
             );
             table_meta.blocks.push(current_block);
             total_bytes_written += bytes.len() as u32;
-            file.write(&bytes)?;
+            file.write_all(&bytes)?;
 
             encoder = GzEncoder::new(Vec::new(), Compression::default());
             current_block = BlockMeta {
@@ -193,38 +291,103 @@ pub fn flush_to_sstable(
             current_block.start_key,
         );
         table_meta.blocks.push(current_block);
-        file.write(&bytes)?;
+        file.write_all(&bytes)?;
     }
+
+    // the key range includes the ranges of the tombstones, since they affect those keys too
+    let mut smallest_key = entries.first().map(|e| e.key.clone());
+    let mut largest_key = entries.last().map(|e| e.key.clone());
+    for tombstone in &table_meta.range_tombstones {
+        if smallest_key
+            .as_ref()
+            .is_none_or(|key| tombstone.start < *key)
+        {
+            smallest_key = Some(tombstone.start.clone());
+        }
+        if largest_key.as_ref().is_none_or(|key| tombstone.end > *key) {
+            largest_key = Some(tombstone.end.clone());
+        }
+    }
+    if let (Some(smallest_key), Some(largest_key)) = (&smallest_key, &largest_key) {
+        table_meta.key_range = Some((smallest_key.clone(), largest_key.clone()));
+    }
+
     file.flush()?;
+    file.sync_all()?;
 
     flush_sstable_meta(config, memtable, &table_meta)?;
 
-    return Ok(1);
+    // the meta file is renamed first, so there is never a data file without one
+    let meta_filename = format!("{}/sstable-meta-{}", config.data_dir, memtable.id);
+    fs::rename(format!("{}{}", meta_filename, TMP_SUFFIX), &meta_filename)?;
+    fs::rename(&tmp_filename, &filename)?;
+    fs::File::open(&config.data_dir)?.sync_all()?;
+
+    Ok(manifest::FileMeta {
+        id: memtable.id.clone(),
+        level,
+        smallest_key: smallest_key.unwrap_or_default(),
+        largest_key: largest_key.unwrap_or_default(),
+        max_seq: table_meta.max_seq,
+    })
+}
+
+pub fn now_millis() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
 
 fn flush_sstable_meta(
     config: &config::Config,
     memtable: &memtable::Memtable,
     metadata: &TableMeta,
-) -> io::Result<()> {
-    let filename = format!("{}/sstable-meta-{}", config.data_dir, memtable.id);
+) -> Result<()> {
+    let filename = format!(
+        "{}/sstable-meta-{}{}",
+        config.data_dir, memtable.id, TMP_SUFFIX
+    );
     let path = path::Path::new(&filename);
     let file = fs::OpenOptions::new()
         .write(true)
-        .create_new(true)
+        .create(true)
+        .truncate(true)
         .open(path)?;
 
-    let result = serde_yaml::to_writer(file, metadata);
-    match result {
-        Ok(_) => return Ok(()),
-        Err(err) => {
-            log::error!("Error happen {:?}", err);
-            panic!(
-                "error deserializsing {}/sstable-meta-{}",
-                config.data_dir, memtable.id
-            );
+    serde_yaml::to_writer(&file, metadata).map_err(|err| {
+        Error::Io(io::Error::other(format!(
+            "error serializing {}/sstable-meta-{}: {}",
+            config.data_dir, memtable.id, err
+        )))
+    })?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// remove the temporary files of tables whose flush didn't finish before the last shutdown
+pub fn remove_temporary_files(config: &config::Config) -> io::Result<()> {
+    for file in fs::read_dir(&config.data_dir)? {
+        let path = file?.path();
+        let filename = match path.file_name().and_then(|f| f.to_str()) {
+            Some(filename) => filename,
+            None => continue,
+        };
+        if filename.starts_with("sstable-") && filename.ends_with(TMP_SUFFIX) {
+            log::info!("removing temporary sstable file {:?}", path);
+            fs::remove_file(&path)?;
         }
-    };
+    }
+    Ok(())
+}
+
+pub fn delete_by_id(config: &config::Config, sstable_id: &str) -> io::Result<()> {
+    let data_file = format!("{}/sstable-data-{}", config.data_dir, sstable_id);
+    fs::remove_file(data_file)?;
+    let meta_file = format!("{}/sstable-meta-{}", config.data_dir, sstable_id);
+    fs::remove_file(meta_file)?;
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -242,23 +405,30 @@ mod mod_tests {
         config.data_dir = String::from(data_dir);
         config.sstable_block_size = 12;
 
-        let mut memtable = memtable::Memtable::new();
+        let memtable = memtable::Memtable::new();
         // block 1
-        memtable.insert("1bc".bytes().collect(), Some("abc".bytes().collect()));
-        memtable.insert("1ef".bytes().collect(), Some("def".bytes().collect()));
+        memtable.insert("1bc".bytes().collect(), Some("abc".bytes().collect()), 1);
+        memtable.insert("1ef".bytes().collect(), Some("def".bytes().collect()), 2);
 
         // block 2
-        memtable.insert("2bc".bytes().collect(), Some("abc".bytes().collect()));
-        memtable.insert("2ef".bytes().collect(), Some("def".bytes().collect()));
+        memtable.insert("2bc".bytes().collect(), Some("abc".bytes().collect()), 3);
+        memtable.insert("2ef".bytes().collect(), Some("def".bytes().collect()), 4);
 
         // block 3
-        memtable.insert("3bc".bytes().collect(), Some("abc".bytes().collect()));
+        memtable.insert("3bc".bytes().collect(), Some("abc".bytes().collect()), 5);
 
         let result = flush_to_sstable(&config, &memtable, 0);
 
         // expect result to be OK
         assert_eq!(true, result.is_ok());
 
+        // expect the result to describe the table for the MANIFEST
+        let file_meta = result.unwrap();
+        assert_eq!(memtable.id, file_meta.id);
+        assert_eq!(String::from("1bc").into_bytes(), file_meta.smallest_key);
+        assert_eq!(String::from("3bc").into_bytes(), file_meta.largest_key);
+        assert_eq!(5, file_meta.max_seq);
+
         // expect there is both a data table a meta table
         let data_meta_r = fs::metadata(format!("{}/sstable-data-{}", data_dir, memtable.id));
         assert_eq!(true, data_meta_r.is_ok());
@@ -292,23 +462,48 @@ mod mod_tests {
         assert_eq!(2, block1.count);
         assert_eq!(12, block1.size);
         assert_eq!(String::from("2bc").into_bytes(), block1.start_key);
-        assert_eq!(40, block1.start_offset);
+        assert_eq!(45, block1.start_offset);
 
         let block2 = &table_meta.blocks[2];
         assert_eq!(1, block2.count);
         assert_eq!(6, block2.size);
         assert_eq!(String::from("3bc").into_bytes(), block2.start_key);
-        assert_eq!(80, block2.start_offset);
+        assert_eq!(89, block2.start_offset);
 
         fs::remove_dir_all(data_dir).unwrap();
     }
-}
 
-pub fn delete_by_id(config: &config::Config, sstable_id: &str) -> io::Result<()> {
-    let data_file = format!("{}/sstable-data-{}", config.data_dir, sstable_id);
-    fs::remove_file(data_file)?;
-    let meta_file = format!("{}/sstable-meta-{}", config.data_dir, sstable_id);
-    fs::remove_file(meta_file)?;
+    #[test]
+    fn it_cleans_up_temporary_files() {
+        let data_dir = "/tmp/sstable_tests/it_cleans_up_temporary_files";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
 
-    Ok(())
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+
+        let memtable = memtable::Memtable::new();
+        memtable.insert("abc".bytes().collect(), Some("abc".bytes().collect()), 1);
+        flush_to_sstable(&config, &memtable, 0).unwrap();
+
+        // simulate a flush that crashed before the files were renamed into place
+        let tmp_data = format!("{}/sstable-data-1234{}", data_dir, TMP_SUFFIX);
+        fs::write(&tmp_data, "partial").unwrap();
+
+        remove_temporary_files(&config).unwrap();
+        let mut filenames: Vec<String> = fs::read_dir(data_dir)
+            .unwrap()
+            .map(|f| f.unwrap().file_name().into_string().unwrap())
+            .collect();
+        filenames.sort();
+        assert_eq!(
+            vec![
+                format!("sstable-data-{}", memtable.id),
+                format!("sstable-meta-{}", memtable.id)
+            ],
+            filenames
+        );
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
 }
@@ -7,70 +7,95 @@ use std::io;
 use std::io::{Read, Seek};
 use std::path;
 
-use super::{BlockMeta, Entry, TableMeta};
+use super::{BlockMeta, Entry, TableMeta, FLAG_DELETED, FLAG_EXPIRES};
 use crate::config;
+use crate::error::{Error, Result};
+use crate::manifest;
 use crate::memtable;
 
 pub struct Reader {
+    data_dir: String,
+    // ordered by max sequence number, newest first
     sstables: VecDeque<(TableMeta, Box<path::Path>)>,
 }
 
 impl Reader {
     pub fn new() -> Self {
         Reader {
+            data_dir: String::new(),
             sstables: VecDeque::new(),
         }
     }
 
-    // this scans the sstable directory for sstables
-    // TODO
-    // - try to ignore half-way flushed sstables
-    // - try to ignore files called sstables, that aren't sstables (could do this by checking metadata)
-    pub fn init(&mut self, config: &config::Config) {
+    // load the sstables that are live according to the MANIFEST
+    pub fn init(&mut self, config: &config::Config, manifest: &manifest::Manifest) -> Result<()> {
         log::info!("initializing sstable reader");
+        self.data_dir = config.data_dir.clone();
 
         let mut sstables = vec![];
-        for file in fs::read_dir(&config.data_dir).unwrap() {
-            let path: Box<path::Path> = file.unwrap().path().into_boxed_path();
-            if is_sstable(&path) {
-                let meta_path = String::from(
-                    Regex::new("sstable-data")
-                        .unwrap()
-                        .replace(path.to_str().unwrap(), "sstable-meta"),
-                );
-                let table_meta = read_table_meta(path::Path::new(&meta_path));
-
-                log::debug!(
-                    "found memtable = {:?}, num_blocks = {:?}",
-                    path,
-                    table_meta.blocks.len()
-                );
-
-                sstables.push((table_meta, path));
-            }
+        for file_meta in manifest.files() {
+            let filename = format!("{}/sstable-data-{}", config.data_dir, file_meta.id);
+            let path = path::PathBuf::from(filename).into_boxed_path();
+            let meta_path = to_metadata_path(&path);
+            let table_meta = read_table_meta(path::Path::new(&meta_path))?;
+
+            log::debug!(
+                "found memtable = {:?}, num_blocks = {:?}",
+                path,
+                table_meta.blocks.len()
+            );
+
+            sstables.push((table_meta, path));
         }
 
         // make sure the sstables are ordered newest to oldest
-        sstables.sort_by(|a, b| {
-            let (a_meta, _1) = a;
-            let (b_meta, _2) = b;
-
-            if a_meta.timestamp > b_meta.timestamp {
-                std::cmp::Ordering::Less
-            } else {
-                std::cmp::Ordering::Greater
-            }
-        });
+        sstables.sort_by_key(|(table_meta, _)| std::cmp::Reverse(table_meta.max_seq));
 
         sstables.into_iter().for_each(|v| {
             self.sstables.push_back(v);
         });
 
         log::info!("initialized with {} memtables", self.sstables.len());
+        Ok(())
+    }
+
+    // the highest sequence number of any entry in the sstables
+    pub fn max_seq(&self) -> u64 {
+        self.sstables
+            .front()
+            .map_or(0, |(table_meta, _)| table_meta.max_seq)
     }
 
-    pub fn find(&self, key: &[u8]) -> Option<Vec<u8>> {
+    // the key can be in more than one table, in which case the version with the highest sequence
+    // number wins
+    pub fn find(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.find_at(key, u64::MAX)
+    }
+
+    // find the newest version of the key written at or before read_seq. A range tombstone in any
+    // table deletes the versions older than it. Tables below level 0 don't overlap each other, so
+    // only the one table per level whose key range contains the key is read
+    pub fn find_at(&self, key: &[u8], read_seq: u64) -> Result<Option<Vec<u8>>> {
+        let mut newest: Option<Entry> = None;
+        // sequence number of the newest range tombstone that contains the key
+        let mut tombstone_seq: Option<u64> = None;
         for (table_meta, path) in &self.sstables {
+            // the tables are ordered by max sequence number, so once we've found a version or
+            // tombstone newer than anything in this table we can stop looking
+            let newest_seq = newest.as_ref().map(|entry| entry.seq).max(tombstone_seq);
+            if matches!(newest_seq, Some(seq) if seq >= table_meta.max_seq) {
+                break;
+            }
+
+            // a table's key range includes its range tombstones, so a table outside it can't
+            // affect the key either
+            if !table_meta.may_contain(key) {
+                continue;
+            }
+
+            let covering = memtable::newest_covering(&table_meta.range_tombstones, key, read_seq);
+            tombstone_seq = tombstone_seq.max(covering);
+
             log::debug!("searching for '{:?}' in '{:?}", key, path);
 
             if !table_meta.bloom_filter.contains(key) {
@@ -84,30 +109,74 @@ impl Reader {
                 continue;
             }
 
-            let result = find_from_table(key, path, &table_meta.blocks[block.unwrap()]);
-            match result {
-                Ok((Some(entry), _)) => {
+            let result = find_from_table(key, read_seq, path, &table_meta.blocks[block.unwrap()]);
+            match result.map_err(|err| table_error(path, err))? {
+                Some(entry) => {
                     log::debug!("found '{:?}' in '{:?}", key, path);
-                    return Some(entry.value);
+                    if !matches!(&newest, Some(found) if found.seq >= entry.seq) {
+                        newest = Some(entry);
+                    }
                 }
-                Ok((None, true)) => return None,
-                Ok((None, false)) => {
+                None => {
                     log::debug!("not found '{:?}' in '{:?}", key, path);
                 }
-                Err(err) => {
-                    // TODO handle in a smarter way
-                    panic!("error happened reading from file {:?} {:?}", path, err)
-                }
             }
         }
-        None
+
+        // an expired value hides older versions like a delete does
+        match newest {
+            Some(entry)
+                if !entry.deleted
+                    && !entry.is_expired(super::now_millis())
+                    && tombstone_seq.is_none_or(|seq| seq < entry.seq) =>
+            {
+                Ok(Some(entry.value))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // the range tombstones of every sstable
+    pub fn range_tombstones(&self) -> Vec<memtable::RangeTombstone> {
+        self.sstables
+            .iter()
+            .flat_map(|(table_meta, _)| table_meta.range_tombstones.iter().cloned())
+            .collect()
     }
 
-    pub fn add_memtable(&mut self, memtable: &memtable::Memtable) {
-        let filename = format!("/tmp/sstable-data-{}", memtable.id);
+    // create iterators over every sstable, ordered newest to oldest. The iterators are positioned
+    // at the start key, or for reverse iterators, just before the end key
+    pub fn iters(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        reverse: bool,
+    ) -> io::Result<Vec<SstableIterator>> {
+        let mut iters = vec![];
+        for (table_meta, path) in &self.sstables {
+            if !table_meta.overlaps(start, end) {
+                continue;
+            }
+            let mut iter = if reverse {
+                SstableIterator::new_reverse(path.clone(), table_meta.clone())?
+            } else {
+                SstableIterator::new(path.clone(), table_meta.clone())?
+            };
+
+            let seek_key = if reverse { end } else { start };
+            if let Some(key) = seek_key {
+                iter.seek(key)?;
+            }
+            iters.push(iter);
+        }
+        Ok(iters)
+    }
+
+    pub fn add_memtable(&mut self, memtable: &memtable::Memtable) -> Result<()> {
+        let filename = format!("{}/sstable-data-{}", self.data_dir, memtable.id);
         let path = path::PathBuf::from(filename).into_boxed_path();
         let meta_path = to_metadata_path(&path);
-        let table_meta = read_table_meta(path::Path::new(&meta_path));
+        let table_meta = read_table_meta(path::Path::new(&meta_path))?;
         log::debug!(
             "memtable added = {:?}, num_blocks = {:?}. There are now {:?} reader memtables",
             path,
@@ -115,8 +184,22 @@ impl Reader {
             self.sstables.len() + 1,
         );
 
-        // TODO need to re-sort sstable
-        self.sstables.push_front((table_meta, path));
+        let position = self
+            .sstables
+            .iter()
+            .position(|(other, _)| other.max_seq < table_meta.max_seq)
+            .unwrap_or(self.sstables.len());
+        self.sstables.insert(position, (table_meta, path));
+        Ok(())
+    }
+
+    // ids of the sstables being read from
+    pub fn ids(&self) -> Vec<String> {
+        self.sstables
+            .iter()
+            .filter_map(|(_, path)| path.file_name()?.to_str()?.strip_prefix("sstable-data-"))
+            .map(String::from)
+            .collect()
     }
 
     pub fn remove_memtable(&mut self, memtable_id: &str) {
@@ -143,107 +226,129 @@ fn to_metadata_path(path: &path::Path) -> String {
     return meta_path;
 }
 
-fn read_table_meta(path: &path::Path) -> TableMeta {
-    let file = fs::OpenOptions::new().read(true).open(path);
-    match file {
-        Ok(file) => {
-            let result: TableMeta = serde_yaml::from_reader(file).unwrap();
-            return result;
-        }
-        Err(err) => {
-            log::error!(
-                "An error happened reading sstable meta at {:?}: {:?}",
-                path,
-                err
-            );
-            panic!("could not read table meta, invalid state");
-        }
-    };
+pub fn read_table_meta(path: &path::Path) -> Result<TableMeta> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .open(path)
+        .map_err(|err| table_error(path, err))?;
+    let table_meta: TableMeta = serde_yaml::from_reader(file)
+        .map_err(|err| Error::Corruption(format!("invalid sstable meta {:?}: {}", path, err)))?;
+    if table_meta.format_version != super::FORMAT_VERSION {
+        return Err(Error::Corruption(format!(
+            "sstable {:?} has format version {}, but only version {} can be read",
+            path,
+            table_meta.format_version,
+            super::FORMAT_VERSION
+        )));
+    }
+    Ok(table_meta)
 }
 
-fn is_sstable(path: &path::Path) -> bool {
-    let re = regex::Regex::new(r".*/sstable-data.*$").unwrap();
-    re.is_match(path.to_str().unwrap())
+// add the path of the table to the error, so it's clear which table is bad
+fn table_error(path: &path::Path, err: io::Error) -> Error {
+    match Error::from(err) {
+        Error::Corruption(msg) => Error::Corruption(format!("{:?}: {}", path, msg)),
+        Error::NotFound(msg) => Error::NotFound(format!("{:?}: {}", path, msg)),
+        err => err,
+    }
 }
 
 fn deserialize_block(path: &path::Path, block: &BlockMeta) -> io::Result<Vec<u8>> {
     let mut file = fs::OpenOptions::new().read(true).open(path)?;
+    read_block(&mut file, block)
+}
+
+fn read_block(file: &mut fs::File, block: &BlockMeta) -> io::Result<Vec<u8>> {
     let start = block.start_offset as u64;
     let seek_start = io::SeekFrom::Start(start);
     file.seek(seek_start)?;
     let mut bytes = Vec::<u8>::with_capacity(block.size_compressed as usize);
-    file.read_to_end(&mut bytes)?;
+    file.take(block.size_compressed as u64)
+        .read_to_end(&mut bytes)?;
 
+    // the block is already in memory, so a decoding error means the block is corrupt
     let mut decoder = GzDecoder::new(&*bytes);
     let mut decompressed = Vec::<u8>::with_capacity(block.size as usize);
-    decoder.read_to_end(&mut decompressed)?;
+    decoder
+        .read_to_end(&mut decompressed)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
     return Ok(decompressed);
 }
 
+// find the newest entry for the key in the block written at or before read_seq. Deleted entries
+// are returned too
 fn find_from_table(
     search_key: &[u8],
+    read_seq: u64,
     path: &path::Path,
     block: &BlockMeta,
-) -> io::Result<(Option<Entry>, bool)> {
-    let bytes1 = deserialize_block(path, block)?;
-    let mut bytes = bytes1.into_iter().map(|b| Ok::<u8, io::Error>(b));
+) -> io::Result<Option<Entry>> {
+    let bytes = deserialize_block(path, block)?;
+    let entries = decode_block(&bytes)?;
+    Ok(entries
+        .into_iter()
+        .find(|entry| *entry.key == *search_key && entry.seq <= read_seq))
+}
 
-    loop {
-        let flags_1_option = bytes.next();
-        if flags_1_option.is_none() {
-            return Ok((None, false));
+fn decode_block(bytes: &[u8]) -> io::Result<Vec<Entry>> {
+    let mut bytes = bytes;
+    let mut entries = vec![];
+    while !bytes.is_empty() {
+        let flags = take(&mut bytes, 1)?[0];
+        let deleted = flags & FLAG_DELETED > 0;
+
+        let seq = read_u64(&mut bytes)?;
+        let mut expires_at = None;
+        if flags & FLAG_EXPIRES > 0 {
+            expires_at = Some(read_u64(&mut bytes)?);
         }
 
-        let flags_1 = flags_1_option.unwrap()?;
-        let deleted = flags_1 & (1 << 6) > 0;
-
-        let key_length = ((bytes.next().unwrap()? as u32) << 24)
-            + ((bytes.next().unwrap()? as u32) << 16)
-            + ((bytes.next().unwrap()? as u32) << 8)
-            + (bytes.next().unwrap()? as u32);
-
-        let mut key: Vec<u8> = Vec::with_capacity(key_length as usize);
-        for _ in 0..key_length {
-            key.push(bytes.next().unwrap()?);
-        }
+        let key_length = read_u32(&mut bytes)?;
+        let key = take(&mut bytes, key_length as usize)?.to_vec();
 
         let mut value_length = 0;
+        let mut value = vec![];
         if !deleted {
-            value_length = ((bytes.next().unwrap()? as u32) << 24)
-                + ((bytes.next().unwrap()? as u32) << 16)
-                + ((bytes.next().unwrap()? as u32) << 8)
-                + (bytes.next().unwrap()? as u32);
+            value_length = read_u32(&mut bytes)?;
+            value = take(&mut bytes, value_length as usize)?.to_vec();
         }
 
-        // if key matches, return result
-        if &key == search_key {
-            if deleted {
-                return Ok((None, true));
-            }
+        entries.push(Entry {
+            flags,
+            seq,
+            deleted,
+            expires_at,
+            key,
+            key_length,
+            value,
+            value_length,
+        });
+    }
+    Ok(entries)
+}
 
-            let mut value = Vec::with_capacity(value_length as usize);
-            for _ in 0..value_length {
-                value.push(bytes.next().unwrap()?);
-            }
+// take the next n bytes from the front of the slice
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if bytes.len() < n {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "sstable block is truncated",
+        ));
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
 
-            return Ok((
-                Some(Entry {
-                    flags: 0,
-                    key_length,
-                    key,
-                    value_length,
-                    value,
-                    deleted,
-                }),
-                true,
-            ));
-        }
+fn read_u32(bytes: &mut &[u8]) -> io::Result<u32> {
+    let value = take(bytes, 4)?;
+    Ok(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+}
 
-        // skip over the value
-        for _ in 0..value_length {
-            bytes.next();
-        }
-    }
+fn read_u64(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut value = [0; 8];
+    value.copy_from_slice(take(bytes, 8)?);
+    Ok(u64::from_be_bytes(value))
 }
 
 // do binary seach on the block data for the key
@@ -288,6 +393,18 @@ fn find_block(search_key: &[u8], table_meta: &TableMeta) -> Option<usize> {
     }
 }
 
+// add the table to the MANIFEST so the reader will load it. The MANIFEST has to be opened
+// before the first table is flushed
+#[cfg(test)]
+fn commit(config: &config::Config, file_meta: manifest::FileMeta) {
+    let mut manifest = manifest::Manifest::open(config).unwrap();
+    let edit = manifest::VersionEdit {
+        added: vec![file_meta],
+        removed: vec![],
+    };
+    manifest.apply(edit).unwrap();
+}
+
 #[cfg(test)]
 mod reader_tests {
     use super::*;
@@ -301,77 +418,267 @@ mod reader_tests {
 
         let mut config = config::Config::new();
         config.data_dir = String::from(data_dir);
+        manifest::Manifest::open(&config).unwrap();
         config.sstable_block_size = 12;
 
-        let mut memtable = memtable::Memtable::new();
+        let memtable = memtable::Memtable::new();
         // block 1
-        memtable.insert("1bc".bytes().collect(), Some("abc".bytes().collect()));
-        memtable.insert("1ef".bytes().collect(), Some("def".bytes().collect()));
+        memtable.insert("1bc".bytes().collect(), Some("abc".bytes().collect()), 1);
+        memtable.insert("1ef".bytes().collect(), Some("def".bytes().collect()), 2);
         // block 2
-        memtable.insert("2bc".bytes().collect(), Some("abc".bytes().collect()));
-        memtable.insert("2ef".bytes().collect(), Some("def".bytes().collect()));
+        memtable.insert("2bc".bytes().collect(), Some("abc".bytes().collect()), 3);
+        memtable.insert("2ef".bytes().collect(), Some("def".bytes().collect()), 4);
         // block 3
-        memtable.insert("3bc".bytes().collect(), Some("abc".bytes().collect()));
-        sstable::flush_to_sstable(&config, &memtable, 0).unwrap();
+        memtable.insert("3bc".bytes().collect(), Some("abc".bytes().collect()), 5);
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &memtable, 0).unwrap(),
+        );
 
-        let mut memtable = memtable::Memtable::new();
+        let memtable = memtable::Memtable::new();
         // block 1
-        memtable.insert("4bc".bytes().collect(), Some("abc".bytes().collect()));
-        memtable.insert("4ef".bytes().collect(), Some("def".bytes().collect()));
+        memtable.insert("4bc".bytes().collect(), Some("abc".bytes().collect()), 6);
+        memtable.insert("4ef".bytes().collect(), Some("def".bytes().collect()), 7);
         // block 2
-        memtable.insert("5bc".bytes().collect(), Some("abc".bytes().collect()));
-        memtable.insert("5ef".bytes().collect(), Some("def".bytes().collect()));
+        memtable.insert("5bc".bytes().collect(), Some("abc".bytes().collect()), 8);
+        memtable.insert("5ef".bytes().collect(), Some("def".bytes().collect()), 9);
         // block 3
-        memtable.insert("6bc".bytes().collect(), Some("abc".bytes().collect()));
-        sstable::flush_to_sstable(&config, &memtable, 0).unwrap();
+        memtable.insert("6bc".bytes().collect(), Some("abc".bytes().collect()), 10);
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &memtable, 0).unwrap(),
+        );
 
         let mut reader = Reader::new();
-        reader.init(&config);
+        reader
+            .init(&config, &manifest::Manifest::open(&config).unwrap())
+            .unwrap();
 
         assert_eq!(2, reader.sstables.len());
 
-        let find_none = reader.find("7bc".as_bytes());
+        let find_none = reader.find("7bc".as_bytes()).unwrap();
         assert_eq!(true, find_none.is_none());
 
-        let find1 = reader.find("1bc".as_bytes());
+        let find1 = reader.find("1bc".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("abc").into_bytes(), find1.unwrap());
-        let find1 = reader.find("1ef".as_bytes());
+        let find1 = reader.find("1ef".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("def").into_bytes(), find1.unwrap());
 
-        let find1 = reader.find("2bc".as_bytes());
+        let find1 = reader.find("2bc".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("abc").into_bytes(), find1.unwrap());
-        let find1 = reader.find("2ef".as_bytes());
+        let find1 = reader.find("2ef".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("def").into_bytes(), find1.unwrap());
 
-        let find1 = reader.find("3bc".as_bytes());
+        let find1 = reader.find("3bc".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("abc").into_bytes(), find1.unwrap());
 
-        let find1 = reader.find("4bc".as_bytes());
+        let find1 = reader.find("4bc".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("abc").into_bytes(), find1.unwrap());
-        let find1 = reader.find("4ef".as_bytes());
+        let find1 = reader.find("4ef".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("def").into_bytes(), find1.unwrap());
 
-        let find1 = reader.find("5bc".as_bytes());
+        let find1 = reader.find("5bc".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("abc").into_bytes(), find1.unwrap());
-        let find1 = reader.find("5ef".as_bytes());
+        let find1 = reader.find("5ef".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("def").into_bytes(), find1.unwrap());
 
-        let find1 = reader.find("6bc".as_bytes());
+        let find1 = reader.find("6bc".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
         assert_eq!(String::from("abc").into_bytes(), find1.unwrap());
 
         fs::remove_dir_all(data_dir).unwrap();
     }
 
+    #[test]
+    fn it_finds_the_version_with_the_highest_seq() {
+        let data_dir = "/tmp/sstable_reader_tests/it_finds_the_version_with_the_highest_seq";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        manifest::Manifest::open(&config).unwrap();
+
+        // the table with the newer version is written first, so its timestamp is older
+        let newer = memtable::Memtable::new();
+        newer.insert("a".bytes().collect(), Some("new".bytes().collect()), 3);
+        newer.insert("b".bytes().collect(), None, 4);
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &newer, 0).unwrap(),
+        );
+
+        let older = memtable::Memtable::new();
+        older.insert("a".bytes().collect(), Some("old".bytes().collect()), 1);
+        older.insert("b".bytes().collect(), Some("old".bytes().collect()), 2);
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &older, 0).unwrap(),
+        );
+
+        let mut reader = Reader::new();
+        reader
+            .init(&config, &manifest::Manifest::open(&config).unwrap())
+            .unwrap();
+        assert_eq!(4, reader.max_seq());
+        assert_eq!(
+            Some("new".bytes().collect()),
+            reader.find("a".as_bytes()).unwrap()
+        );
+        assert_eq!(None, reader.find("b".as_bytes()).unwrap());
+
+        // adding the tables in the wrong order still keeps them ordered by seq
+        let mut reader = Reader::new();
+        reader.data_dir = String::from(data_dir);
+        reader.add_memtable(&newer).unwrap();
+        reader.add_memtable(&older).unwrap();
+        assert_eq!(
+            Some("new".bytes().collect()),
+            reader.find("a".as_bytes()).unwrap()
+        );
+        assert_eq!(None, reader.find("b".as_bytes()).unwrap());
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_finds_older_versions_at_a_snapshot() {
+        let data_dir = "/tmp/sstable_reader_tests/it_finds_older_versions_at_a_snapshot";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        manifest::Manifest::open(&config).unwrap();
+        // small enough that every entry would get its own block
+        config.sstable_block_size = 2;
+
+        let snapshots = [2, 3];
+        let memtable = memtable::Memtable::new();
+        memtable.insert_retaining(
+            "a".bytes().collect(),
+            Some("1".bytes().collect()),
+            1,
+            &snapshots,
+        );
+        memtable.insert_retaining("a".bytes().collect(), None, 3, &snapshots);
+        memtable.insert_retaining(
+            "a".bytes().collect(),
+            Some("4".bytes().collect()),
+            4,
+            &snapshots,
+        );
+        memtable.insert_retaining(
+            "b".bytes().collect(),
+            Some("2".bytes().collect()),
+            2,
+            &snapshots,
+        );
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &memtable, 0).unwrap(),
+        );
+
+        let mut reader = Reader::new();
+        reader
+            .init(&config, &manifest::Manifest::open(&config).unwrap())
+            .unwrap();
+        assert_eq!(
+            Some("4".bytes().collect()),
+            reader.find("a".as_bytes()).unwrap()
+        );
+        assert_eq!(None, reader.find_at("a".as_bytes(), 3).unwrap());
+        assert_eq!(
+            Some("1".bytes().collect()),
+            reader.find_at("a".as_bytes(), 2).unwrap()
+        );
+        assert_eq!(None, reader.find_at("b".as_bytes(), 1).unwrap());
+
+        // the versions of a key aren't split across blocks
+        assert_eq!(2, reader.sstables[0].0.blocks.len());
+
+        // iterating in reverse still returns the versions of a key newest first
+        let (table_meta, path) = &reader.sstables[0];
+        let mut iter = SstableIterator::new_reverse(path.clone(), table_meta.clone()).unwrap();
+        let mut seqs = vec![];
+        while let Some(entry) = iter.try_next().unwrap() {
+            seqs.push(entry.seq);
+        }
+        assert_eq!(vec![2, 4, 3, 1], seqs);
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_deletes_keys_covered_by_range_tombstones() {
+        let data_dir = "/tmp/sstable_reader_tests/it_deletes_keys_covered_by_range_tombstones";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        manifest::Manifest::open(&config).unwrap();
+
+        let older = memtable::Memtable::new();
+        for (seq, key) in ["a", "b", "c"].iter().enumerate() {
+            older.insert(
+                key.bytes().collect(),
+                Some("old".bytes().collect()),
+                seq as u64 + 1,
+            );
+        }
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &older, 0).unwrap(),
+        );
+
+        // a table with nothing but a tombstone still has a key range
+        let deleted = memtable::Memtable::new();
+        deleted.delete_range("a".bytes().collect(), "c".bytes().collect(), 4);
+        let file_meta = sstable::flush_to_sstable(&config, &deleted, 0).unwrap();
+        assert_eq!(String::from("a").into_bytes(), file_meta.smallest_key);
+        assert_eq!(String::from("c").into_bytes(), file_meta.largest_key);
+        assert_eq!(4, file_meta.max_seq);
+        commit(&config, file_meta);
+
+        let newer = memtable::Memtable::new();
+        newer.insert("b".bytes().collect(), Some("new".bytes().collect()), 5);
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &newer, 0).unwrap(),
+        );
+
+        let mut reader = Reader::new();
+        reader
+            .init(&config, &manifest::Manifest::open(&config).unwrap())
+            .unwrap();
+        assert_eq!(None, reader.find("a".as_bytes()).unwrap());
+        assert_eq!(
+            Some("new".bytes().collect()),
+            reader.find("b".as_bytes()).unwrap()
+        );
+        assert_eq!(
+            Some("old".bytes().collect()),
+            reader.find("c".as_bytes()).unwrap()
+        );
+        assert_eq!(
+            Some("old".bytes().collect()),
+            reader.find_at("a".as_bytes(), 3).unwrap()
+        );
+        assert_eq!(1, reader.range_tombstones().len());
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
     #[test]
     fn remove_from_reader() {
         let data_dir = "/tmp/sstable_reader_tests/remove_from_reader";
@@ -380,22 +687,175 @@ mod reader_tests {
 
         let mut config = config::Config::new();
         config.data_dir = String::from(data_dir);
+        manifest::Manifest::open(&config).unwrap();
         config.sstable_block_size = 12;
 
-        let mut memtable = memtable::Memtable::new();
-        memtable.insert("abc".bytes().collect(), Some("abc".bytes().collect()));
-        sstable::flush_to_sstable(&config, &memtable, 0).unwrap();
+        let memtable = memtable::Memtable::new();
+        memtable.insert("abc".bytes().collect(), Some("abc".bytes().collect()), 11);
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &memtable, 0).unwrap(),
+        );
 
         let mut reader = Reader::new();
-        reader.init(&config);
+        reader
+            .init(&config, &manifest::Manifest::open(&config).unwrap())
+            .unwrap();
 
         assert_eq!(1, reader.sstables.len());
-        let find1 = reader.find("abc".as_bytes());
+        let find1 = reader.find("abc".as_bytes()).unwrap();
         assert_eq!(true, find1.is_some());
 
         reader.remove_memtable(&memtable.id);
         assert_eq!(0, reader.sstables.len());
-        assert_eq!(false, reader.find("abc".as_bytes()).is_some());
+        assert_eq!(false, reader.find("abc".as_bytes()).unwrap().is_some());
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_only_loads_tables_in_the_manifest() {
+        let data_dir = "/tmp/sstable_reader_tests/it_only_loads_tables_in_the_manifest";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        manifest::Manifest::open(&config).unwrap();
+
+        let committed = memtable::Memtable::new();
+        committed.insert("a".bytes().collect(), Some("a".bytes().collect()), 1);
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &committed, 0).unwrap(),
+        );
+
+        // e.g. a flush that didn't finish before a crash
+        let uncommitted = memtable::Memtable::new();
+        uncommitted.insert("b".bytes().collect(), Some("b".bytes().collect()), 2);
+        sstable::flush_to_sstable(&config, &uncommitted, 0).unwrap();
+
+        let mut reader = Reader::new();
+        reader
+            .init(&config, &manifest::Manifest::open(&config).unwrap())
+            .unwrap();
+        assert_eq!(1, reader.sstables.len());
+        assert_eq!(None, reader.find("b".as_bytes()).unwrap());
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_returns_an_error_for_a_corrupt_block() {
+        let data_dir = "/tmp/sstable_reader_tests/it_returns_an_error_for_a_corrupt_block";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        manifest::Manifest::open(&config).unwrap();
+
+        let memtable = memtable::Memtable::new();
+        memtable.insert("a".bytes().collect(), Some("a".bytes().collect()), 1);
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &memtable, 0).unwrap(),
+        );
+        let data_file = format!("{}/sstable-data-{}", data_dir, memtable.id);
+        let len = fs::metadata(&data_file).unwrap().len() as usize;
+        fs::write(&data_file, vec![0xff; len]).unwrap();
+
+        let mut reader = Reader::new();
+        reader
+            .init(&config, &manifest::Manifest::open(&config).unwrap())
+            .unwrap();
+        let result = reader.find("a".as_bytes());
+        assert_eq!(true, matches!(result, Err(Error::Corruption(_))));
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_refuses_tables_in_an_older_format() {
+        let data_dir = "/tmp/sstable_reader_tests/it_refuses_tables_in_an_older_format";
+        fs::remove_dir_all(data_dir).ok();
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        manifest::Manifest::open(&config).unwrap();
+
+        let memtable = memtable::Memtable::new();
+        memtable.insert("a".bytes().collect(), Some("a".bytes().collect()), 1);
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &memtable, 0).unwrap(),
+        );
+        // tables written before there was a format version don't have one in their meta
+        let meta_file = format!("{}/sstable-meta-{}", data_dir, memtable.id);
+        let meta = fs::read_to_string(&meta_file).unwrap();
+        fs::write(&meta_file, meta.replace("format_version: 1\n", "")).unwrap();
+
+        let result = read_table_meta(path::Path::new(&meta_file));
+        assert_eq!(true, matches!(result, Err(Error::Corruption(_))));
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod iterator_tests {
+    use super::*;
+    use crate::sstable;
+
+    fn keys(mut iter: SstableIterator) -> Vec<String> {
+        let mut keys = vec![];
+        while let Some(entry) = iter.try_next().unwrap() {
+            keys.push(String::from_utf8(entry.key).unwrap());
+        }
+        keys
+    }
+
+    #[test]
+    fn it_can_seek_forward_and_reverse() {
+        let data_dir = "/tmp/sstable_reader_tests/it_can_seek_forward_and_reverse";
+        fs::remove_dir_all(data_dir);
+        fs::create_dir_all(data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = String::from(data_dir);
+        manifest::Manifest::open(&config).unwrap();
+        config.sstable_block_size = 12;
+
+        let memtable = memtable::Memtable::new();
+        for key in ["1bc", "1ef", "2bc", "2ef", "3bc"] {
+            memtable.insert(key.bytes().collect(), Some("abc".bytes().collect()), 12);
+        }
+        commit(
+            &config,
+            sstable::flush_to_sstable(&config, &memtable, 0).unwrap(),
+        );
+
+        let mut reader = Reader::new();
+        reader
+            .init(&config, &manifest::Manifest::open(&config).unwrap())
+            .unwrap();
+        let (table_meta, path) = &reader.sstables[0];
+
+        let iter = SstableIterator::new(path.clone(), table_meta.clone()).unwrap();
+        assert_eq!(vec!["1bc", "1ef", "2bc", "2ef", "3bc"], keys(iter));
+
+        let mut iter = SstableIterator::new(path.clone(), table_meta.clone()).unwrap();
+        iter.seek("1zz".as_bytes()).unwrap();
+        assert_eq!(vec!["2bc", "2ef", "3bc"], keys(iter));
+
+        let mut iter = SstableIterator::new_reverse(path.clone(), table_meta.clone()).unwrap();
+        iter.seek("2ef".as_bytes()).unwrap();
+        assert_eq!(vec!["2bc", "1ef", "1bc"], keys(iter));
+
+        let mut iter = SstableIterator::new_reverse(path.clone(), table_meta.clone()).unwrap();
+        iter.seek("0".as_bytes()).unwrap();
+        assert_eq!(0, keys(iter).len());
 
         fs::remove_dir_all(data_dir).unwrap();
     }
@@ -408,7 +868,7 @@ mod find_block_tests {
 
     #[test]
     fn test5() {
-        let mut table_meta = TableMeta::new(0);
+        let mut table_meta = TableMeta::new(0, 0);
         table_meta.blocks.push(BlockMeta {
             count: 10,
             size: 10,
@@ -431,7 +891,7 @@ mod find_block_tests {
 
     #[test]
     fn smoke_test() {
-        let mut table_meta = TableMeta::new(0);
+        let mut table_meta = TableMeta::new(0, 0);
         table_meta.blocks.push(BlockMeta {
             count: 10,
             size: 10,
@@ -461,7 +921,7 @@ mod find_block_tests {
 
     #[test]
     fn test2() {
-        let mut table_meta = TableMeta::new(0);
+        let mut table_meta = TableMeta::new(0, 0);
         table_meta.blocks.push(BlockMeta {
             count: 10,
             size: 10,
@@ -490,7 +950,7 @@ mod find_block_tests {
 
     #[test]
     fn test3() {
-        let mut table_meta = TableMeta::new(0);
+        let mut table_meta = TableMeta::new(0, 0);
         table_meta.blocks.push(BlockMeta {
             count: 10,
             size: 10,
@@ -519,7 +979,7 @@ mod find_block_tests {
 
     #[test]
     fn test4() {
-        let mut table_meta = TableMeta::new(0);
+        let mut table_meta = TableMeta::new(0, 0);
         table_meta.blocks.push(BlockMeta {
             count: 10,
             size: 10,
@@ -548,7 +1008,7 @@ mod find_block_tests {
 
     #[test]
     fn test6() {
-        let mut table_meta = TableMeta::new(0);
+        let mut table_meta = TableMeta::new(0, 0);
         let starts = vec!["a", "g", "j", "l", "r", "u", "z"];
         for i in 0..starts.len() {
             table_meta.blocks.push(BlockMeta {
@@ -589,89 +1049,105 @@ mod find_block_tests {
 #[derive(Debug)]
 pub struct SstableIterator {
     path: Box<path::Path>,
+    file: fs::File,
     table_meta: super::TableMeta,
     table_index: usize,
     block_index: usize,
     curr_block: Vec<super::Entry>,
+    reverse: bool,
 }
 
 impl SstableIterator {
-    pub fn new(path: Box<path::Path>, table_meta: super::TableMeta) -> Self {
-        SstableIterator {
+    // the file is opened when the iterator is created, so that the iterator can keep reading the
+    // table even if it gets compacted & deleted while the iterator is in use
+    pub fn new(path: Box<path::Path>, table_meta: super::TableMeta) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().read(true).open(&path)?;
+        Ok(SstableIterator {
             path,
+            file,
             table_meta,
             table_index: 0,
             block_index: 0,
             curr_block: vec![],
-        }
+            reverse: false,
+        })
     }
 
-    fn goto_next_block(&mut self) -> io::Result<()> {
-        let block = &self.table_meta.blocks[self.table_index];
-        self.table_index += 1;
-        let bytes1 = super::reader::deserialize_block(&self.path, block).unwrap();
-        let mut bytes = bytes1.into_iter().map(|b| Ok::<u8, io::Error>(b));
-
-        let mut next_block = vec![];
-        loop {
-            let flags_1_option = bytes.next();
-            if flags_1_option.is_none() {
-                break;
-            }
-
-            let flags_1 = flags_1_option.unwrap().unwrap();
-            let deleted = flags_1 & (1 << 6) > 0;
+    // iterate the table in descending key order
+    pub fn new_reverse(path: Box<path::Path>, table_meta: super::TableMeta) -> io::Result<Self> {
+        let mut iter = SstableIterator::new(path, table_meta)?;
+        iter.reverse = true;
+        iter.table_index = iter.table_meta.blocks.len();
+        Ok(iter)
+    }
 
-            let key_length = ((bytes.next().unwrap()? as u32) << 24)
-                + ((bytes.next().unwrap()? as u32) << 16)
-                + ((bytes.next().unwrap()? as u32) << 8)
-                + (bytes.next().unwrap()? as u32);
+    // skip ahead so the next entry returned is the first entry with key >= start (or, for a
+    // reverse iterator, the first entry with key < start). Blocks that can't contain the key are
+    // never read from disk
+    pub fn seek(&mut self, start: &[u8]) -> io::Result<()> {
+        if self.table_meta.blocks.is_empty() {
+            return Ok(());
+        }
 
-            let mut key: Vec<u8> = Vec::with_capacity(key_length as usize);
-            for _ in 0..key_length {
-                key.push(bytes.next().unwrap()?);
+        let block = find_block(start, &self.table_meta);
+        if self.reverse {
+            match block {
+                // the key is before the first block, so no entries are less than the key
+                None => {
+                    self.table_index = 0;
+                    self.curr_block = vec![];
+                    self.block_index = 0;
+                    return Ok(());
+                }
+                Some(block) => {
+                    self.load_block(block)?;
+                    self.table_index = block;
+                }
             }
-
-            let mut value_length = 0;
-            if !deleted {
-                value_length = ((bytes.next().unwrap()? as u32) << 24)
-                    + ((bytes.next().unwrap()? as u32) << 16)
-                    + ((bytes.next().unwrap()? as u32) << 8)
-                    + (bytes.next().unwrap()? as u32);
+            while self.block_index < self.curr_block.len()
+                && *self.curr_block[self.block_index].key >= *start
+            {
+                self.block_index += 1;
             }
-
-            let mut value = Vec::with_capacity(value_length as usize);
-            for _ in 0..value_length {
-                value.push(bytes.next().unwrap()?);
+        } else {
+            let block = block.unwrap_or(0);
+            self.load_block(block)?;
+            self.table_index = block + 1;
+            while self.block_index < self.curr_block.len()
+                && *self.curr_block[self.block_index].key < *start
+            {
+                self.block_index += 1;
             }
-
-            let entry = super::Entry {
-                flags: flags_1,
-                deleted,
-                key,
-                key_length,
-                value,
-                value_length,
-            };
-            next_block.push(entry);
         }
 
-        self.curr_block = next_block;
-        self.block_index = 0;
-
         Ok(())
     }
-}
-
-impl Iterator for SstableIterator {
-    type Item = super::Entry;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.block_index >= self.curr_block.len() {
+    fn next_block_index(&mut self) -> Option<usize> {
+        if self.reverse {
+            if self.table_index == 0 {
+                return None;
+            }
+            self.table_index -= 1;
+            Some(self.table_index)
+        } else {
             if self.table_index >= self.table_meta.blocks.len() {
                 return None;
             }
-            self.goto_next_block().unwrap();
+            self.table_index += 1;
+            Some(self.table_index - 1)
+        }
+    }
+
+    // like next, but returns an error instead of panicking if a block can't be read
+    pub fn try_next(&mut self) -> Result<Option<super::Entry>> {
+        while self.block_index >= self.curr_block.len() {
+            let index = match self.next_block_index() {
+                Some(index) => index,
+                None => return Ok(None),
+            };
+            self.load_block(index)
+                .map_err(|err| table_error(&self.path, err))?;
         }
 
         let entry = std::mem::replace(
@@ -682,11 +1158,29 @@ impl Iterator for SstableIterator {
                 key_length: 0,
                 value_length: 0,
                 flags: 0,
+                seq: 0,
                 deleted: false,
+                expires_at: None,
             },
         );
         self.block_index += 1;
 
-        Some(entry)
+        Ok(Some(entry))
+    }
+
+    fn load_block(&mut self, index: usize) -> io::Result<()> {
+        let block = &self.table_meta.blocks[index];
+        let bytes = read_block(&mut self.file, block)?;
+        let mut next_block = decode_block(&bytes)?;
+
+        // the versions of each key stay newest first when iterating in reverse. The sort is
+        // stable so it keeps the order of entries with the same key
+        if self.reverse {
+            next_block.sort_by(|a, b| b.key.cmp(&a.key));
+        }
+        self.curr_block = next_block;
+        self.block_index = 0;
+
+        Ok(())
     }
 }
@@ -3,217 +3,768 @@ use std::fs;
 use std::io;
 use std::io::{Read, Write};
 use std::path;
+use std::sync::Arc;
 
+use crate::batch;
+use crate::config;
+use crate::manifest;
 use crate::memtable;
 
+mod sync;
+pub use sync::SyncWaiter;
+
 #[derive(Debug)]
 struct WriteEntry {
     flags: u8,
+    seq: u64,
     key_length: u32,
     key: Vec<u8>,
     value_length: u32,
     value: Vec<u8>,
 }
 
+#[derive(Debug)]
+struct RecordHeader {
+    length: u32,
+    crc: u32,
+}
+
+const RECORD_HEADER_SIZE: usize = 8;
+
+// flag set on entries that are deletes
+const FLAG_DELETE: u8 = 1 << 6;
+
+// flag set on records that contain a batch of entries
+const FLAG_BATCH: u8 = 1 << 5;
+
+// flag set on entries that delete a range of keys. The key is the start of the range and the value
+// is the end
+const FLAG_RANGE_DELETE: u8 = 1 << 4;
+
+// flag set on entries with an expiry, which follows the sequence number
+const FLAG_EXPIRES: u8 = 1 << 3;
+
+// an entry read back from a WAL
+#[derive(Debug)]
+enum Mutation {
+    Write(memtable::Version),
+    DeleteRange(memtable::RangeTombstone),
+}
+
 pub struct Wal {
     pub id: String,
+    path: Box<path::Path>,
     file: fs::File,
+    sync_mode: config::WalSyncMode,
+    sync_state: Arc<sync::SyncState>,
+    // set once a write fails. The record may have been partly written, and recovery stops at the
+    // first bad record, so anything written after it would be lost
+    failed: bool,
 }
 
 impl Wal {
-    pub fn new(id: String) -> Self {
-        let filename = wal_filename(&id);
+    // create a new WAL for the memtable with the given id. seq must be greater than the seq of
+    // every other WAL so that recovery can replay them in the order they were written
+    pub fn new(config: &config::Config, seq: u64, id: String) -> io::Result<Self> {
+        let filename = wal_filename(config, seq, &id);
         let path = path::Path::new(&filename);
         let file = fs::OpenOptions::new()
             .append(true)
             .create(true)
-            .open(path)
-            .unwrap();
-        Wal { file, id }
+            .open(path)?;
+        let sync_state = sync::SyncState::new(file.try_clone()?, &config.wal_sync_mode);
+        Ok(Wal {
+            file,
+            id,
+            path: path.to_path_buf().into_boxed_path(),
+            sync_mode: config.wal_sync_mode.clone(),
+            sync_state,
+            failed: false,
+        })
     }
 
-    pub fn write(&mut self, key: &[u8], value: Option<&[u8]>) -> io::Result<u32> {
-        let mut write_entry = WriteEntry {
-            flags: 0,
-            key_length: key.len() as u32,
-            key: key.to_owned(),
-            value_length: 0,
-            value: vec![],
-        };
+    // write the key and value to the WAL with the sequence number of the write. Returns a waiter
+    // that can be used to block until the write is durable. In `always` sync mode the write is
+    // already durable when this returns
+    pub fn write(&mut self, key: &[u8], value: Option<&[u8]>, seq: u64) -> io::Result<SyncWaiter> {
+        self.write_expiring(key, value, seq, None)
+    }
+
+    // like write, for a value that expires at expires_at (millis since the epoch)
+    pub fn write_expiring(
+        &mut self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        seq: u64,
+        expires_at: Option<u64>,
+    ) -> io::Result<SyncWaiter> {
+        let mut payload = vec![];
+        encode_entry(&mut payload, 0, key, value, seq, expires_at);
+        self.write_record(&payload)
+    }
+
+    // write a tombstone deleting every key in [start, end) written before seq
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8], seq: u64) -> io::Result<SyncWaiter> {
+        let mut payload = vec![];
+        encode_entry(&mut payload, FLAG_RANGE_DELETE, start, Some(end), seq, None);
+        self.write_record(&payload)
+    }
+
+    // write all the operations in the batch as a single record, so that recovery will either
+    // replay the whole batch or none of it. The operations get consecutive sequence numbers
+    // starting at first_seq
+    pub fn write_batch(
+        &mut self,
+        batch: &batch::WriteBatch,
+        first_seq: u64,
+    ) -> io::Result<SyncWaiter> {
+        let mut payload = vec![FLAG_BATCH];
+        payload.extend_from_slice(&(batch.len() as u32).to_be_bytes());
+        for (i, (key, value)) in batch.iter().enumerate() {
+            encode_entry(
+                &mut payload,
+                0,
+                key,
+                value.as_deref(),
+                first_seq + i as u64,
+                None,
+            );
+        }
+        self.write_record(&payload)
+    }
+
+    // block until everything written so far is durable
+    pub fn sync(&self) -> io::Result<()> {
+        self.sync_state.sync_to(self.sync_state.written(), true)
+    }
 
-        if value.is_none() {
-            write_entry.flags += 1 << 6;
-        } else {
-            write_entry.value_length = value.unwrap().len() as u32;
-            write_entry.value = value.unwrap().clone().to_owned();
+    // returns a waiter for everything written so far
+    pub fn sync_waiter(&self) -> SyncWaiter {
+        SyncWaiter::new(
+            self.sync_state.clone(),
+            self.sync_state.written(),
+            &self.sync_mode,
+        )
+    }
+
+    // every record is written as the length of the payload, the CRC32 of the payload and then the
+    // payload itself
+    fn write_record(&mut self, payload: &[u8]) -> io::Result<SyncWaiter> {
+        if let Some(err) = self.error() {
+            return Err(io::Error::other(err));
         }
+        let header = RecordHeader {
+            length: payload.len() as u32,
+            crc: crc32fast::hash(payload),
+        };
 
-        self.file.write(&[
-            // write flags
-            write_entry.flags,
-            // write key length
-            (write_entry.key_length >> 24) as u8,
-            (write_entry.key_length >> 16) as u8,
-            (write_entry.key_length >> 8) as u8,
-            write_entry.key_length as u8,
-        ])?;
-
-        if value.is_some() {
-            self.file.write(&[
-                // write value length
-                (write_entry.value_length >> 24) as u8,
-                (write_entry.value_length >> 16) as u8,
-                (write_entry.value_length >> 8) as u8,
-                write_entry.value_length as u8,
-            ])?;
+        let mut buffer = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        buffer.extend_from_slice(&header.length.to_be_bytes());
+        buffer.extend_from_slice(&header.crc.to_be_bytes());
+        buffer.extend_from_slice(payload);
+
+        if let Err(err) = self.file.write_all(&buffer).and_then(|_| self.file.flush()) {
+            log::error!("error writing to WAL {:?}: {:?}", self.path, err);
+            self.failed = true;
+            return Err(err);
+        }
+        let offset = self.sync_state.add_written(buffer.len() as u64);
+        if self.sync_mode == config::WalSyncMode::Always {
+            self.sync_state.sync_to(offset, true)?;
         }
-        // write key and value
-        self.file.write(&write_entry.key)?;
+        Ok(SyncWaiter::new(
+            self.sync_state.clone(),
+            offset,
+            &self.sync_mode,
+        ))
+    }
 
-        if value.is_some() {
-            self.file.write(&write_entry.value)?;
+    // why nothing more can be written to the WAL, if a write or sync has failed
+    pub fn error(&self) -> Option<String> {
+        if self.failed {
+            return Some(format!("an earlier write to WAL {:?} failed", self.path));
         }
+        self.sync_state.error()
+    }
 
-        self.file.flush()?;
-        return Ok(1); // TODO return how many bytes we actually wrote
+    // make every write from now on fail, as if the disk had filled up
+    #[cfg(test)]
+    pub(crate) fn fail_writes(&mut self) {
+        self.file = fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/full")
+            .unwrap();
     }
 
     pub fn delete(&self) -> io::Result<bool> {
-        let filename = wal_filename(&self.id);
-        let path = path::Path::new(&filename);
-        fs::remove_file(path)?;
+        fs::remove_file(&self.path)?;
         return Ok(true);
     }
 }
 
-fn wal_filename(id: &str) -> String {
-    let filename = format!("/tmp/wal-{}", id);
+fn encode_entry(
+    buffer: &mut Vec<u8>,
+    flags: u8,
+    key: &[u8],
+    value: Option<&[u8]>,
+    seq: u64,
+    expires_at: Option<u64>,
+) {
+    let mut write_entry = WriteEntry {
+        flags,
+        seq,
+        key_length: key.len() as u32,
+        key: key.to_owned(),
+        value_length: 0,
+        value: vec![],
+    };
+
+    if let Some(value) = value {
+        write_entry.value_length = value.len() as u32;
+        write_entry.value = value.to_owned();
+    } else {
+        write_entry.flags += FLAG_DELETE;
+    }
+    if expires_at.is_some() {
+        write_entry.flags += FLAG_EXPIRES;
+    }
+
+    buffer.push(write_entry.flags);
+    buffer.extend_from_slice(&write_entry.seq.to_be_bytes());
+    if let Some(expires_at) = expires_at {
+        buffer.extend_from_slice(&expires_at.to_be_bytes());
+    }
+    buffer.extend_from_slice(&write_entry.key_length.to_be_bytes());
+    if value.is_some() {
+        buffer.extend_from_slice(&write_entry.value_length.to_be_bytes());
+    }
+    buffer.extend_from_slice(&write_entry.key);
+    if value.is_some() {
+        buffer.extend_from_slice(&write_entry.value);
+    }
+}
+
+// delete the WAL for the memtable with the given id
+pub fn delete_by_id(config: &config::Config, id: &str) -> io::Result<()> {
+    for (_, wal_id, path) in list_wals(config)? {
+        if wal_id == id {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// check if there is a WAL for the memtable with the given id
+pub fn exists(config: &config::Config, id: &str) -> bool {
+    match list_wals(config) {
+        Ok(wals) => wals.iter().any(|(_, wal_id, _)| wal_id == id),
+        Err(_) => false,
+    }
+}
+
+// WALs are named with a sequence number followed by the id of the memtable. The sequence number
+// is zero padded so the files also sort in the order they were created
+fn wal_filename(config: &config::Config, seq: u64, id: &str) -> String {
+    let filename = format!("{}/wal-{:020}-{}", config.wal_dir(), seq, id);
     return filename;
 }
 
+// list the WALs in the WAL directory ordered by sequence number (oldest first). Returns the
+// sequence number, memtable id and path of each WAL. A WAL that isn't named this way was written by
+// an older version, and is an error rather than skipped, so its writes aren't lost
+pub fn list_wals(config: &config::Config) -> io::Result<Vec<(u64, String, Box<path::Path>)>> {
+    let re = Regex::new(r"^wal-(\d+)-(.+)$").unwrap();
+    let mut wals = vec![];
+    for file in fs::read_dir(config.wal_dir())? {
+        let path = file?.path();
+        let filename = match path.file_name().and_then(|f| f.to_str()) {
+            Some(filename) => filename.to_owned(),
+            None => continue,
+        };
+        if !filename.starts_with("wal-") {
+            continue;
+        }
+        let parsed = re
+            .captures(&filename)
+            .and_then(|captures| Some((captures[1].parse().ok()?, captures[2].to_owned())));
+        match parsed {
+            Some((seq, id)) => wals.push((seq, id, path.into_boxed_path())),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unrecognized WAL {:?}", path),
+                ))
+            }
+        }
+    }
+    wals.sort_by_key(|(seq, _, _)| *seq);
+    Ok(wals)
+}
+
 pub struct WalRecovery {
-    pub writable_memtable: Option<memtable::Memtable>,
-    pub flushing_memtables: Vec<memtable::Memtable>,
+    pub writable_memtable: memtable::Memtable,
+    pub writable_wal: Wal,
+    // sequence number to use for the next WAL that gets created
+    pub next_wal_seq: u64,
+    // highest sequence number of any write found in the WALs
+    pub max_seq: u64,
 }
 
-pub fn recover() -> io::Result<WalRecovery> {
-    let data_dir = "/tmp"; // TODO not have this hard-coded (read from config)
+pub fn recover(config: &config::Config, manifest: &manifest::Manifest) -> io::Result<WalRecovery> {
+    fs::create_dir_all(config.wal_dir())?;
+    let wals = list_wals(config)?;
+    let recovery_seq = wals.last().map_or(0, |(seq, _, _)| seq + 1);
 
-    // for any memtable that was writable during the last shutdown, we'll add
+    // for any memtable that wasn't flushed before the last shutdown, we'll add
     // values into this new memtable and also create a new recovery wal for the
-    // memtable. we'll also be deleting the old memtables as we go
-    let mut writable_memtable = memtable::Memtable::new();
-    let mut recovery_wal = Wal::new(writable_memtable.id.clone());
-    let recovery_wal_filename = &wal_filename(&writable_memtable.id);
-
-    let mut flushing_memtables = vec![];
-
-    for file in fs::read_dir(data_dir).unwrap() {
-        let path: Box<path::Path> = file.unwrap().path().into_boxed_path();
-        if is_wal(&path) {
-            if path::Path::new(&recovery_wal_filename) == &*path {
-                continue;
-            }
+    // memtable. we'll also be deleting the old memtables as we go. The WALs are
+    // replayed oldest to newest so newer values overwrite older ones
+    let writable_memtable = memtable::Memtable::with_rep(&config.memtable_rep);
+    let mut recovery_wal = Wal::new(config, recovery_seq, writable_memtable.id.clone())?;
 
-            let memtable = recover_memtable(&path)?;
-            let flushing = is_flushing(&path);
+    let mut max_seq = 0;
 
-            log::debug!(
-                "recovered memtable. num_records = {:?}, path = {:?}, flushing = {:?}",
-                memtable.size(),
-                path,
-                flushing
-            );
-            if !flushing {
-                if writable_memtable.size() > 0 {
-                    // this is because we don't know which WAL was for more recent
-                    // data, so data from the older one could overwrite the newer
-                    // TODO actually fix this somehow
-                    log::warn!("recovered more than one memtable via WAL that were not in process of flushing - this could lead to invalid recovery state");
-                }
+    for (_, id, path) in wals {
+        // the memtable's sstable was added to the MANIFEST, but the database shut down before
+        // the WAL was deleted
+        if manifest.contains(&id) {
+            log::debug!("removing WAL of flushed memtable. path = {:?}", path);
+            fs::remove_file(path)?;
+            continue;
+        }
 
-                memtable.into_iter().for_each(|(k, v)| {
-                    if v.is_none() {
-                        recovery_wal.write(&k, None).unwrap();
-                    } else {
-                        recovery_wal.write(&k, Some(v.as_ref().unwrap())).unwrap();
-                    }
-                    writable_memtable.insert(k, v);
-                });
-                fs::remove_file(path)?;
-            } else {
-                flushing_memtables.push(memtable);
-            }
+        // a memtable that was part way through flushing is recovered like any other. Its
+        // sstable isn't in the MANIFEST, so it isn't live
+        let memtable = recover_memtable(&path)?;
+        log::debug!(
+            "recovered memtable. num_records = {:?}, path = {:?}",
+            memtable.size(),
+            path,
+        );
+        max_seq = max_seq.max(memtable.max_seq());
+
+        // keep the original sequence numbers so the recovered values still order correctly
+        // against values in the sstables
+        for tombstone in memtable.range_tombstones() {
+            recovery_wal.delete_range(&tombstone.start, &tombstone.end, tombstone.seq)?;
+            writable_memtable.add_range_tombstone(tombstone);
+        }
+        for version in memtable.into_iter() {
+            recovery_wal.write_expiring(
+                &version.key,
+                version.value.as_deref(),
+                version.seq,
+                version.expires_at,
+            )?;
+            writable_memtable.insert_version(version, &[]);
         }
+        // make sure the recovered values are durable before removing the old WAL
+        recovery_wal.sync()?;
+        fs::remove_file(path)?;
     }
 
     Ok(WalRecovery {
-        writable_memtable: Some(writable_memtable),
-        flushing_memtables,
+        writable_memtable,
+        writable_wal: recovery_wal,
+        next_wal_seq: recovery_seq + 1,
+        max_seq,
     })
 }
 
-// check if the file at this path is a WAL
-fn is_wal(path: &path::Path) -> bool {
-    let re = regex::Regex::new(r".*/wal-.*$").unwrap();
-    return re.is_match(path.to_str().unwrap());
-}
+// replay the records in the WAL into a new memtable. Replay stops at the first record that is
+// incomplete or fails its checksum (e.g. because the database crashed in the middle of writing
+// it) and the WAL is truncated to the end of the last good record
+fn recover_memtable(path: &path::Path) -> io::Result<memtable::Memtable> {
+    let memtable = memtable::Memtable::new();
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let mut contents = vec![];
+    file.read_to_end(&mut contents)?;
+
+    let mut offset = 0;
+    while offset < contents.len() {
+        match read_record(&contents[offset..]) {
+            Ok((mutations, record_length)) => {
+                for mutation in mutations {
+                    match mutation {
+                        Mutation::Write(version) => memtable.insert_version(version, &[]),
+                        Mutation::DeleteRange(tombstone) => memtable.add_range_tombstone(tombstone),
+                    }
+                }
+                offset += record_length;
+            }
+            Err(err) => {
+                log::warn!(
+                    "invalid record in WAL {:?} at offset {}: {}",
+                    path,
+                    offset,
+                    err
+                );
+                break;
+            }
+        }
+    }
+
+    if offset < contents.len() {
+        log::warn!(
+            "truncating WAL {:?} to {} bytes, dropped {} bytes",
+            path,
+            offset,
+            contents.len() - offset
+        );
+        file.set_len(offset as u64)?;
+        file.sync_all()?;
+    }
 
-// check if the path was in the process of flushing when the database shut down last
-fn is_flushing(wal_path: &path::Path) -> bool {
-    let sstable_data_path = String::from(
-        Regex::new("wal-")
-            .unwrap()
-            .replace(wal_path.to_str().unwrap(), "sstable-data-"),
-    );
-    let is_flushing = fs::metadata(&path::Path::new(&sstable_data_path)).is_ok();
-    return is_flushing;
+    Ok(memtable)
 }
 
-fn recover_memtable(path: &path::Path) -> io::Result<memtable::Memtable> {
-    let mut memtable = memtable::Memtable::new();
-    let file = fs::OpenOptions::new().read(true).open(path)?;
-    let mut bytes = file.bytes();
-
-    loop {
-        let flags_1_o = bytes.next();
-        if flags_1_o.is_none() {
-            return Ok(memtable);
+// read one record from the start of bytes. Returns the entries in the record and the total
+// length of the record including the header
+fn read_record(bytes: &[u8]) -> io::Result<(Vec<Mutation>, usize)> {
+    let mut remaining = bytes;
+    let header = RecordHeader {
+        length: read_u32(&mut remaining)?,
+        crc: read_u32(&mut remaining)?,
+    };
+    let payload = take(&mut remaining, header.length as usize)?;
+    if crc32fast::hash(payload) != header.crc {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record checksum mismatch",
+        ));
+    }
+
+    let mut payload = payload;
+    let mut entries = vec![];
+    if payload.first() == Some(&FLAG_BATCH) {
+        take(&mut payload, 1)?;
+        let count = read_u32(&mut payload)?;
+        for _ in 0..count {
+            entries.push(read_entry(&mut payload)?);
         }
+    } else {
+        entries.push(read_entry(&mut payload)?);
+    }
 
-        let flags_1 = flags_1_o.unwrap()?;
-        let delete = flags_1 & (1 << 6) > 0;
+    Ok((entries, RECORD_HEADER_SIZE + header.length as usize))
+}
 
-        let key_length = ((bytes.next().unwrap()? as u32) << 24)
-            + ((bytes.next().unwrap()? as u32) << 16)
-            + ((bytes.next().unwrap()? as u32) << 8)
-            + (bytes.next().unwrap()? as u32);
+// take the next n bytes from the front of the slice
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if bytes.len() < n {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "record is incomplete",
+        ));
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
 
-        let mut value_length = 0;
-        if !delete {
-            value_length = ((bytes.next().unwrap()? as u32) << 24)
-                + ((bytes.next().unwrap()? as u32) << 16)
-                + ((bytes.next().unwrap()? as u32) << 8)
-                + (bytes.next().unwrap()? as u32);
-        }
+fn read_u32(bytes: &mut &[u8]) -> io::Result<u32> {
+    let value = take(bytes, 4)?;
+    Ok(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+}
 
-        let mut key: Vec<u8> = Vec::with_capacity(key_length as usize);
-        for _ in 0..key_length {
-            key.push(bytes.next().unwrap()?);
-        }
+fn read_u64(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut value = [0; 8];
+    value.copy_from_slice(take(bytes, 8)?);
+    Ok(u64::from_be_bytes(value))
+}
 
-        let mut value = vec![];
-        if !delete {
-            value = Vec::with_capacity(value_length as usize);
-            for _ in 0..value_length {
-                value.push(bytes.next().unwrap()?);
-            }
-        }
+// read one entry. The value of a returned version is None if the entry is a delete
+fn read_entry(bytes: &mut &[u8]) -> io::Result<Mutation> {
+    let flags = take(bytes, 1)?[0];
+    let delete = flags & FLAG_DELETE > 0;
+    let seq = read_u64(bytes)?;
+    let mut expires_at = None;
+    if flags & FLAG_EXPIRES > 0 {
+        expires_at = Some(read_u64(bytes)?);
+    }
 
-        if delete {
-            memtable.insert(key, None);
-        } else {
-            memtable.insert(key, Some(value));
-        }
+    let key_length = read_u32(bytes)?;
+
+    let mut value_length = 0;
+    if !delete {
+        value_length = read_u32(bytes)?;
+    }
+
+    let key = take(bytes, key_length as usize)?.to_vec();
+    let mut value = None;
+    if !delete {
+        value = Some(take(bytes, value_length as usize)?.to_vec());
+    }
+
+    if flags & FLAG_RANGE_DELETE > 0 {
+        return Ok(Mutation::DeleteRange(memtable::RangeTombstone {
+            start: key,
+            end: value.unwrap_or_default(),
+            seq,
+        }));
+    }
+    Ok(Mutation::Write(memtable::Version {
+        key,
+        value,
+        seq,
+        expires_at,
+    }))
+}
+
+#[cfg(test)]
+mod wal_tests {
+    use super::*;
+
+    fn test_config(test_name: &str) -> config::Config {
+        let data_dir = format!("/tmp/wal_tests/{}", test_name);
+        fs::remove_dir_all(&data_dir).ok();
+        fs::create_dir_all(&data_dir).unwrap();
+
+        let mut config = config::Config::new();
+        config.data_dir = data_dir;
+        config
+    }
+
+    #[test]
+    fn it_can_recover_writes_and_batches() {
+        let config = test_config("it_can_recover_writes_and_batches");
+        let mut wal = Wal::new(&config, 0, String::from("1")).unwrap();
+        wal.write("a".as_bytes(), Some("1".as_bytes()), 1).unwrap();
+
+        let mut batch = batch::WriteBatch::new();
+        batch.put("b".as_bytes(), "2".as_bytes());
+        batch.delete("a".as_bytes());
+        batch.put("c".as_bytes(), "3".as_bytes());
+        wal.write_batch(&batch, 2).unwrap();
+
+        let memtable = recover_memtable(&wal.path).unwrap();
+        assert_eq!((None, true), memtable.search("a".as_bytes()));
+        assert_eq!(
+            (Some("2".bytes().collect()), true),
+            memtable.search("b".as_bytes())
+        );
+        assert_eq!(
+            (Some("3".bytes().collect()), true),
+            memtable.search("c".as_bytes())
+        );
+        assert_eq!(4, memtable.max_seq());
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_can_recover_range_deletes() {
+        let config = test_config("it_can_recover_range_deletes");
+        let mut wal = Wal::new(&config, 0, String::from("1")).unwrap();
+        wal.write("b".as_bytes(), Some("1".as_bytes()), 1).unwrap();
+        wal.delete_range("a".as_bytes(), "c".as_bytes(), 2).unwrap();
+        wal.write("c".as_bytes(), Some("3".as_bytes()), 3).unwrap();
+
+        let memtable = recover_memtable(&wal.path).unwrap();
+        assert_eq!((None, true), memtable.search("b".as_bytes()));
+        assert_eq!(
+            (Some("3".bytes().collect()), true),
+            memtable.search("c".as_bytes())
+        );
+        assert_eq!(
+            vec![memtable::RangeTombstone {
+                start: "a".bytes().collect(),
+                end: "c".bytes().collect(),
+                seq: 2,
+            }],
+            memtable.range_tombstones()
+        );
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_can_recover_expiring_writes() {
+        let config = test_config("it_can_recover_expiring_writes");
+        let mut wal = Wal::new(&config, 0, String::from("1")).unwrap();
+        wal.write_expiring("a".as_bytes(), Some("1".as_bytes()), 1, Some(1))
+            .unwrap();
+        wal.write_expiring("b".as_bytes(), Some("2".as_bytes()), 2, Some(u64::MAX))
+            .unwrap();
+
+        let memtable = recover_memtable(&wal.path).unwrap();
+        assert_eq!((None, true), memtable.search("a".as_bytes()));
+        assert_eq!(
+            (Some("2".bytes().collect()), true),
+            memtable.search("b".as_bytes())
+        );
+        let expiries: Vec<Option<u64>> = memtable.iter().map(|v| v.expires_at).collect();
+        assert_eq!(vec![Some(1), Some(u64::MAX)], expiries);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_truncates_records_that_fail_the_checksum() {
+        let config = test_config("it_truncates_records_that_fail_the_checksum");
+        let mut wal = Wal::new(&config, 0, String::from("1")).unwrap();
+        wal.write("a".as_bytes(), Some("1".as_bytes()), 1).unwrap();
+        let good_length = fs::metadata(&wal.path).unwrap().len();
+        wal.write("b".as_bytes(), Some("2".as_bytes()), 2).unwrap();
+
+        // corrupt the last byte of the value in the second record
+        let mut contents = fs::read(&wal.path).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        fs::write(&wal.path, &contents).unwrap();
+
+        let memtable = recover_memtable(&wal.path).unwrap();
+        assert_eq!(1, memtable.size());
+        assert_eq!((None, false), memtable.search("b".as_bytes()));
+        assert_eq!(good_length, fs::metadata(&wal.path).unwrap().len());
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_skips_incomplete_batches() {
+        let config = test_config("it_skips_incomplete_batches");
+        let mut wal = Wal::new(&config, 0, String::from("1")).unwrap();
+        wal.write("a".as_bytes(), Some("1".as_bytes()), 1).unwrap();
+        let good_length = fs::metadata(&wal.path).unwrap().len();
+
+        let mut batch = batch::WriteBatch::new();
+        batch.put("b".as_bytes(), "2".as_bytes());
+        batch.put("c".as_bytes(), "3".as_bytes());
+        wal.write_batch(&batch, 2).unwrap();
+
+        // simulate a crash part way through writing the batch
+        let len = fs::metadata(&wal.path).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(&wal.path).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let memtable = recover_memtable(&wal.path).unwrap();
+        assert_eq!(1, memtable.size());
+        assert_eq!((None, false), memtable.search("b".as_bytes()));
+        assert_eq!(good_length, fs::metadata(&wal.path).unwrap().len());
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_recovers_wals_oldest_to_newest() {
+        let mut config = test_config("it_recovers_wals_oldest_to_newest");
+        config.wal_dir = Some(format!("{}/wal", config.data_dir));
+        fs::create_dir_all(config.wal_dir()).unwrap();
+
+        // the newer WAL has the id that sorts first, so only the sequence numbers give the order
+        let mut newer = Wal::new(&config, 11, String::from("1")).unwrap();
+        newer
+            .write("a".as_bytes(), Some("new".as_bytes()), 3)
+            .unwrap();
+        let mut older = Wal::new(&config, 10, String::from("2")).unwrap();
+        older
+            .write("a".as_bytes(), Some("old".as_bytes()), 1)
+            .unwrap();
+        older
+            .write("b".as_bytes(), Some("old".as_bytes()), 2)
+            .unwrap();
+
+        let manifest = manifest::Manifest::open(&config).unwrap();
+        let recovery = recover(&config, &manifest).unwrap();
+        let memtable = recovery.writable_memtable;
+        assert_eq!(
+            (Some("new".bytes().collect()), true),
+            memtable.search("a".as_bytes())
+        );
+        assert_eq!(
+            (Some("old".bytes().collect()), true),
+            memtable.search("b".as_bytes())
+        );
+        assert_eq!(13, recovery.next_wal_seq);
+        assert_eq!(3, recovery.max_seq);
+
+        // the old WALs were replaced by the recovery WAL
+        let wals = list_wals(&config).unwrap();
+        assert_eq!(1, wals.len());
+        assert_eq!(12, wals[0].0);
+        assert_eq!(memtable.id, wals[0].1);
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_refuses_writes_after_one_fails() {
+        let config = test_config("it_refuses_writes_after_one_fails");
+        let mut wal = Wal::new(&config, 0, String::from("1")).unwrap();
+        wal.write("a".as_bytes(), Some("1".as_bytes()), 1).unwrap();
+        wal.fail_writes();
+        assert_eq!(
+            true,
+            wal.write("b".as_bytes(), Some("2".as_bytes()), 2).is_err()
+        );
+        assert_eq!(true, wal.error().is_some());
+
+        // even once the disk has space again, a write would land after the torn record
+        wal.file = fs::OpenOptions::new().append(true).open(&wal.path).unwrap();
+        assert_eq!(
+            true,
+            wal.write("c".as_bytes(), Some("3".as_bytes()), 3).is_err()
+        );
+        let memtable = recover_memtable(&wal.path).unwrap();
+        assert_eq!(1, memtable.size());
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_refuses_wals_it_cant_recognize() {
+        let config = test_config("it_refuses_wals_it_cant_recognize");
+        Wal::new(&config, 0, String::from("1")).unwrap();
+        // WALs used to be named after the memtable only
+        fs::write(format!("{}/wal-2", config.wal_dir()), "").unwrap();
+
+        let err = list_wals(&config).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn it_removes_wals_of_flushed_memtables() {
+        let config = test_config("it_removes_wals_of_flushed_memtables");
+
+        // the memtable for this WAL was flushed and added to the MANIFEST
+        let mut flushed = Wal::new(&config, 0, String::from("1")).unwrap();
+        flushed
+            .write("a".as_bytes(), Some("flushed".as_bytes()), 1)
+            .unwrap();
+        let mut manifest = manifest::Manifest::open(&config).unwrap();
+        let file_meta = manifest::FileMeta {
+            id: String::from("1"),
+            level: 0,
+            smallest_key: "a".bytes().collect(),
+            largest_key: "a".bytes().collect(),
+            max_seq: 1,
+        };
+        manifest
+            .apply(manifest::VersionEdit {
+                added: vec![file_meta],
+                removed: vec![],
+            })
+            .unwrap();
+
+        // this memtable's flush never finished, so it has to be recovered
+        let mut unflushed = Wal::new(&config, 1, String::from("2")).unwrap();
+        unflushed
+            .write("b".as_bytes(), Some("unflushed".as_bytes()), 2)
+            .unwrap();
+
+        let recovery = recover(&config, &manifest).unwrap();
+        let memtable = recovery.writable_memtable;
+        assert_eq!((None, false), memtable.search("a".as_bytes()));
+        assert_eq!(
+            (Some("unflushed".bytes().collect()), true),
+            memtable.search("b".as_bytes())
+        );
+        assert_eq!(false, exists(&config, "1"));
+        assert_eq!(false, exists(&config, "2"));
+
+        fs::remove_dir_all(&config.data_dir).unwrap();
     }
 }
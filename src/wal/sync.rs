@@ -0,0 +1,200 @@
+// Tracks how much of a WAL has been written and how much of it has been synced to stable
+// storage, so that writers can find out when their write is durable.
+//
+// In group mode there is no background thread. The first writer to wait becomes the leader and
+// calls fsync for everything written so far, while writers that arrive in the meantime wait for
+// the next sync. This way many concurrent writers share a single fsync.
+
+use std::fs;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time;
+
+use crate::config;
+
+struct Progress {
+    // number of bytes written to the WAL
+    written: u64,
+    // number of bytes that are known to be on stable storage
+    synced: u64,
+    // true while some thread is calling fsync
+    syncing: bool,
+    // set if an fsync failed. Nothing written after the last successful sync can be considered
+    // durable after this happens
+    error: Option<String>,
+}
+
+pub struct SyncState {
+    file: fs::File,
+    progress: Mutex<Progress>,
+    synced_cond: Condvar,
+}
+
+impl SyncState {
+    pub fn new(file: fs::File, sync_mode: &config::WalSyncMode) -> Arc<Self> {
+        let state = Arc::new(SyncState {
+            file,
+            progress: Mutex::new(Progress {
+                written: 0,
+                synced: 0,
+                syncing: false,
+                error: None,
+            }),
+            synced_cond: Condvar::new(),
+        });
+
+        if let config::WalSyncMode::IntervalMs(interval) = sync_mode {
+            start_interval_sync(Arc::downgrade(&state), *interval);
+        }
+
+        state
+    }
+
+    // record that len more bytes have been written. Returns the offset the WAL must be synced to
+    // for these bytes to be durable
+    pub fn add_written(&self, len: u64) -> u64 {
+        let mut progress = self.progress.lock().unwrap();
+        progress.written += len;
+        progress.written
+    }
+
+    pub fn written(&self) -> u64 {
+        self.progress.lock().unwrap().written
+    }
+
+    // the error from a failed fsync, if there has been one
+    pub fn error(&self) -> Option<String> {
+        self.progress.lock().unwrap().error.clone()
+    }
+
+    // block until the WAL has been synced to at least offset. If lead is true and nobody else is
+    // syncing, the calling thread does the sync, otherwise it waits for some other thread to do it
+    pub fn sync_to(&self, offset: u64, lead: bool) -> io::Result<()> {
+        let mut progress = self.progress.lock().unwrap();
+        loop {
+            if let Some(err) = &progress.error {
+                return Err(io::Error::other(err.clone()));
+            }
+            if progress.synced >= offset {
+                return Ok(());
+            }
+            if progress.syncing || !lead {
+                progress = self.synced_cond.wait(progress).unwrap();
+                continue;
+            }
+
+            // sync everything that has been written so far, which includes writes from any other
+            // writers that are waiting
+            progress.syncing = true;
+            let target = progress.written;
+            drop(progress);
+
+            let result = self.file.sync_data();
+
+            progress = self.progress.lock().unwrap();
+            progress.syncing = false;
+            match result {
+                Ok(()) => progress.synced = progress.synced.max(target),
+                Err(err) => {
+                    log::error!("error syncing WAL: {:?}", err);
+                    progress.error = Some(err.to_string());
+                }
+            }
+            self.synced_cond.notify_all();
+        }
+    }
+}
+
+// sync the WAL every interval milliseconds until it is dropped
+fn start_interval_sync(state: Weak<SyncState>, interval: u64) {
+    thread::spawn(move || loop {
+        thread::sleep(time::Duration::from_millis(interval));
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        let written = state.written();
+        if let Err(err) = state.sync_to(written, true) {
+            log::error!("stopping interval sync of WAL: {:?}", err);
+            return;
+        }
+    });
+}
+
+// returned to writers so they can wait until their write is durable
+pub struct SyncWaiter {
+    state: Arc<SyncState>,
+    offset: u64,
+    lead: bool,
+}
+
+impl SyncWaiter {
+    pub fn new(state: Arc<SyncState>, offset: u64, sync_mode: &config::WalSyncMode) -> Self {
+        SyncWaiter {
+            state,
+            offset,
+            lead: !matches!(sync_mode, config::WalSyncMode::IntervalMs(_)),
+        }
+    }
+
+    // blocks until the write is on stable storage
+    pub fn wait(&self) -> io::Result<()> {
+        self.state.sync_to(self.offset, self.lead)
+    }
+}
+
+#[cfg(test)]
+mod sync_tests {
+    use super::*;
+    use std::io::Write;
+
+    fn test_file(name: &str) -> (String, fs::File) {
+        let dir = "/tmp/wal_sync_tests";
+        fs::create_dir_all(dir).unwrap();
+        let filename = format!("{}/{}", dir, name);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&filename)
+            .unwrap();
+        (filename, file)
+    }
+
+    #[test]
+    fn group_sync_makes_concurrent_writes_durable() {
+        let (filename, mut file) = test_file("group_sync_makes_concurrent_writes_durable");
+        let mode = config::WalSyncMode::Group;
+        let state = SyncState::new(file.try_clone().unwrap(), &mode);
+
+        let mut handles = vec![];
+        for _ in 0..4 {
+            file.write_all(&[1, 2, 3]).unwrap();
+            let offset = state.add_written(3);
+            let waiter = SyncWaiter::new(state.clone(), offset, &mode);
+            handles.push(thread::spawn(move || waiter.wait()));
+        }
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+        assert_eq!(12, state.progress.lock().unwrap().synced);
+
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn interval_sync_syncs_in_the_background() {
+        let (filename, mut file) = test_file("interval_sync_syncs_in_the_background");
+        let mode = config::WalSyncMode::IntervalMs(5);
+        let state = SyncState::new(file.try_clone().unwrap(), &mode);
+
+        file.write_all(&[1, 2, 3]).unwrap();
+        let offset = state.add_written(3);
+        let waiter = SyncWaiter::new(state.clone(), offset, &mode);
+        assert!(waiter.wait().is_ok());
+        assert_eq!(3, state.progress.lock().unwrap().synced);
+
+        fs::remove_file(filename).unwrap();
+    }
+}
@@ -2,7 +2,9 @@ extern crate albertdb;
 
 use albertdb::{
   config::Config,
+  engine::Engine,
   frontend::http,
+  ring,
 };
 
 // https://cloudmaker.dev/actix-integration-tests//
@@ -10,18 +12,42 @@ use albertdb::{
 #[cfg(test)]
 mod tests {
   use actix_web::{ test::{ self, TestRequest } };
+  use futures::lock::Mutex;
   use serde_json::json;
+  use std::fs;
+  use std::sync::{ Arc, RwLock };
 
   use super::*;
+
+  // the replica config, with its data in a directory of the test's own
+  fn test_config(test_name: &str) -> Config {
+    let mut config = Config::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/config-replica1.yaml")).unwrap();
+    config.data_dir = format!("/tmp/integration_tests/{}", test_name);
+    fs::remove_dir_all(&config.data_dir).ok();
+    fs::create_dir_all(&config.data_dir).unwrap();
+    config
+  }
+
+  fn open_engine(config: &Config) -> Arc<RwLock<Engine>> {
+    Arc::new(RwLock::new(Engine::new(config.clone()).unwrap()))
+  }
+
+  macro_rules! init_app {
+    ($config:expr, $engine:expr) => {
+      test::init_service(
+        actix_web::App::new().configure(|cfg| {
+          let ring = Arc::new(Mutex::new(ring::init(&$config)));
+          http::configure($config.clone(), cfg, $engine.clone(), ring)
+        })
+      ).await
+    };
+  }
+
   #[actix_rt::test]
   async fn read_write_smoketest() {
-    let config = Config::from_file("/home/albertlockett/Development/albertdb/config-replica1.yaml");
-
-    let mut app = actix_web::test::init_service(
-      actix_web::App::new().configure(|cfg| {
-        http::configure(config, cfg)
-      })
-    ).await;
+    let config = test_config("read_write_smoketest");
+    let engine = open_engine(&config);
+    let mut app = init_app!(config, engine);
 
     let write_request_body = json!({
       "key": "key1",
@@ -43,7 +69,84 @@ mod tests {
       .send_request(&mut app)
       .await;
     assert!(read_resp.status().is_success(), "failed to read");
+    assert_eq!("val1".as_bytes(), &test::read_body(read_resp).await[..]);
+
+    engine.write().unwrap().close(false).unwrap();
+    fs::remove_dir_all(&config.data_dir).unwrap();
+  }
+
+  #[actix_rt::test]
+  async fn it_keeps_a_scan_within_its_prefix_whatever_the_cursor() {
+    let config = test_config("it_keeps_a_scan_within_its_prefix_whatever_the_cursor");
+    let engine = open_engine(&config);
+    for key in ["a1", "b1", "b2", "c1"] {
+      engine.read().unwrap().write(key.as_bytes(), b"val", None).unwrap().wait().unwrap();
+    }
+    let mut app = init_app!(config, engine);
+
+    // the hex encoded cursor for resuming after "a", which sorts before the prefix
+    let scan_resp = TestRequest::post()
+      .uri("/scan")
+      .set_json(&json!({ "prefix": "b", "cursor": "6100" }))
+      .send_request(&mut app)
+      .await;
+    assert!(scan_resp.status().is_success(), "failed to scan");
+    let body: serde_json::Value = test::read_body_json(scan_resp).await;
+    let keys: Vec<&str> = body["records"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|record| record["key"].as_str().unwrap())
+      .collect();
+    assert_eq!(vec!["b1", "b2"], keys);
+
+    engine.write().unwrap().close(false).unwrap();
+    fs::remove_dir_all(&config.data_dir).unwrap();
+  }
+
+  #[actix_rt::test]
+  async fn it_returns_service_unavailable_once_the_engine_is_closed() {
+    let config = test_config("it_returns_service_unavailable_once_the_engine_is_closed");
+    let engine = open_engine(&config);
+    let mut app = init_app!(config, engine);
+    engine.write().unwrap().close(false).unwrap();
+
+    let write_resp = TestRequest::post()
+      .uri("/write")
+      .set_json(&json!({ "key": "key1", "value": "val1" }))
+      .send_request(&mut app)
+      .await;
+    assert_eq!(503, write_resp.status().as_u16());
+
+    fs::remove_dir_all(&config.data_dir).unwrap();
+  }
+
+  #[actix_rt::test]
+  async fn it_returns_internal_server_error_when_an_sstable_is_missing() {
+    let config = test_config("it_returns_internal_server_error_when_an_sstable_is_missing");
+    let engine = open_engine(&config);
+    engine.read().unwrap().write(b"key1", b"val1", None).unwrap().wait().unwrap();
+    // closing waits for the flush to finish
+    engine.write().unwrap().close(true).unwrap();
+    drop(engine);
+    let engine = open_engine(&config);
+    let mut app = init_app!(config, engine);
+
+    // the table is still live, so its data file going missing is an error rather than a miss
+    for file in fs::read_dir(&config.data_dir).unwrap() {
+      let path = file.unwrap().path();
+      if path.file_name().unwrap().to_str().unwrap().starts_with("sstable-data-") {
+        fs::remove_file(path).unwrap();
+      }
+    }
+    let read_resp = TestRequest::post()
+      .uri("/read")
+      .set_json(&json!({ "key": "key1" }))
+      .send_request(&mut app)
+      .await;
+    assert_eq!(500, read_resp.status().as_u16());
 
-    // TODO assert on the response
+    engine.write().unwrap().close(false).unwrap();
+    fs::remove_dir_all(&config.data_dir).unwrap();
   }
-}
\ No newline at end of file
+}